@@ -0,0 +1,65 @@
+//! Times the scripts in benches/scripts from start to finish through the library, with
+//! their output thrown away. Each one leans on a different part of the VM, so a change
+//! to one part shows up mostly in its script.
+
+use std::{fs, io, path::Path};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use dymaxilang::Interpreter;
+
+/// Script name and the arguments it's run with
+const SCRIPTS: [(&str, &[&str]); 6] = [
+    ("fib", &[]),
+    ("arithmetic", &[]),
+    ("split_sort", &["data/words.txt"]),
+    ("maps", &[]),
+    ("concat", &[]),
+    ("word_counts", &["data/words.txt"]),
+];
+
+fn bench_dir() -> &'static Path {
+    Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/benches"))
+}
+
+fn interpreter(args: &[&str]) -> Interpreter {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(io::sink());
+    interpreter.set_args(
+        args.iter()
+            .map(|arg| bench_dir().join(arg).to_string_lossy().into_owned())
+            .collect(),
+    );
+    interpreter
+}
+
+fn scripts(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scripts");
+    // runs take tens of milliseconds, so 100 samples wouldn't fit in the measurement time
+    group.sample_size(20);
+
+    for (name, args) in SCRIPTS {
+        let path = bench_dir().join("scripts").join(name).with_extension("dy");
+        let source = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("{} couldn't be read: {err}", path.display()));
+
+        // a script that fails would make the timings meaningless
+        if let Err(err) = interpreter(args).interpret(&source) {
+            panic!("{name} failed:\n{err}");
+        }
+
+        // the interpreter is made and dropped outside the timing, so compiling the
+        // prelude and freeing everything the script left behind aren't counted
+        group.bench_function(name, |b| {
+            b.iter_batched_ref(
+                || interpreter(args),
+                |interpreter| interpreter.interpret(&source),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, scripts);
+criterion_main!(benches);
@@ -0,0 +1,301 @@
+use dymaxilang::{codes::ErrorCode, compiler::LangVersion, vm::DEFAULT_MAX_FRAMES};
+
+pub const DEFAULT_MAX_SOURCE_SIZE: u64 = 64 * 1024 * 1024;
+
+pub const USAGE: &str = "\
+usage: dymaxilang [options] [--] [file] [args...]
+
+Runs file, or starts a REPL if no file is given. A file of - or a program
+piped in without a file is read from stdin. Arguments after the file, or
+after --, are passed to the script.
+
+options:
+  --repl                    start a REPL even if a file is given
+  --no-prelude              don't define the functions from the prelude
+  --strict                  treat warnings as errors
+  --lang-version <n>        language version for scripts without a //! lang
+                            pragma (default latest)
+  --buffered-output         only flush output when the program ends
+  --max-source-size <bytes> refuse source files larger than this (default 64 MiB)
+  --max-call-depth <n>      calls that can be nested before a stack overflow
+                            (default 10000)
+  --disassemble             print the bytecode of every function after compiling
+  --no-run                  stop after compiling, useful with --disassemble
+  --check                   compile every file given without running any of
+                            them, naming the file in each diagnostic
+  --trace                   print the stack and each instruction as it runs
+  --profile                 print how often each instruction and function ran
+                            when the program finishes
+  --gc-stats                print how much the garbage collector allocated and
+                            freed, and how long it paused for, when the program
+                            finishes
+  --debug                   stop at the first line and read debugger commands
+                            from stdin, enter help at the prompt to see them
+  --break <line>            debug, stopping whenever line is reached rather
+                            than at the first line, can be given more than once
+  --timings                 print how long each compiler phase took
+  --no-color                don't colour diagnostics
+  --explain <code>          explain an error code, like E0003
+  --help, -h                print this message
+  --version, -V             print the version of dymaxilang
+
+exit status:
+  0    the program ran to the end
+  n    the program called exit(n)
+  2    the options couldn't be parsed
+  65   the program didn't compile, or the source file isn't valid
+  66   the source file couldn't be read
+  70   the program stopped with a runtime error
+";
+
+/// How the binary was asked to run a program
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub use_prelude: bool,
+    pub buffered_output: bool,
+    pub strict: bool,
+    pub timings: bool,
+    pub repl: bool,
+    pub disassemble: bool,
+    pub no_run: bool,
+    pub check: bool,
+    pub trace: bool,
+    pub profile: bool,
+    pub gc_stats: bool,
+    pub debug: bool,
+    pub breakpoints: Vec<u32>,
+    pub color: bool,
+    pub max_source_size: u64,
+    pub lang_version: LangVersion,
+    pub max_call_depth: usize,
+    pub file: Option<String>,
+    /// Everything after the file, given to the script (or, with --check, more files)
+    pub args: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            use_prelude: true,
+            buffered_output: false,
+            strict: false,
+            timings: false,
+            repl: false,
+            disassemble: false,
+            no_run: false,
+            check: false,
+            trace: false,
+            profile: false,
+            gc_stats: false,
+            debug: false,
+            breakpoints: Vec::new(),
+            color: true,
+            max_source_size: DEFAULT_MAX_SOURCE_SIZE,
+            lang_version: LangVersion::LATEST,
+            max_call_depth: DEFAULT_MAX_FRAMES,
+            file: None,
+            args: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Options),
+    Help,
+    Version,
+    Explain(ErrorCode),
+}
+
+/// Parses the arguments after the binary name. Flags are only read before the
+/// file, so a script can take flags of its own
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-prelude" => options.use_prelude = false,
+            "--buffered-output" => options.buffered_output = true,
+            "--strict" => options.strict = true,
+            "--timings" => options.timings = true,
+            "--repl" => options.repl = true,
+            "--disassemble" => options.disassemble = true,
+            "--no-run" => options.no_run = true,
+            "--check" => options.check = true,
+            "--trace" => options.trace = true,
+            "--profile" => options.profile = true,
+            "--gc-stats" => options.gc_stats = true,
+            "--debug" => options.debug = true,
+            "--break" => {
+                let Some(line) = args.next().and_then(|line| line.parse().ok()) else {
+                    return Err("--break must be followed by a line number".into());
+                };
+                options.debug = true;
+                options.breakpoints.push(line);
+            }
+            "--no-color" => options.color = false,
+            "--help" | "-h" => return Ok(Command::Help),
+            "--version" | "-V" => return Ok(Command::Version),
+            "--explain" => {
+                let Some(code) = args.next() else {
+                    return Err("--explain must be followed by an error code, like E0003".into());
+                };
+                let Some(code) = ErrorCode::from_code(&code) else {
+                    return Err(format!("{code} is not a dymaxilang error code"));
+                };
+                return Ok(Command::Explain(code));
+            }
+            "--lang-version" => {
+                let Some(version) = args.next().and_then(|n| LangVersion::from_number(&n)) else {
+                    return Err(format!(
+                        "--lang-version must be followed by a version from 1 to {}",
+                        LangVersion::LATEST
+                    ));
+                };
+                options.lang_version = version;
+            }
+            "--max-source-size" => {
+                let Some(size) = args.next().and_then(|size| size.parse().ok()) else {
+                    return Err("--max-source-size must be followed by a number of bytes".into());
+                };
+                options.max_source_size = size;
+            }
+            "--max-call-depth" => {
+                let Some(depth) = args.next().and_then(|depth| depth.parse().ok()) else {
+                    return Err("--max-call-depth must be followed by a number of calls".into());
+                };
+                options.max_call_depth = depth;
+            }
+            "--" => {
+                options.file = args.next();
+                break;
+            }
+            // - on its own is the file name for stdin
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(format!("unknown option {flag}"));
+            }
+            _ => {
+                options.file = Some(arg);
+                break;
+            }
+        }
+    }
+    options.args = args.collect();
+    Ok(Command::Run(options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_strs(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn run(args: &[&str]) -> Options {
+        match parse_strs(args) {
+            Ok(Command::Run(options)) => options,
+            other => panic!("expected options for {args:?}, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn no_arguments() {
+        assert_eq!(run(&[]), Options::default());
+    }
+
+    #[test]
+    fn flags_before_file() {
+        let options = run(&[
+            "--strict",
+            "--no-prelude",
+            "--no-color",
+            "--gc-stats",
+            "main.dy",
+        ]);
+        assert!(options.strict);
+        assert!(options.gc_stats);
+        assert!(!options.use_prelude);
+        assert!(!options.color);
+        assert_eq!(options.file.as_deref(), Some("main.dy"));
+        assert!(options.args.is_empty());
+    }
+
+    #[test]
+    fn arguments_after_file_go_to_script() {
+        let options = run(&["main.dy", "--strict", "x"]);
+        assert!(!options.strict);
+        assert_eq!(options.file.as_deref(), Some("main.dy"));
+        assert_eq!(options.args, ["--strict", "x"]);
+    }
+
+    #[test]
+    fn double_dash_stops_flags() {
+        let options = run(&["--trace", "--", "--odd-name.dy", "-v"]);
+        assert!(options.trace);
+        assert_eq!(options.file.as_deref(), Some("--odd-name.dy"));
+        assert_eq!(options.args, ["-v"]);
+
+        assert_eq!(run(&["--"]).file, None);
+    }
+
+    #[test]
+    fn stdin_file() {
+        let options = run(&["-", "a"]);
+        assert_eq!(options.file.as_deref(), Some("-"));
+        assert_eq!(options.args, ["a"]);
+    }
+
+    #[test]
+    fn values() {
+        let options = run(&[
+            "--lang-version",
+            "1",
+            "--max-source-size",
+            "10",
+            "--max-call-depth",
+            "20",
+        ]);
+        assert_eq!(options.lang_version, LangVersion::from_number("1").unwrap());
+        assert_eq!(options.max_source_size, 10);
+        assert_eq!(options.max_call_depth, 20);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let options = run(&["--break", "3", "--break", "10", "main.dy"]);
+        assert!(options.debug);
+        assert_eq!(options.breakpoints, [3, 10]);
+        assert!(run(&["--debug"]).breakpoints.is_empty());
+    }
+
+    #[test]
+    fn bad_values() {
+        assert!(parse_strs(&["--lang-version"]).is_err());
+        assert!(parse_strs(&["--lang-version", "0"]).is_err());
+        assert!(parse_strs(&["--max-source-size", "lots"]).is_err());
+        assert!(parse_strs(&["--max-call-depth", "-1"]).is_err());
+        assert!(parse_strs(&["--explain", "nope"]).is_err());
+        assert!(parse_strs(&["--break", "first"]).is_err());
+    }
+
+    #[test]
+    fn unknown_flag() {
+        assert_eq!(
+            parse_strs(&["--fast", "main.dy"]),
+            Err("unknown option --fast".into())
+        );
+    }
+
+    #[test]
+    fn help_and_version() {
+        assert_eq!(parse_strs(&["--strict", "--help"]), Ok(Command::Help));
+        assert_eq!(parse_strs(&["-h"]), Ok(Command::Help));
+        assert_eq!(parse_strs(&["--version"]), Ok(Command::Version));
+        assert_eq!(parse_strs(&["-V", "main.dy"]), Ok(Command::Version));
+        assert_eq!(
+            parse_strs(&["--explain", "E0003"]),
+            Ok(Command::Explain(ErrorCode::from_code("E0003").unwrap()))
+        );
+    }
+}
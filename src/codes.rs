@@ -0,0 +1,93 @@
+//! Stable codes for every error and warning, each with a longer explanation that
+//! `dymaxilang --explain <code>` prints.
+
+use std::fmt::{self, Display};
+
+macro_rules! error_codes {
+    ($($(#[$doc:meta])* $name:ident = $code:literal,)*) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum ErrorCode {
+            $($(#[$doc])* $name,)*
+        }
+
+        impl ErrorCode {
+            pub const ALL: &[ErrorCode] = &[$(ErrorCode::$name,)*];
+
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $(ErrorCode::$name => $code,)*
+                }
+            }
+
+            /// Explanations are included from src/codes, so a code without one fails to build
+            pub fn explanation(self) -> &'static str {
+                match self {
+                    $(ErrorCode::$name => include_str!(concat!("codes/", $code, ".txt")),)*
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
+    UnterminatedString = "E0001",
+    /// A character or operator the lexer doesn't know
+    InvalidToken = "E0002",
+    /// The parser needed a particular token, like a semicolon, and found something else
+    ExpectedToken = "E0003",
+    InvalidExpression = "E0004",
+    InvalidNumber = "E0005",
+    InvalidEscape = "E0006",
+    ReturnOutsideFunction = "E0007",
+    DuplicateVariable = "E0008",
+    SelfReferentialInitialiser = "E0009",
+    InvalidRange = "E0010",
+    /// More locals, parameters, arguments, or nesting than the bytecode can encode
+    LimitExceeded = "E0011",
+    /// Something other than a variable or map access on the left of =
+    InvalidAssignmentTarget = "E0012",
+    /// A `//! lang` pragma names a version that doesn't exist
+    InvalidLangVersion = "E0013",
+    /// Two cases of a switch with the same value
+    DuplicateCase = "E0014",
+    UnreadGlobal = "W0001",
+    UnreadLocal = "W0002",
+    /// A statement after a return in the same block
+    UnreachableCode = "W0003",
+    /// A global that's read but never assigned anywhere in the program
+    UndefinedGlobal = "W0004",
+    /// A native compared with `==` or `!=` on its own, when it was probably meant to be called
+    ComparedNative = "W0005",
+    /// Operands of a binary operator have the wrong types
+    InvalidOperands = "R0001",
+    InvalidOperand = "R0002",
+    DivisionByZero = "R0003",
+    NumberOverflow = "R0004",
+    UndefinedVariable = "R0005",
+    NotCallable = "R0006",
+    WrongArgumentCount = "R0007",
+    NotIterable = "R0008",
+    /// A native function was given arguments it can't use, or failed itself
+    NativeError = "R0009",
+    /// Calls nested past the frame or stack slot limit
+    StackOverflow = "R0010",
+    /// The condition of an `if` or `while`, or either side of `&&` or `||`, isn't a boolean
+    NonBoolCondition = "R0011",
+    /// The step of a for range is 0 or isn't a number
+    InvalidStep = "R0012",
+}
+
+impl ErrorCode {
+    pub fn from_code(code: &str) -> Option<ErrorCode> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|error_code| error_code.as_str().eq_ignore_ascii_case(code))
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    fmt::{self, Display},
+    ops::Range,
+    rc::Rc,
+};
+
+use crate::{codes::ErrorCode, style};
+
+/// Most characters of the source line shown under a diagnostic, so an error in
+/// minified or generated code doesn't print the whole program
+const SNIPPET_WIDTH: usize = 120;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    pub message: String,
+    pub line: u32,
+    /// Byte offsets of the highlighted span in the source
+    pub start: usize,
+    pub end: usize,
+    /// Only set for code that didn't come from the user's program, like the prelude
+    pub source_name: Option<&'static str>,
+    /// File the program was read from, if the compiler was told it
+    pub file_name: Option<Rc<str>>,
+    line_start: usize,
+    snippet: Snippet,
+}
+
+impl Diagnostic {
+    pub fn new(
+        severity: Severity,
+        code: ErrorCode,
+        message: &str,
+        program: &str,
+        line_start: usize,
+        span: Range<usize>,
+        line: u32,
+    ) -> Self {
+        let line_end = program[line_start..]
+            .find('\n')
+            .map_or(program.len(), |i| line_start + i);
+        let snippet = Snippet::new(
+            &program[line_start..line_end],
+            line,
+            span.start - line_start..span.end - line_start,
+        );
+
+        Self {
+            severity,
+            code,
+            message: message.to_owned(),
+            line,
+            start: span.start,
+            end: span.end,
+            source_name: None,
+            file_name: None,
+            line_start,
+            snippet,
+        }
+    }
+
+    pub fn column(&self) -> usize {
+        self.start - self.line_start + 1
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Error => style::error("error"),
+            Severity::Warning => style::warning("warning"),
+        };
+        let source = match (self.source_name, &self.file_name) {
+            (Some(name), _) => format!("{name}:"),
+            (None, Some(file)) => format!("{file}:"),
+            (None, None) => String::new(),
+        };
+        writeln!(
+            f,
+            "{label} at [{source}{}:{}]: {} [{}]",
+            self.line,
+            self.column(),
+            self.message,
+            self.code
+        )?;
+
+        writeln!(f, "{}", self.snippet)
+    }
+}
+
+/// A source line with part of it underlined, shown under compile and runtime errors
+#[derive(Clone, Debug)]
+pub struct Snippet {
+    line: u32,
+    /// The part of the line shown, with … where it was cut
+    text: String,
+    /// Characters of the text to underline
+    caret: Range<usize>,
+}
+
+impl Snippet {
+    /// Cuts a line longer than SNIPPET_WIDTH down to a window around the span, given as
+    /// byte offsets into the line
+    pub fn new(text: &str, line: u32, span: Range<usize>) -> Self {
+        // files with windows line endings would otherwise print the \r
+        let text = text.strip_suffix('\r').unwrap_or(text);
+        let (text, caret) = window(text, span);
+        Self { line, text, caret }
+    }
+}
+
+impl Display for Snippet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "    | ")?;
+        writeln!(f, "{:^4}| {}", self.line, self.text)?;
+        write!(f, "    | ")?;
+        for _ in 0..self.caret.start {
+            write!(f, " ")?;
+        }
+        for _ in self.caret.clone() {
+            write!(f, "^")?;
+        }
+        writeln!(f)?;
+        write!(f, "    | ")
+    }
+}
+
+/// Returns the shown part of the line with the span as character offsets into it
+fn window(line: &str, span: Range<usize>) -> (String, Range<usize>) {
+    let char_offset = |byte: usize| line.char_indices().take_while(|(i, _)| *i < byte).count();
+    let len = line.chars().count();
+    let start = char_offset(span.start);
+    // spans can run past the end of the line, like a missing semicolon at the end
+    let end = match span.end.checked_sub(line.len()) {
+        Some(past) if past > 0 => len + past,
+        _ => char_offset(span.end),
+    }
+    .max(start);
+
+    if len <= SNIPPET_WIDTH {
+        return (line.to_owned(), start..end);
+    }
+
+    let centre = start + usize::min(end - start, SNIPPET_WIDTH) / 2;
+    let window_start = centre
+        .saturating_sub(SNIPPET_WIDTH / 2)
+        .min(len - SNIPPET_WIDTH);
+    let window_end = window_start + SNIPPET_WIDTH;
+
+    let mut snippet = String::new();
+    if window_start > 0 {
+        snippet.push('…');
+    }
+    snippet.extend(line.chars().skip(window_start).take(SNIPPET_WIDTH));
+    if window_end < len {
+        snippet.push('…');
+    }
+
+    let shift = window_start - usize::from(window_start > 0);
+    let caret_end = usize::min(end, window_end + 1);
+    (snippet, start - shift..caret_end - shift)
+}
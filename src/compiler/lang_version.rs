@@ -0,0 +1,63 @@
+//! Versions of the language, for changes that would break scripts written before
+//! them. A script picks its version with a `//! lang N` pragma at the top, and
+//! scripts without one get the version from `--lang-version`, or the latest.
+
+use std::{
+    fmt::{self, Display},
+    ops::Range,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LangVersion(u8);
+
+impl LangVersion {
+    /// `read` trims whitespace from both ends of the file
+    pub const V1: LangVersion = LangVersion(1);
+    /// `read` only removes the line ending at the end of the file
+    pub const V2: LangVersion = LangVersion(2);
+    /// Conditions of `if`, `while`, `&&`, and `||` must be booleans, rather than
+    /// anything but `true` counting as false
+    pub const V3: LangVersion = LangVersion(3);
+    /// The right side of `&&` and `||` must be a boolean too, so they always produce one
+    pub const V4: LangVersion = LangVersion(4);
+    pub const LATEST: LangVersion = LangVersion::V4;
+
+    pub fn from_number(number: &str) -> Option<LangVersion> {
+        let number: u8 = number.parse().ok()?;
+        (1..=Self::LATEST.0)
+            .contains(&number)
+            .then_some(LangVersion(number))
+    }
+
+    /// Finds a `//! lang N` pragma among the comments at the start of the program,
+    /// returning the line it's on and the byte span of N
+    pub fn find_pragma(program: &str) -> Option<(u32, Range<usize>)> {
+        let mut line_start = 0;
+        for (i, line) in program.split_inclusive('\n').enumerate() {
+            let trimmed = line.trim();
+            if let Some(version) = trimmed.strip_prefix("//! lang") {
+                let version = version.trim();
+                let start = line_start + line.find(version).unwrap_or(line.len());
+                return Some((i as u32 + 1, start..start + version.len()));
+            }
+            if !trimmed.is_empty() && !trimmed.starts_with("//") {
+                return None;
+            }
+            line_start += line.len();
+        }
+
+        None
+    }
+}
+
+impl Default for LangVersion {
+    fn default() -> Self {
+        Self::LATEST
+    }
+}
+
+impl Display for LangVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -1,9 +1,15 @@
+use std::{ops::Range, rc::Rc};
+
 pub use token::{AtomKind, OpKind, Token, TokenKind};
 
+use crate::codes::ErrorCode;
+
 mod token;
 
+pub type LexError = (ErrorCode, &'static str);
+
 pub struct Lexer {
-    program: String,
+    program: Rc<str>,
     start: usize,
     position: usize,
     line: u32,
@@ -13,7 +19,7 @@ pub struct Lexer {
 impl Lexer {
     pub fn new(program: String) -> Self {
         Self {
-            program,
+            program: program.into(),
             start: 0,
             position: 0,
             line: 1,
@@ -25,6 +31,11 @@ impl Lexer {
         &self.program
     }
 
+    /// Shared handle to the program, kept by functions so runtime errors can show their source
+    pub fn source(&self) -> Rc<str> {
+        Rc::clone(&self.program)
+    }
+
     fn is_alpha(c: char) -> bool {
         matches!(c, 'A'..='Z' | 'a'..='z' | '_')
     }
@@ -38,16 +49,15 @@ impl Lexer {
     }
 
     fn peek(&mut self) -> char {
-        self.program
-            .as_bytes()
-            .get(self.position)
-            .copied()
-            .unwrap_or(b'\0') as char
+        self.program[self.position..].chars().next().unwrap_or('\0')
     }
 
     fn advance(&mut self) -> char {
         let c = self.peek();
-        self.position += c.len_utf8();
+        // stay put at the end so asking for tokens past Eof keeps returning Eof
+        if self.position < self.program.len() {
+            self.position += c.len_utf8();
+        }
         c
     }
 
@@ -60,7 +70,7 @@ impl Lexer {
         }
     }
 
-    fn make_token(&mut self, kind: TokenKind) -> Result<Token, String> {
+    fn make_token(&mut self, kind: TokenKind) -> Result<Token, LexError> {
         Ok(Token::new(kind, self.line, self.start, self.position))
     }
 
@@ -77,6 +87,7 @@ impl Lexer {
 
         let mut cs = identifier.chars();
         match cs.next().unwrap() {
+            'c' => check_keyword(1, "ase", TokenKind::Case),
             'e' => check_keyword(1, "lse", TokenKind::Else),
             'f' => match cs.next().unwrap_or('\0') {
                 'a' => check_keyword(2, "lse", TokenKind::Atom(AtomKind::False)),
@@ -92,13 +103,14 @@ impl Lexer {
             'l' => check_keyword(1, "et", TokenKind::Let),
             'n' => check_keyword(1, "ull", TokenKind::Atom(AtomKind::Null)),
             'r' => check_keyword(1, "eturn", TokenKind::Return),
+            's' => check_keyword(1, "witch", TokenKind::Switch),
             't' => check_keyword(1, "rue", TokenKind::Atom(AtomKind::True)),
             'w' => check_keyword(1, "hile", TokenKind::While),
             _ => TokenKind::Atom(AtomKind::Ident),
         }
     }
 
-    fn identifier(&mut self) -> Result<Token, String> {
+    fn identifier(&mut self) -> Result<Token, LexError> {
         while Self::is_alphanumeric(self.peek()) {
             self.advance();
         }
@@ -106,13 +118,25 @@ impl Lexer {
         self.make_token(self.identifier_type())
     }
 
-    fn number(&mut self) -> Result<Token, String> {
-        while Self::is_numeric(self.peek()) {
+    fn number(&mut self) -> Result<Token, LexError> {
+        let prefixed = &self.program[self.start..self.position] == "0"
+            && matches!(self.peek(), 'x' | 'X' | 'b' | 'B');
+        if prefixed {
+            self.advance();
+            // letters are taken too so a bad digit is reported by parse_number rather
+            // than starting an identifier
+            while Self::is_alphanumeric(self.peek()) {
+                self.advance();
+            }
+            return self.make_token(TokenKind::Atom(AtomKind::Number));
+        }
+
+        while Self::is_numeric(self.peek()) || self.peek() == '_' {
             self.advance();
         }
 
         if self.check('.') {
-            while Self::is_numeric(self.peek()) {
+            while Self::is_numeric(self.peek()) || self.peek() == '_' {
                 self.advance();
             }
         }
@@ -120,14 +144,21 @@ impl Lexer {
         self.make_token(TokenKind::Atom(AtomKind::Number))
     }
 
-    fn string(&mut self) -> Result<Token, String> {
+    fn string(&mut self) -> Result<Token, LexError> {
         while self.peek() != '"' {
             if self.peek() == '\0' {
-                return Err("string not closed".to_owned());
+                return Err((ErrorCode::UnterminatedString, "string not closed"));
             }
 
-            if self.advance() == '\n' {
+            let mut c = self.advance();
+            // the character after a backslash can't end the string, whether or not
+            // it makes a valid escape, which is checked by unescape
+            if c == '\\' && self.peek() != '\0' {
+                c = self.advance();
+            }
+            if c == '\n' {
                 self.line += 1;
+                self.lines.push(self.position);
             }
         }
         self.advance();
@@ -135,7 +166,20 @@ impl Lexer {
         self.make_token(TokenKind::Atom(AtomKind::String))
     }
 
-    pub fn next_token(&mut self) -> Result<Token, String> {
+    /// Kind of the token n tokens after the next one, without consuming anything
+    pub fn peek_kind(&mut self, n: usize) -> Option<TokenKind> {
+        let (start, position, line, lines) =
+            (self.start, self.position, self.line, self.lines.len());
+        let mut kind = None;
+        for _ in 0..n {
+            kind = self.next_token().ok().map(|token| token.kind);
+        }
+        (self.start, self.position, self.line) = (start, position, line);
+        self.lines.truncate(lines);
+        kind
+    }
+
+    pub fn next_token(&mut self) -> Result<Token, LexError> {
         loop {
             self.start = self.position;
             match self.advance() {
@@ -144,7 +188,7 @@ impl Lexer {
                 '*' => return self.make_token(TokenKind::Op(OpKind::Mul)),
                 '/' => {
                     if self.check('/') {
-                        while self.peek() != '\n' {
+                        while !matches!(self.peek(), '\n' | '\0') {
                             self.advance();
                         }
                     } else {
@@ -181,14 +225,14 @@ impl Lexer {
                 }
                 '&' => {
                     if self.advance() != '&' {
-                        return Err("use '&&' not '&'".to_owned());
+                        return Err((ErrorCode::InvalidToken, "use '&&' not '&'"));
                     }
 
                     return self.make_token(TokenKind::Op(OpKind::And));
                 }
                 '|' => {
                     if self.advance() != '|' {
-                        return Err("use '||' not '|'".to_owned());
+                        return Err((ErrorCode::InvalidToken, "use '||' not '|'"));
                     }
 
                     return self.make_token(TokenKind::Op(OpKind::Or));
@@ -210,12 +254,298 @@ impl Lexer {
                 }
                 '\0' => return self.make_token(TokenKind::Eof),
                 c if c.is_whitespace() => (),
-                _ => return Err("unrecognised token".to_owned()),
+                _ => return Err((ErrorCode::InvalidToken, "unrecognised token")),
             }
         }
     }
 
+    /// Line of the byte at offset, which must have been lexed already
+    pub fn line_of(&self, offset: usize) -> u32 {
+        self.lines.partition_point(|start| *start <= offset) as u32
+    }
+
     pub fn get_token_string(&self, token: &Token) -> &str {
         &self.program[token.start..token.end]
     }
 }
+
+/// Replaces the escapes in the contents of a string literal, or returns the byte span
+/// of the first one that isn't valid. The escapes are \n, \t, \r, \0, \\, \", \',
+/// \x followed by two hex digits for an ASCII character, and \u{...} with up to six
+/// hex digits for any character.
+pub fn unescape(text: &str) -> Result<String, Range<usize>> {
+    let mut value = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('\\') {
+        value.push_str(&rest[..i]);
+        let escape = &rest[i..];
+        let offset = text.len() - escape.len();
+        let Some((c, len)) = parse_escape(escape) else {
+            return Err(offset..offset + invalid_escape_len(escape));
+        };
+        value.push(c);
+        rest = &escape[len..];
+    }
+    value.push_str(rest);
+
+    Ok(value)
+}
+
+/// Reads the escape at the start of text, returning the character and how many bytes
+/// the escape took up
+fn parse_escape(text: &str) -> Option<(char, usize)> {
+    let hex = |digits: &str| {
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        u32::from_str_radix(digits, 16).ok()
+    };
+
+    let c = match text[1..].chars().next()? {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        '\\' => '\\',
+        '"' => '"',
+        '\'' => '\'',
+        'x' => {
+            let code = hex(text.get(2..4)?).filter(|code| *code < 0x80)?;
+            return Some((char::from_u32(code)?, 4));
+        }
+        'u' => {
+            let digits = text.strip_prefix("\\u{")?;
+            let end = digits.find('}').filter(|end| *end <= 6)?;
+            let c = char::from_u32(hex(&digits[..end])?)?;
+            return Some((c, 4 + end));
+        }
+        _ => return None,
+    };
+
+    Some((c, 2))
+}
+
+/// How much of an invalid escape to underline, the whole of \x.. or \u{...} if it
+/// looks like one and otherwise the backslash and the character after it
+fn invalid_escape_len(text: &str) -> usize {
+    if let Some(digits) = text.strip_prefix("\\x") {
+        return 2 + digits
+            .chars()
+            .take(2)
+            .take_while(char::is_ascii_hexdigit)
+            .count();
+    }
+    if let Some(end) = text
+        .strip_prefix("\\u{")
+        .and_then(|digits| digits.find('}'))
+    {
+        if text[3..3 + end].chars().all(|c| c.is_ascii_hexdigit()) {
+            return 4 + end;
+        }
+    }
+    1 + text[1..].chars().next().map_or(0, char::len_utf8)
+}
+
+/// Largest integer every smaller one of which an f64 holds exactly
+const MAX_EXACT_INTEGER: u64 = 1 << 53;
+
+/// Reads a number literal, which is decimal or an integer in hex after 0x or binary
+/// after 0b, with underscores between digits ignored
+pub fn parse_number(text: &str) -> Result<f64, String> {
+    let digits: String = text.chars().filter(|c| *c != '_').collect();
+
+    let (radix, name, digits) = match digits.get(..2) {
+        Some("0x" | "0X") => (16, "hex", &digits[2..]),
+        Some("0b" | "0B") => (2, "binary", &digits[2..]),
+        _ => {
+            let value: f64 = digits
+                .parse()
+                .map_err(|_| format!("'{text}' isn't a number"))?;
+            if !value.is_finite() {
+                return Err(String::from("number is too large, numbers must be finite"));
+            }
+            return Ok(value);
+        }
+    };
+
+    if digits.is_empty() {
+        return Err(format!("expected {name} digits after '{}'", &text[..2]));
+    }
+    if let Some(c) = digits.chars().find(|c| !c.is_digit(radix)) {
+        return Err(format!("'{c}' isn't a {name} digit"));
+    }
+    match u64::from_str_radix(digits, radix) {
+        Ok(value) if value <= MAX_EXACT_INTEGER => Ok(value as f64),
+        _ => Err(format!(
+            "{name} number is too large to be exact, the largest is {}",
+            match radix {
+                16 => format!("0x{MAX_EXACT_INTEGER:X}"),
+                _ => format!("0b{MAX_EXACT_INTEGER:b}"),
+            }
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRING: TokenKind = TokenKind::Atom(AtomKind::String);
+    const NUMBER: TokenKind = TokenKind::Atom(AtomKind::Number);
+    const IDENT: TokenKind = TokenKind::Atom(AtomKind::Ident);
+
+    /// Every token up to and including Eof, as its kind and text
+    fn tokens(program: &str) -> Vec<(TokenKind, String)> {
+        let mut lexer = Lexer::new(program.to_owned());
+        let mut tokens = Vec::new();
+        // a lexer that stops making progress would otherwise hang the test
+        for _ in 0..=program.len() + 1 {
+            let token = lexer.next_token().unwrap();
+            tokens.push((token.kind, lexer.get_token_string(&token).to_owned()));
+            if token.kind == TokenKind::Eof {
+                return tokens;
+            }
+        }
+        panic!("no Eof after {tokens:?}");
+    }
+
+    fn kinds(program: &str) -> Vec<TokenKind> {
+        tokens(program).into_iter().map(|(kind, _)| kind).collect()
+    }
+
+    #[test]
+    fn escaped_quote_doesnt_end_a_string() {
+        assert_eq!(
+            tokens(r#""say \"hi\"" x"#),
+            [
+                (STRING, r#""say \"hi\"""#.to_owned()),
+                (IDENT, "x".to_owned()),
+                (TokenKind::Eof, String::new())
+            ]
+        );
+        assert_eq!(kinds(r#""\\" x"#), [STRING, IDENT, TokenKind::Eof]);
+        // even an invalid escape can't end the string, unescape reports it instead
+        assert_eq!(kinds(r#""\q\"" x"#), [STRING, IDENT, TokenKind::Eof]);
+    }
+
+    #[test]
+    fn string_ending_in_a_backslash_is_unterminated() {
+        let mut lexer = Lexer::new(String::from(r#""abc\"#));
+        assert_eq!(
+            lexer.next_token().unwrap_err(),
+            (ErrorCode::UnterminatedString, "string not closed")
+        );
+        let mut lexer = Lexer::new(String::from(r#""abc\""#));
+        assert!(lexer.next_token().is_err());
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            unescape(r#"a\nb\tc\rd\0e\\f\"g\'h"#).unwrap(),
+            "a\nb\tc\rd\0e\\f\"g'h"
+        );
+        assert_eq!(unescape(r"\x41\x7f").unwrap(), "A\x7f");
+        assert_eq!(unescape(r"\u{e9}\u{1F600}\u{0}").unwrap(), "é😀\0");
+        assert_eq!(unescape("no escapes").unwrap(), "no escapes");
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        assert_eq!(unescape(r"ab\q"), Err(2..4));
+        assert_eq!(unescape(r"\x80"), Err(0..4));
+        assert_eq!(unescape(r"\xg1"), Err(0..2));
+        assert_eq!(unescape(r"\u{D800}"), Err(0..8));
+        assert_eq!(unescape(r"\u{1234567}"), Err(0..11));
+        assert_eq!(unescape(r"\u{}"), Err(0..4));
+        assert_eq!(unescape(r"\u1234"), Err(0..2));
+        assert_eq!(unescape(r"\é"), Err(0..3));
+    }
+
+    #[test]
+    fn comment_at_the_end_without_a_newline() {
+        assert_eq!(kinds("x // comment"), [IDENT, TokenKind::Eof]);
+        assert_eq!(kinds("//"), [TokenKind::Eof]);
+        assert_eq!(
+            kinds("x;\n// last line"),
+            [IDENT, TokenKind::SemiColon, TokenKind::Eof]
+        );
+    }
+
+    #[test]
+    fn eof_keeps_being_returned() {
+        let mut lexer = Lexer::new(String::from("// only a comment"));
+        for _ in 0..3 {
+            assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Eof);
+        }
+    }
+
+    #[test]
+    fn comment_counts_its_newline() {
+        let mut lexer = Lexer::new(String::from("// one\n// two\nx"));
+        let token = lexer.next_token().unwrap();
+        assert_eq!(token.kind, IDENT);
+        assert_eq!(token.line, 3);
+    }
+
+    #[test]
+    fn prefixed_and_separated_numbers() {
+        for literal in [
+            "0x1F",
+            "0XfF",
+            "0b1010",
+            "0B1",
+            "1_000_000",
+            "1.234_5",
+            "0x_ff",
+        ] {
+            assert_eq!(
+                tokens(literal),
+                [
+                    (NUMBER, literal.to_owned()),
+                    (TokenKind::Eof, String::new())
+                ]
+            );
+        }
+        // a bad digit stays part of the number so parse_number can name it
+        assert_eq!(kinds("0xfg 0b12"), [NUMBER, NUMBER, TokenKind::Eof]);
+        assert_eq!(
+            kinds("0x1+2"),
+            [NUMBER, TokenKind::Op(OpKind::Plus), NUMBER, TokenKind::Eof]
+        );
+        // only 0 starts a prefix
+        assert_eq!(kinds("10x"), [NUMBER, IDENT, TokenKind::Eof]);
+    }
+
+    #[test]
+    fn number_values() {
+        assert_eq!(parse_number("0x1F"), Ok(31.0));
+        assert_eq!(parse_number("0XfF"), Ok(255.0));
+        assert_eq!(parse_number("0b1010"), Ok(10.0));
+        assert_eq!(parse_number("1_000_000"), Ok(1_000_000.0));
+        assert_eq!(parse_number("1.234_5"), Ok(1.2345));
+        assert_eq!(parse_number("0x20_0000_0000_0000"), Ok((1u64 << 53) as f64));
+    }
+
+    #[test]
+    fn invalid_numbers() {
+        assert_eq!(
+            parse_number("0x"),
+            Err(String::from("expected hex digits after '0x'"))
+        );
+        assert_eq!(
+            parse_number("0xfg"),
+            Err(String::from("'g' isn't a hex digit"))
+        );
+        assert_eq!(
+            parse_number("0b12"),
+            Err(String::from("'2' isn't a binary digit"))
+        );
+        assert_eq!(
+            parse_number("0x20_0000_0000_0001"),
+            Err(String::from(
+                "hex number is too large to be exact, the largest is 0x20000000000000"
+            ))
+        );
+    }
+}
@@ -13,6 +13,8 @@ pub enum TokenKind {
     Let,
     Return,
     While,
+    Switch,
+    Case,
     Eof,
 }
 
@@ -49,7 +51,7 @@ pub enum AtomKind {
     Fn,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct Token {
     pub kind: TokenKind,
     pub line: u32,
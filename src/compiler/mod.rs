@@ -1,34 +1,61 @@
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    time::Instant,
+};
+
+pub use diagnostic::{Diagnostic, Severity, Snippet};
+pub use lang_version::LangVersion;
 use lexer::{AtomKind, Lexer, OpKind, Token, TokenKind};
+use passes::{Counters, Timings, PASSES};
 
+use crate::codes::ErrorCode;
 use crate::vm::{
-    chunk::{Chunk, OpCode},
-    object::{NativeFn, ObjFunction, ObjNative, ObjString},
+    chunk::{Chunk, Location, OpCode},
+    error::closest_name,
+    object::{NativeFn, ObjFunction, ObjKind},
     value::Value,
     VM,
 };
 
+mod diagnostic;
+mod lang_version;
 mod lexer;
 mod natives;
+mod passes;
+
+/// Every nested map scope adds a hashmap that `GetMap` has to search through
+#[cfg(feature = "local_map_scopes")]
+const MAX_MAP_SCOPE_DEPTH: usize = 64;
+
+const PRELUDE: &str = include_str!("prelude.dy");
+const PRELUDE_NAME: &str = "<prelude>";
 
 struct Parser {
     lexer: lexer::Lexer,
+    source_name: Option<&'static str>,
     previous: Option<Token>,
     current: Token,
     had_error: bool,
     handling_error: bool,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Parser {
-    pub fn new(program: String) -> Self {
-        let mut lexer = Lexer::new(program);
-        let current = lexer.next_token().unwrap();
-        Parser {
-            lexer,
+    pub fn new(program: String, source_name: Option<&'static str>) -> Self {
+        let mut parser = Parser {
+            lexer: Lexer::new(program),
+            source_name,
             previous: None,
-            current,
+            current: Token::new(TokenKind::Eof, 1, 0, 0),
             had_error: false,
             handling_error: false,
-        }
+            diagnostics: Vec::new(),
+        };
+        // advancing reports errors in the first token like any other
+        parser.advance();
+        parser.previous = None;
+        parser
     }
 
     pub fn previous(&self) -> Token {
@@ -40,53 +67,62 @@ impl Parser {
         self.current
     }
 
-    pub fn error_at(&mut self, start: usize, end: usize, line: u32, message: &str) {
+    /// Location of the last token consumed, which is what emitted code is attributed to.
+    /// Empty programs haven't consumed anything, so fall back to the current token.
+    pub fn location(&self) -> Location {
+        let token = self.previous.unwrap_or(self.current);
+        Location {
+            line: token.line,
+            start: token.start,
+            end: token.end,
+        }
+    }
+
+    pub fn error_at(
+        &mut self,
+        code: ErrorCode,
+        start: usize,
+        end: usize,
+        line: u32,
+        message: &str,
+    ) {
         if self.handling_error {
             return;
         }
         self.handling_error = true;
 
-        let line_start = self.lexer.lines[line as usize - 1];
-        eprintln!(
-            "\x1b[91merror\x1b[0m at [{}:{}]: {message}",
-            line,
-            start - line_start + 1
-        );
+        let diagnostic = self.diagnostic(Severity::Error, code, start, end, line, message);
+        self.diagnostics.push(diagnostic);
 
-        let line_end = if self.lexer.lines.len() > line as usize {
-            self.lexer.lines[line as usize]
-        } else {
-            'outer: {
-                for (i, c) in self.lexer.program().char_indices().skip(line_start) {
-                    if c == '\n' {
-                        break 'outer i + 1;
-                    }
-                }
-                self.lexer.program().len()
-            }
-        };
+        self.had_error = true;
+    }
 
-        eprintln!("    | ");
-        eprint!(
-            "{:^4}| {}",
+    fn diagnostic(
+        &self,
+        severity: Severity,
+        code: ErrorCode,
+        start: usize,
+        end: usize,
+        line: u32,
+        message: &str,
+    ) -> Diagnostic {
+        let line_start = self.lexer.lines[line as usize - 1];
+        let mut diagnostic = Diagnostic::new(
+            severity,
+            code,
+            message,
+            self.lexer.program(),
+            line_start,
+            start..end,
             line,
-            &self.lexer.program()[line_start..line_end]
         );
-        eprint!("    | ");
-        for _ in line_start..start {
-            eprint!(" ");
-        }
-        for _ in start..end {
-            eprint!("^");
-        }
-        eprintln!();
-        eprintln!("    | \n");
-
-        self.had_error = true;
+        diagnostic.source_name = self.source_name;
+        diagnostic
     }
 
-    pub fn error_bad_token(&mut self, message: &str) {
+    pub fn error_bad_token(&mut self, code: ErrorCode, message: &str) {
         self.error_at(
+            code,
             self.previous().end,
             self.previous().end + 1,
             self.previous().line,
@@ -94,8 +130,9 @@ impl Parser {
         );
     }
 
-    pub fn error(&mut self, message: &str) {
+    pub fn error(&mut self, code: ErrorCode, message: &str) {
         self.error_at(
+            code,
             self.previous().start,
             self.previous().end,
             self.previous().line,
@@ -125,8 +162,11 @@ impl Parser {
                 TokenKind::While
                 | TokenKind::For
                 | TokenKind::If
+                | TokenKind::Switch
                 | TokenKind::Return
                 | TokenKind::Let => return,
+                // the end of the enclosing block, which is left for it to consume
+                TokenKind::CloseBrace if scope_count == 0 => return,
                 _ => (),
             }
 
@@ -142,19 +182,53 @@ impl Parser {
 
             match token {
                 Ok(token) => break token,
-                Err(message) => self.error_bad_token(&message),
+                Err((code, message)) => self.error_bad_token(code, message),
             }
         };
     }
 
     pub fn consume(&mut self, kind: TokenKind, error_message: &str) {
         if self.current.kind != kind {
-            self.error_bad_token(error_message);
+            let found = match self.current.kind {
+                TokenKind::Eof => String::from("end of file"),
+                _ => format!("'{}'", self.current.lexeme_str(self.lexer.program())),
+            };
+            self.error_bad_token(
+                ErrorCode::ExpectedToken,
+                &format!("{error_message}, found {found}"),
+            );
+
+            // leave tokens that end or start a statement for sync, since skipping
+            // them makes the next statement an error too
+            if self.is_sync_point() {
+                return;
+            }
         }
 
         self.advance();
     }
 
+    fn is_sync_point(&self) -> bool {
+        matches!(
+            self.current.kind,
+            TokenKind::CloseBrace
+                | TokenKind::While
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::Else
+                | TokenKind::Switch
+                | TokenKind::Case
+                | TokenKind::Return
+                | TokenKind::Let
+                | TokenKind::Eof
+        )
+    }
+
+    /// Kind of the token n tokens after the current one
+    pub fn peek(&mut self, n: usize) -> Option<TokenKind> {
+        self.lexer.peek_kind(n)
+    }
+
     pub fn compare_next(&mut self, kind: TokenKind) -> bool {
         self.current.kind == kind
     }
@@ -172,6 +246,20 @@ impl Parser {
 struct Local {
     name: String,
     depth: Option<u32>,
+    /// Index of the local's entry in the chunk's local names, once it's initialised
+    debug_idx: Option<usize>,
+    /// Where the local was declared, for warning if it's never read. None for
+    /// parameters and the locals the compiler adds itself.
+    token: Option<Token>,
+    read: bool,
+}
+
+/// How much a for range moves its variable each time round
+enum RangeStep {
+    /// Known when compiling, so the comparison can be picked for its direction
+    Literal(f64),
+    /// Held in the hidden local at this slot, and checked when the loop starts
+    Local(u16),
 }
 
 struct CompilingFunction {
@@ -183,6 +271,8 @@ struct CompilingFunction {
     #[cfg(feature = "local_map_scopes")]
     map_set: Vec<(usize, bool)>,
     is_function: bool,
+    /// Offset of the last Call emitted, so a return of its result can become a tail call
+    last_call: Option<usize>,
 }
 
 impl CompilingFunction {
@@ -196,27 +286,163 @@ impl CompilingFunction {
             #[cfg(feature = "local_map_scopes")]
             map_set: Vec::new(),
             is_function,
+            last_call: None,
         }
     }
+
+    pub fn script() -> Self {
+        let mut script = Self::new(false);
+        script.function.name = Some(Box::from("<script>"));
+        script
+    }
 }
 
 pub struct Compiler {
     vm: VM,
     parser: Parser,
     function_stack: Vec<CompilingFunction>,
+    /// First assignment to each global in the user's program
+    global_writes: HashMap<u16, Token>,
+    /// First read of each global in the user's program
+    global_reads: HashMap<u16, Token>,
+    /// Globals defined by natives or the prelude, which are never reported as unread
+    builtin_globals: HashSet<u16>,
+    /// Globals compared with `==` or `!=` on their own, which is probably a missing call
+    /// if they hold a native
+    compared_globals: Vec<(u16, Token)>,
+    /// Name for the next function expression, set when it's the initialiser of a variable
+    function_name: Option<String>,
+    pub use_prelude: bool,
+    /// Turns warnings into errors
+    pub strict: bool,
+    /// Prints how long each phase took to stderr
+    pub timings: bool,
+    /// Prints the bytecode of every function once compiled
+    pub disassemble: bool,
+    /// Version for programs without a `//! lang` pragma
+    pub lang_version: LangVersion,
+    /// File the program was read from, named in its diagnostics
+    pub file_name: Option<Rc<str>>,
+    /// Constant loads that reused an existing entry in the constant table
+    constants_reused: usize,
+    /// Start of the expression the code being emitted belongs to, so runtime errors
+    /// can underline all of it rather than its last token
+    expression_start: Option<usize>,
+    /// The VM has already run a program, so its natives and prelude are defined
+    resumed: bool,
+    repl: bool,
 }
 
 impl Compiler {
     pub fn new(program: String) -> Self {
+        Self::with_vm(program, VM::new())
+    }
+
+    /// Compiles more code for a VM that has already run a script, like each line of
+    /// the REPL. Natives and the prelude are already defined, and a bare expression
+    /// at the end of the program becomes its result.
+    pub fn resume(program: String, vm: VM) -> Self {
+        let mut compiler = Self::resume_script(program, vm);
+        compiler.repl = true;
+        compiler
+    }
+
+    /// Compiles another whole program for a VM that has already run one. Pragmas and
+    /// lints apply as they do to a program run from a file, but the natives, the
+    /// prelude, and the globals defined by earlier programs are kept.
+    pub fn resume_script(program: String, vm: VM) -> Self {
+        let mut compiler = Self::with_vm(program, vm);
+        compiler.resumed = true;
+        compiler
+    }
+
+    fn with_vm(program: String, vm: VM) -> Self {
         Self {
-            vm: VM::new(),
-            parser: Parser::new(program),
-            function_stack: vec![CompilingFunction::new(false)],
+            vm,
+            parser: Parser::new(program, None),
+            function_stack: vec![CompilingFunction::script()],
+            global_writes: HashMap::new(),
+            global_reads: HashMap::new(),
+            builtin_globals: HashSet::new(),
+            compared_globals: Vec::new(),
+            function_name: None,
+            use_prelude: true,
+            strict: false,
+            timings: false,
+            disassemble: false,
+            lang_version: LangVersion::LATEST,
+            file_name: None,
+            constants_reused: 0,
+            expression_start: None,
+            resumed: false,
+            repl: false,
+        }
+    }
+
+    fn warning(&mut self, code: ErrorCode, token: Token, message: &str) {
+        if self.strict {
+            self.parser.handling_error = false;
+            self.parser
+                .error_at(code, token.start, token.end, token.line, message);
+        } else {
+            let mut diagnostic = self.parser.diagnostic(
+                Severity::Warning,
+                code,
+                token.start,
+                token.end,
+                token.line,
+                message,
+            );
+            diagnostic.file_name = self.file_name.clone();
+            self.vm.report(diagnostic);
+        }
+    }
+
+    /// Warns about a local going out of scope without having been read, unless its name
+    /// starts with _ to say that's on purpose
+    fn check_unread_local(&mut self, local: &Local) {
+        // the prelude's locals are up to it, and a syntax error can hide the reads
+        if local.read || self.parser.source_name.is_some() || self.parser.had_error {
+            return;
+        }
+        let Some(token) = local.token else {
+            return;
+        };
+        if local.name.starts_with('_') {
+            return;
+        }
+
+        let message = format!("local '{}' is never read", local.name);
+        self.warning(ErrorCode::UnreadLocal, token, &message);
+    }
+
+    fn record_global_write(&mut self, idx: u16, token: Token) {
+        if self.parser.source_name.is_some() {
+            self.builtin_globals.insert(idx);
+        } else {
+            self.global_writes.entry(idx).or_insert(token);
         }
     }
 
     pub fn push_constant(&mut self, constant: Value) {
+        // the most common constants have their own instructions and stay out of the table
+        let immediate = match constant.to_bits() {
+            bits if bits == Value::float(0.0).to_bits() => Some(OpCode::LoadZero),
+            bits if bits == Value::float(1.0).to_bits() => Some(OpCode::LoadOne),
+            bits if bits == Value::TRUE.to_bits() => Some(OpCode::LoadTrue),
+            bits if bits == Value::FALSE.to_bits() => Some(OpCode::LoadFalse),
+            _ => None,
+        };
+        if let Some(op) = immediate {
+            self.push_opcode(op);
+            return;
+        }
+
+        let count = self.chunk().constants.len();
         let idx = self.chunk_mut().add_constant(constant);
+        if idx < count {
+            self.constants_reused += 1;
+        }
         if idx <= u8::MAX as usize {
             self.push_opcode(OpCode::LoadConstant);
             self.push_byte(idx as u8);
@@ -230,27 +456,29 @@ impl Compiler {
 
     #[cfg(feature = "local_map_scopes")]
     pub fn push_map(&mut self, target: usize) {
-        let line = self.parser.previous().line;
-        self.chunk_mut().push_map(target, line);
+        let location = self.location();
+        self.chunk_mut().push_map(target, location);
     }
 
     pub fn push_jump(&mut self, opcode: OpCode) -> usize {
-        self.push_opcode(opcode);
-        self.push_byte(0xFF);
-        self.push_byte(0xFF);
-        self.chunk().jump_target() - 2
+        let location = self.location();
+        self.chunk_mut().push_jump(opcode, location)
     }
 
     pub fn push_loop(&mut self, target: usize) {
         let offset = self.chunk().jump_target() - target + 3;
 
-        if offset > u16::MAX as usize {
-            self.parser.error("loop too long");
+        if offset <= u16::MAX as usize {
+            self.push_opcode(OpCode::JumpUp);
+            self.push_byte((offset >> 8) as u8);
+            self.push_byte((offset & 0xFF) as u8)
+        } else {
+            let offset = offset + 2;
+            self.push_opcode(OpCode::JumpUpLong);
+            for shift in [24, 16, 8, 0] {
+                self.push_byte((offset >> shift) as u8);
+            }
         }
-
-        self.push_opcode(OpCode::JumpUp);
-        self.push_byte((offset >> 8) as u8);
-        self.push_byte((offset & 0xFF) as u8)
     }
 
     fn push_opcode(&mut self, op: OpCode) {
@@ -258,15 +486,40 @@ impl Compiler {
     }
 
     fn push_byte(&mut self, byte: u8) {
-        let line = self.parser.previous().line;
-        self.chunk_mut().push_byte(byte, line);
+        let location = self.location();
+        self.chunk_mut().push_byte(byte, location);
+    }
+
+    fn location(&self) -> Location {
+        let mut location = self.parser.location();
+        if let Some(start) = self.expression_start {
+            location.start = start;
+        }
+        location
+    }
+
+    /// Attributes the code emitted by emit to the source from start up to the last
+    /// token consumed
+    fn spanning<T>(&mut self, start: usize, emit: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = self.expression_start.replace(start);
+        let result = emit(self);
+        self.expression_start = outer;
+        result
     }
 
     fn push_fn(&mut self) {
-        self.function_stack.push(CompilingFunction::new(true));
+        let mut function = CompilingFunction::new(true);
+        function.function.name = self.function_name.take().map(String::into_boxed_str);
+        function.function.source = Some(self.parser.lexer.source());
+        self.function_stack.push(function);
     }
 
     fn pop_fn(&mut self) {
+        // the function's own scope is never ended, its locals go when it returns
+        for local in std::mem::take(self.locals_mut()) {
+            self.check_unread_local(&local);
+        }
+
         self.push_opcode(OpCode::Null);
         self.push_opcode(OpCode::Return);
         let stack_effect = self.function_stack.last().unwrap().peak_stack_effect;
@@ -312,98 +565,165 @@ impl Compiler {
         &mut self.current().chunk
     }
 
-    fn integer(&mut self) {
-        let token = self.parser.previous();
-        let value: f64 = self.parser.lexer.get_token_string(&token).parse().unwrap();
-        if value != value.round() {
-            self.parser.error("number must be an integer");
-        }
-        self.push_constant(Value::float(value))
+    fn number(&mut self) {
+        let value = self.number_value();
+        self.push_constant(Value::float(value));
     }
 
-    fn number(&mut self) {
+    /// Value of the number literal just consumed, reporting it if it can't be read
+    fn number_value(&mut self) -> f64 {
         let token = self.parser.previous();
-        let value = self.parser.lexer.get_token_string(&token).parse().unwrap();
-        self.push_constant(Value::float(value));
+        match lexer::parse_number(self.parser.lexer.get_token_string(&token)) {
+            Ok(value) => value,
+            Err(message) => {
+                self.parser.error(ErrorCode::InvalidNumber, &message);
+                0.0
+            }
+        }
     }
 
     fn string(&mut self) {
+        if let Some(value) = self.string_value() {
+            self.push_constant(value);
+        }
+    }
+
+    /// Value of the string literal just consumed, reporting it if it has a bad escape
+    fn string_value(&mut self) -> Option<Value> {
         let token = self.parser.previous();
-        let value = self.parser.lexer.get_token_string(&token);
-        let Ok(value) =
-            escape_bytes::unescape(value.as_bytes()).map(|v| String::from_utf8(v).unwrap())
-        else {
-            self.parser.error("invalid escape in string");
-            return;
+        let text = self.parser.lexer.get_token_string(&token);
+        let value = match lexer::unescape(&text[1..text.len() - 1]) {
+            Ok(value) => value,
+            Err(span) => {
+                // the span is within the quotes, and the escape can be on an earlier
+                // line than the token if the string spans several
+                let start = token.start + 1 + span.start;
+                let line = self.parser.lexer.line_of(start);
+                self.parser.error_at(
+                    ErrorCode::InvalidEscape,
+                    start,
+                    token.start + 1 + span.end,
+                    line,
+                    "invalid escape in string",
+                );
+                return None;
+            }
         };
-        let obj = ObjString::new(&value[1..value.len() - 1]);
-        let obj = self.vm.alloc(obj);
-        self.push_constant(Value::obj(obj));
+        let obj = self.vm.intern(&value);
+        Some(Value::obj(obj))
     }
 
-    fn resolve_local(&mut self, name: &str) -> Option<u8> {
+    fn resolve_local(&mut self, name: &str) -> Option<u16> {
         for (i, local) in self.locals().iter().enumerate().rev() {
             if name == local.name {
                 if local.depth.is_none() {
-                    self.parser
-                        .error("can't reference local in its own initialiser");
+                    self.parser.error(
+                        ErrorCode::SelfReferentialInitialiser,
+                        "can't reference local in its own initialiser",
+                    );
                 }
 
-                return Some(i as u8);
+                return Some(i as u16);
             }
         }
 
         None
     }
 
-    fn identifier(&mut self) {
-        let (get_op, set_op);
-        let name = self
-            .parser
-            .previous()
-            .lexeme_str(self.parser.lexer.program())
-            .to_owned();
-        let mut arg = self.resolve_local(&name);
+    /// Reports an = that follows a target parsed as an operand, as in `1 + x = 2`.
+    /// Without this the assignment would silently bind to the operand alone.
+    fn reject_assignment(&mut self, can_assign: bool) {
+        if !can_assign && self.parser.compare_next(TokenKind::Op(OpKind::Equal)) {
+            let equal = self.parser.current();
+            self.parser.error_at(
+                ErrorCode::InvalidAssignmentTarget,
+                equal.start,
+                equal.end,
+                equal.line,
+                "invalid assignment target, add brackets to assign before using the value",
+            );
+        }
+    }
 
-        match arg {
-            Some(_) => {
-                get_op = OpCode::GetLocal;
-                set_op = OpCode::SetLocal;
+    fn identifier(&mut self, can_assign: bool) {
+        let token = self.parser.previous();
+        let name = token.lexeme_str(self.parser.lexer.program()).to_owned();
+        let (get_op, set_op, arg) = match self.resolve_local(&name) {
+            Some(slot) => (OpCode::GetLocal, OpCode::SetLocal, slot),
+            None => (OpCode::GetGlobal, OpCode::SetGlobal, self.global_idx(&name)),
+        };
+
+        self.reject_assignment(can_assign);
+        if can_assign && self.parser.check(TokenKind::Op(OpKind::Equal)) {
+            if set_op == OpCode::SetGlobal {
+                self.record_global_write(arg, token);
+            }
+
+            self.expression();
+            self.spanning(token.start, |this| this.push_variable(set_op, arg));
+        } else {
+            if get_op == OpCode::GetLocal {
+                self.locals_mut()[arg as usize].read = true;
+            } else if self.parser.source_name.is_none() {
+                self.global_reads.entry(arg).or_insert(token);
             }
+            self.push_variable(get_op, arg);
+        }
+    }
+
+    fn global_idx(&mut self, name: &str) -> u16 {
+        match self.vm.globals.get_global_idx(name) {
+            Some(idx) => idx,
             None => {
-                arg = Some(self.vm.globals.get_global_idx(&name));
-                get_op = OpCode::GetGlobal;
-                set_op = OpCode::SetGlobal;
+                self.parser.error(
+                    ErrorCode::LimitExceeded,
+                    &format!("can't have more than {} globals", u16::MAX as usize + 1),
+                );
+                0
             }
         }
+    }
 
-        if self.parser.check(TokenKind::Op(OpKind::Equal)) {
-            self.expression();
-            self.push_opcode(set_op);
-            self.push_byte(arg.unwrap());
-        } else {
-            self.push_opcode(get_op);
-            self.push_byte(arg.unwrap());
+    /// Emits an instruction taking a local slot or global index, using the form with a
+    /// two byte operand past 255
+    fn push_variable(&mut self, op: OpCode, idx: u16) {
+        if let Ok(idx) = u8::try_from(idx) {
+            self.push_opcode(op);
+            self.push_byte(idx);
+            return;
         }
+
+        let op = match op {
+            OpCode::DefineGlobal => OpCode::DefineGlobalExt,
+            OpCode::GetGlobal => OpCode::GetGlobalExt,
+            OpCode::SetGlobal => OpCode::SetGlobalExt,
+            OpCode::GetLocal => OpCode::GetLocalExt,
+            OpCode::SetLocal => OpCode::SetLocalExt,
+            _ => unreachable!("{op:?} has no two byte form"),
+        };
+        self.push_opcode(op);
+        self.push_byte((idx >> 8) as u8);
+        self.push_byte((idx & 0xFF) as u8);
     }
 
-    fn map_access(&mut self) {
+    fn map_access(&mut self, can_assign: bool, start: usize) {
         self.expression();
         self.parser.consume(
             TokenKind::Op(OpKind::CloseSquare),
             "expected ']' after map access",
         );
 
-        if self.parser.check(TokenKind::Op(OpKind::Equal)) {
+        self.reject_assignment(can_assign);
+        if can_assign && self.parser.check(TokenKind::Op(OpKind::Equal)) {
             #[cfg(feature = "local_map_scopes")]
             if let Some(set) = self.function_stack.last_mut().unwrap().map_set.last_mut() {
                 *set = (set.0, true);
             }
 
             self.expression();
-            self.push_opcode(OpCode::SetMap);
+            self.spanning(start, |this| this.push_opcode(OpCode::SetMap));
         } else {
-            self.push_opcode(OpCode::GetMap);
+            self.spanning(start, |this| this.push_opcode(OpCode::GetMap));
         }
     }
 
@@ -419,9 +739,14 @@ impl Compiler {
             loop {
                 self.current().arity += 1;
                 if self.current().arity > 255 {
-                    self.parser.error("can't have more than 255 parameters");
+                    self.parser.error(
+                        ErrorCode::LimitExceeded,
+                        "can't have more than 255 parameters",
+                    );
                 }
                 self.parse_variable("expected parameter");
+                // a callback has to take every argument it's called with, used or not
+                self.locals_mut().last_mut().unwrap().token = None;
                 self.mark_initialised();
 
                 if !self.parser.check(TokenKind::Comma) {
@@ -442,12 +767,15 @@ impl Compiler {
         self.pop_fn();
     }
 
-    fn call(&mut self) {
+    fn call(&mut self, start: usize) {
         let mut arg_count = 0;
         if !self.parser.compare_next(TokenKind::Op(OpKind::CloseParen)) {
             loop {
                 if arg_count == u8::MAX {
-                    self.parser.error("can't have more than 255 arguments");
+                    self.parser.error(
+                        ErrorCode::LimitExceeded,
+                        "can't have more than 255 arguments",
+                    );
                 }
                 arg_count += 1;
 
@@ -463,8 +791,12 @@ impl Compiler {
             "expected ')' after arguments to function call",
         );
 
-        self.push_opcode(OpCode::Call);
-        self.push_byte(arg_count);
+        let offset = self.chunk().jump_target();
+        self.function_stack.last_mut().unwrap().last_call = Some(offset);
+        self.spanning(start, |this| {
+            this.push_opcode(OpCode::Call);
+            this.push_byte(arg_count);
+        });
     }
 
     fn expression_bp(&mut self, min_bp: u8) {
@@ -492,12 +824,19 @@ impl Compiler {
             Some(ret)
         }
 
+        // assignment binds loosest and groups from the right, since its value is
+        // parsed as a whole expression, so a target can only be assigned to when
+        // nothing to its left is waiting for an operand
+        let can_assign = min_bp == 0;
+
         self.parser.advance();
+        let first = self.parser.previous();
+        let start = first.start;
         match self.parser.previous().kind {
             TokenKind::Atom(it) => match it {
                 AtomKind::Number => self.number(),
                 AtomKind::String => self.string(),
-                AtomKind::Ident => self.identifier(),
+                AtomKind::Ident => self.identifier(can_assign),
                 AtomKind::True => self.push_constant(Value::TRUE),
                 AtomKind::False => self.push_constant(Value::FALSE),
                 AtomKind::Null => self.push_opcode(OpCode::Null),
@@ -505,104 +844,188 @@ impl Compiler {
             },
             TokenKind::Op(OpKind::OpenParen) => {
                 self.expression_bp(0);
-                assert!(self.parser.check(TokenKind::Op(OpKind::CloseParen)));
+                self.parser.consume(
+                    TokenKind::Op(OpKind::CloseParen),
+                    "expected ')' after expression",
+                );
             }
             TokenKind::Op(op) => {
                 if let Some(((), r_bp)) = prefix_bp(op) {
                     self.expression_bp(r_bp);
 
-                    match op {
-                        OpKind::Bang => self.push_opcode(OpCode::Not),
-                        OpKind::Minus => self.push_opcode(OpCode::Negate),
+                    let op = match op {
+                        OpKind::Bang => OpCode::Not,
+                        OpKind::Minus => OpCode::Negate,
                         _ => unreachable!("Non prefix operator returned from prefix_bp"),
-                    }
+                    };
+                    self.spanning(start, |this| this.push_opcode(op));
                 } else {
-                    self.parser.error(&format!(
-                        "'{}' is not a prefix operator",
-                        self.parser
-                            .previous()
-                            .lexeme_str(self.parser.lexer.program())
-                    ))
+                    self.parser.error(
+                        ErrorCode::InvalidExpression,
+                        &format!(
+                            "'{}' is not a prefix operator",
+                            self.parser
+                                .previous()
+                                .lexeme_str(self.parser.lexer.program())
+                        ),
+                    )
                 }
             }
-            _ => self.parser.error(&format!(
-                "'{}' can't be used in an expression",
-                self.parser
-                    .previous()
-                    .lexeme_str(self.parser.lexer.program())
-            )),
+            _ => self.parser.error(
+                ErrorCode::InvalidExpression,
+                &format!(
+                    "'{}' can't be used in an expression",
+                    self.parser
+                        .previous()
+                        .lexeme_str(self.parser.lexer.program())
+                ),
+            ),
         }
 
+        // the left operand while it's still a single identifier
+        let mut lone = self.lone_identifier(first);
         while let TokenKind::Op(op) = self.parser.current().kind {
             if let Some((l_bp, r_bp)) = infix_bp(op) {
                 if l_bp < min_bp {
                     break;
                 }
-                self.parser.advance();
-
-                if op == OpKind::And {
-                    let jump = self.push_jump(OpCode::JumpIfFalseNoPop);
-                    self.push_opcode(OpCode::Pop);
-                    self.expression_bp(r_bp);
-                    self.chunk_mut().patch_jump(jump);
-                    continue;
-                } else if op == OpKind::Or {
-                    let jump = self.push_jump(OpCode::JumpIfTrueNoPop);
+                let left = lone.take();
+
+                if op == OpKind::And || op == OpKind::Or {
+                    let jump_op = match op {
+                        OpKind::And => OpCode::JumpIfFalseNoPop,
+                        _ => OpCode::JumpIfTrueNoPop,
+                    };
+                    // emitted before taking the operator, so errors only underline the left side
+                    let jump = self.spanning(start, |this| this.push_jump(jump_op));
+                    self.parser.advance();
                     self.push_opcode(OpCode::Pop);
+                    let right = self.parser.current().start;
                     self.expression_bp(r_bp);
+                    self.spanning(right, |this| this.push_opcode(OpCode::CheckBool));
                     self.chunk_mut().patch_jump(jump);
                     continue;
-                } else if op == OpKind::OpenParen {
-                    self.call();
+                }
+                self.parser.advance();
+
+                if op == OpKind::OpenParen {
+                    self.call(start);
                     continue;
                 } else if op == OpKind::OpenSquare {
-                    self.map_access();
+                    self.map_access(can_assign, start);
                     continue;
                 }
 
+                let right = self.parser.current();
                 self.expression_bp(r_bp);
+                if op == OpKind::DoubleEqual || op == OpKind::BangEqual {
+                    let right = self.lone_identifier(right);
+                    for token in [left, right].into_iter().flatten() {
+                        self.record_compared_global(token);
+                    }
+                }
 
-                match op {
-                    OpKind::Plus => self.push_opcode(OpCode::Add),
-                    OpKind::Minus => self.push_opcode(OpCode::Sub),
-                    OpKind::Mul => self.push_opcode(OpCode::Mul),
-                    OpKind::Div => self.push_opcode(OpCode::Div),
-                    OpKind::DoubleEqual => self.push_opcode(OpCode::Equal),
-                    OpKind::BangEqual => self.push_opcode(OpCode::NotEqual),
-                    OpKind::Greater => self.push_opcode(OpCode::Greater),
-                    OpKind::GreaterEqual => self.push_opcode(OpCode::GreaterEqual),
-                    OpKind::Less => self.push_opcode(OpCode::Less),
-                    OpKind::LessEqual => self.push_opcode(OpCode::LessEqual),
+                let op = match op {
+                    OpKind::Plus => OpCode::Add,
+                    OpKind::Minus => OpCode::Sub,
+                    OpKind::Mul => OpCode::Mul,
+                    OpKind::Div => OpCode::Div,
+                    OpKind::DoubleEqual => OpCode::Equal,
+                    OpKind::BangEqual => OpCode::NotEqual,
+                    OpKind::Greater => OpCode::Greater,
+                    OpKind::GreaterEqual => OpCode::GreaterEqual,
+                    OpKind::Less => OpCode::Less,
+                    OpKind::LessEqual => OpCode::LessEqual,
                     _ => unreachable!("{:?} not handled", op),
-                }
+                };
+                self.spanning(start, |this| this.push_opcode(op));
 
                 continue;
             }
 
             break;
         }
+
+        // variables and map accesses consume their own '=', so one left over here
+        // follows something else, like a literal, a call, or brackets
+        if can_assign && self.parser.compare_next(TokenKind::Op(OpKind::Equal)) {
+            let equal = self.parser.current();
+            self.parser.error_at(
+                ErrorCode::InvalidAssignmentTarget,
+                equal.start,
+                equal.end,
+                equal.line,
+                "invalid assignment target, only variables and map accesses can be assigned to",
+            );
+        }
     }
 
     fn expression(&mut self) {
         self.expression_bp(0);
     }
 
+    /// Returns token if it's an identifier and nothing has been parsed after it, so an
+    /// operand that started with it is just the variable
+    fn lone_identifier(&self, token: Token) -> Option<Token> {
+        let alone = token.kind == TokenKind::Atom(AtomKind::Ident)
+            && self.parser.previous().start == token.start;
+        alone.then_some(token)
+    }
+
+    fn record_compared_global(&mut self, token: Token) {
+        if self.parser.source_name.is_some() {
+            return;
+        }
+        let name = token.lexeme_str(self.parser.lexer.program());
+        if self.locals().iter().any(|local| local.name == name) {
+            return;
+        }
+        if let Some(idx) = self.vm.globals.lookup(name) {
+            self.compared_globals.push((idx, token));
+        }
+    }
+
     fn expression_statement(&mut self) {
         self.expression();
-        self.parser
-            .consume(TokenKind::SemiColon, "expected ';' after expression");
+
+        // the last expression typed into the repl is returned so it can be printed,
+        // and it doesn't need a semicolon
+        if self.repl && self.function_stack.len() == 1 && self.scope_depth() == 0 {
+            let has_semicolon = self.parser.check(TokenKind::SemiColon);
+            if self.parser.compare_next(TokenKind::Eof) {
+                self.push_opcode(OpCode::Return);
+                return;
+            }
+            if !has_semicolon {
+                self.parser
+                    .error_bad_token(ErrorCode::ExpectedToken, "expected ';' after expression");
+            }
+        } else {
+            self.parser
+                .consume(TokenKind::SemiColon, "expected ';' after expression");
+        }
         self.push_opcode(OpCode::Pop);
     }
 
     fn return_statement(&mut self) {
         if !self.function_stack.last().unwrap().is_function {
-            self.parser.error("can't use return when not in a function");
+            self.parser.error(
+                ErrorCode::ReturnOutsideFunction,
+                "can't use return when not in a function",
+            );
         }
 
         if self.parser.compare_next(TokenKind::SemiColon) {
             self.push_opcode(OpCode::Null);
         } else {
             self.expression();
+
+            // the Return stays after it for natives, which can't replace the frame
+            let call = self.function_stack.last().unwrap().last_call;
+            if call.is_some_and(|offset| offset + 2 == self.chunk().jump_target()) {
+                self.chunk_mut()
+                    .replace_opcode(call.unwrap(), OpCode::TailCall);
+            }
         }
         self.push_opcode(OpCode::Return);
         self.parser
@@ -611,6 +1034,13 @@ impl Compiler {
 
     #[cfg(feature = "local_map_scopes")]
     fn open_map_scope(&mut self) {
+        if self.function_stack.last().unwrap().map_set.len() >= MAX_MAP_SCOPE_DEPTH {
+            self.parser.error(
+                ErrorCode::LimitExceeded,
+                &format!("can't nest more than {MAX_MAP_SCOPE_DEPTH} scopes in one function"),
+            );
+        }
+
         let target = self.chunk_mut().jump_target();
         self.function_stack
             .last_mut()
@@ -621,13 +1051,11 @@ impl Compiler {
 
     #[cfg(feature = "local_map_scopes")]
     fn finish_map_scope(&mut self) {
-        let (target, map_set) = self
-            .function_stack
-            .last_mut()
-            .unwrap()
-            .map_set
-            .pop()
-            .unwrap();
+        // can only be unbalanced if an earlier error skipped open_map_scope
+        let Some((target, map_set)) = self.function_stack.last_mut().unwrap().map_set.pop() else {
+            debug_assert!(self.parser.had_error, "unbalanced map scopes");
+            return;
+        };
         if map_set {
             self.push_map(target);
         }
@@ -648,8 +1076,12 @@ impl Compiler {
                 break;
             }
 
+            if let Some(idx) = local.debug_idx {
+                self.chunk_mut().end_local(idx);
+            }
             self.push_opcode(OpCode::Pop);
-            self.locals_mut().pop();
+            let local = self.locals_mut().pop().unwrap();
+            self.check_unread_local(&local);
             self.remove_stack_effect(1);
         }
 
@@ -658,9 +1090,22 @@ impl Compiler {
     }
 
     fn block(&mut self) {
+        let mut returned = false;
         while !self.parser.compare_next(TokenKind::CloseBrace)
             && !self.parser.compare_next(TokenKind::Eof)
         {
+            if returned && !self.parser.had_error {
+                // only the first statement is reported, the rest follow from it
+                let token = self.parser.current();
+                self.warning(
+                    ErrorCode::UnreachableCode,
+                    token,
+                    "unreachable code, the return before it always leaves the function",
+                );
+                returned = false;
+            } else {
+                returned = self.parser.compare_next(TokenKind::Return);
+            }
             self.statement();
         }
 
@@ -668,13 +1113,24 @@ impl Compiler {
             .consume(TokenKind::CloseBrace, "expected '}' after block");
     }
 
-    fn add_local(&mut self, name: String) {
-        if self.locals().len() == 256 {
-            self.parser
-                .error("can't have more than 256 local variables per function");
+    fn add_local(&mut self, name: String, token: Option<Token>) {
+        if self.locals().len() == u16::MAX as usize + 1 {
+            self.parser.error(
+                ErrorCode::LimitExceeded,
+                &format!(
+                    "can't have more than {} local variables per function",
+                    u16::MAX as usize + 1
+                ),
+            );
         }
 
-        self.locals_mut().push(Local { name, depth: None });
+        self.locals_mut().push(Local {
+            name,
+            depth: None,
+            debug_idx: None,
+            token,
+            read: false,
+        });
     }
 
     fn declare_variable(&mut self) {
@@ -699,18 +1155,22 @@ impl Compiler {
             }
         }
         if had_error {
-            self.parser.error(&format!(
-                "there is already a variable with name '{}' in this scope",
-                self.parser
-                    .previous()
-                    .lexeme_str(self.parser.lexer.program())
-            ));
+            self.parser.error(
+                ErrorCode::DuplicateVariable,
+                &format!(
+                    "there is already a variable with name '{}' in this scope",
+                    self.parser
+                        .previous()
+                        .lexeme_str(self.parser.lexer.program())
+                ),
+            );
         }
 
-        self.add_local(name.to_owned());
+        let token = self.parser.previous();
+        self.add_local(name.to_owned(), Some(token));
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    fn parse_variable(&mut self, error_message: &str) -> u16 {
         self.parser
             .consume(TokenKind::Atom(AtomKind::Ident), error_message);
 
@@ -719,32 +1179,44 @@ impl Compiler {
             return 0;
         }
 
-        self.vm.globals.get_global_idx(
-            self.parser
-                .previous()
-                .lexeme_str(self.parser.lexer.program()),
-        )
+        let token = self.parser.previous();
+        let name = token.lexeme_str(self.parser.lexer.program()).to_owned();
+        let idx = self.global_idx(&name);
+        self.record_global_write(idx, token);
+        idx
     }
 
     fn mark_initialised(&mut self) {
         self.add_stack_effect(1);
-        self.locals_mut().last_mut().unwrap().depth = Some(self.scope_depth());
+        let depth = self.scope_depth();
+        let slot = (self.locals().len() - 1) as u16;
+        let function = self.function_stack.last_mut().unwrap();
+        let local = function.locals.last_mut().unwrap();
+        local.depth = Some(depth);
+        local.debug_idx = Some(function.function.chunk.start_local(&local.name, slot));
     }
 
-    fn define_variable(&mut self, global_idx: u8) {
+    fn define_variable(&mut self, global_idx: u16) {
         if self.scope_depth() > 0 {
             self.mark_initialised();
             return;
         }
 
-        self.push_opcode(OpCode::DefineGlobal);
-        self.push_byte(global_idx);
+        self.push_variable(OpCode::DefineGlobal, global_idx);
     }
 
     fn var_decl(&mut self) {
         let global_idx = self.parse_variable("expected variable name");
+        let name = self
+            .parser
+            .previous()
+            .lexeme_str(self.parser.lexer.program())
+            .to_owned();
 
         if self.parser.check(TokenKind::Op(OpKind::Equal)) {
+            if self.parser.compare_next(TokenKind::Atom(AtomKind::Fn)) {
+                self.function_name = Some(name);
+            }
             self.expression();
         } else {
             self.push_opcode(OpCode::Null);
@@ -759,10 +1231,11 @@ impl Compiler {
     }
 
     fn if_statement(&mut self) {
+        let start = self.parser.current().start;
         self.expression();
+        let jump = self.spanning(start, |this| this.push_jump(OpCode::JumpIfFalse));
         self.parser
             .consume(TokenKind::OpenBrace, "expected '{' after condition");
-        let jump = self.push_jump(OpCode::JumpIfFalse);
 
         self.begin_scope();
         self.block();
@@ -770,7 +1243,8 @@ impl Compiler {
 
         if self.parser.check(TokenKind::Else) {
             let else_jump = self.push_jump(OpCode::Jump);
-            self.chunk_mut().patch_jump(jump);
+            // widening the first jump moves the second
+            let else_jump = else_jump + self.chunk_mut().patch_jump(jump);
             self.parser
                 .consume(TokenKind::OpenBrace, "expected '{' after else");
             self.begin_scope();
@@ -792,41 +1266,82 @@ impl Compiler {
 
         self.parser
             .consume(TokenKind::In, "expected 'in' after loop variable");
-        if self.parser.check(TokenKind::Atom(AtomKind::Number)) {
-            self.integer();
-        } else if self.parser.check(TokenKind::Atom(AtomKind::Ident)) {
-            self.identifier();
-        } else {
-            self.parser
-                .error("expected either integer or identifer for start of range");
-        }
-
-        let start = self.chunk_mut().jump_target();
 
-        let var_idx = (self.locals().len() - 1) as u8;
-        self.push_opcode(OpCode::GetLocal);
-        self.push_byte(var_idx);
+        // keys isn't a keyword, it's only one when followed by a value like `keys x`
+        let current = self.parser.current();
+        if current.kind == TokenKind::Atom(AtomKind::Ident)
+            && current.lexeme_str(self.parser.lexer.program()) == "keys"
+            && matches!(self.parser.peek(1), Some(TokenKind::Atom(_)))
+        {
+            self.parser.advance();
+            self.expression();
+            self.push_opcode(OpCode::MapKeys);
+            self.for_each(OpCode::NextKey, "(keys)", "(index)", "map");
+            return;
+        }
 
+        // binds tighter than comparisons so the range operator isn't consumed
+        self.expression_bp(11);
         let op = if self.parser.check(TokenKind::Op(OpKind::Greater)) {
             OpCode::Less
         } else if self.parser.check(TokenKind::Op(OpKind::GreaterEqual)) {
             OpCode::LessEqual
+        } else if self.parser.compare_next(TokenKind::Op(OpKind::Less))
+            || self.parser.compare_next(TokenKind::Op(OpKind::LessEqual))
+        {
+            let token = self.parser.current();
+            self.parser.error_at(
+                ErrorCode::InvalidRange,
+                token.start,
+                token.end,
+                token.line,
+                "must use either '>' or '>=' in for loop range",
+            );
+            return;
         } else {
-            self.parser
-                .error("must use either '>' or '>=' in for loop range");
+            // without a range, the value is a string to loop over the characters of
+            self.for_each_char();
             return;
         };
 
-        if self.parser.check(TokenKind::Atom(AtomKind::Number)) {
-            self.integer();
-        } else if self.parser.check(TokenKind::Atom(AtomKind::Ident)) {
-            self.identifier();
-        } else {
-            self.parser
-                .error("expected either integer or identifer for end of range");
-        }
-        self.push_opcode(op);
+        // evaluated once before the loop, while the loop variable can't be referenced
+        self.expression();
+        let var_idx = (self.locals().len() - 1) as u16;
         self.mark_initialised();
+        self.add_local(String::from("(end)"), None);
+        self.mark_initialised();
+        let end_idx = var_idx + 1;
+        let step = self.range_step();
+
+        let start = self.chunk_mut().jump_target();
+        let inclusive = op == OpCode::LessEqual;
+        match step {
+            RangeStep::Literal(step) => {
+                let op = match (step > 0.0, inclusive) {
+                    (true, false) => OpCode::Less,
+                    (true, true) => OpCode::LessEqual,
+                    (false, false) => OpCode::Greater,
+                    (false, true) => OpCode::GreaterEqual,
+                };
+                self.push_variable(OpCode::GetLocal, var_idx);
+                self.push_variable(OpCode::GetLocal, end_idx);
+                self.push_opcode(op);
+            }
+            RangeStep::Local(step_idx) => {
+                // (end - i) * step stays positive until i reaches end from either side
+                self.push_variable(OpCode::GetLocal, end_idx);
+                self.push_variable(OpCode::GetLocal, var_idx);
+                self.push_opcode(OpCode::Sub);
+                self.push_variable(OpCode::GetLocal, step_idx);
+                self.push_opcode(OpCode::Mul);
+                self.push_constant(Value::float(0.0));
+                self.push_opcode(if inclusive {
+                    OpCode::GreaterEqual
+                } else {
+                    OpCode::Greater
+                });
+            }
+        }
         let jump = self.push_jump(OpCode::JumpIfFalse);
 
         self.begin_scope();
@@ -834,13 +1349,101 @@ impl Compiler {
             .consume(TokenKind::OpenBrace, "expected '{' after range");
         self.block();
 
-        self.push_opcode(OpCode::GetLocal);
-        self.push_byte(var_idx);
-        self.push_constant(Value::float(1.0));
-        self.push_opcode(OpCode::Add);
-        self.push_opcode(OpCode::SetLocal);
-        self.push_byte(var_idx);
-        self.push_opcode(OpCode::Pop);
+        match (step, u8::try_from(var_idx)) {
+            (RangeStep::Literal(1.0), Ok(var_idx)) => {
+                self.push_opcode(OpCode::IncrementLocal);
+                self.push_byte(var_idx);
+            }
+            (step, _) => {
+                self.push_variable(OpCode::GetLocal, var_idx);
+                match step {
+                    RangeStep::Literal(step) => self.push_constant(Value::float(step)),
+                    RangeStep::Local(step_idx) => self.push_variable(OpCode::GetLocal, step_idx),
+                }
+                self.push_opcode(OpCode::Add);
+                self.push_variable(OpCode::SetLocal, var_idx);
+                self.push_opcode(OpCode::Pop);
+            }
+        }
+        self.end_scope();
+
+        self.push_loop(start);
+        self.chunk_mut().patch_jump(jump);
+        self.end_scope();
+    }
+
+    /// Parses the optional step clause after the end of a for range. step isn't a
+    /// keyword, so it's only recognised here and can still be used as a name.
+    fn range_step(&mut self) -> RangeStep {
+        let current = self.parser.current();
+        if current.kind != TokenKind::Atom(AtomKind::Ident)
+            || current.lexeme_str(self.parser.lexer.program()) != "step"
+        {
+            return RangeStep::Literal(1.0);
+        }
+        self.parser.advance();
+
+        let number = Some(TokenKind::Atom(AtomKind::Number));
+        let open_brace = Some(TokenKind::OpenBrace);
+        let negative = self.parser.compare_next(TokenKind::Op(OpKind::Minus))
+            && self.parser.peek(1) == number
+            && self.parser.peek(2) == open_brace;
+        if negative
+            || (self.parser.compare_next(TokenKind::Atom(AtomKind::Number))
+                && self.parser.peek(1) == open_brace)
+        {
+            self.parser.check(TokenKind::Op(OpKind::Minus));
+            self.parser.advance();
+            let step = self.number_value();
+            if step == 0.0 {
+                self.parser.error(
+                    ErrorCode::InvalidRange,
+                    "step of a for range can't be 0, the loop would never end",
+                );
+            }
+            return RangeStep::Literal(if negative { -step } else { step });
+        }
+
+        let start = self.parser.current().start;
+        self.expression();
+        self.spanning(start, |this| this.push_opcode(OpCode::CheckStep));
+        self.add_local(String::from("(step)"), None);
+        self.mark_initialised();
+        RangeStep::Local((self.locals().len() - 1) as u16)
+    }
+
+    /// Loops over the characters of the string on top of the stack, which becomes a hidden
+    /// local in place of the loop variable
+    fn for_each_char(&mut self) {
+        self.for_each(OpCode::NextChar, "(string)", "(offset)", "string");
+    }
+
+    /// Loops over the value on top of the stack with next, an instruction that takes the
+    /// slot of the value and pushes the next item and whether there was one. The value
+    /// and its position become hidden locals in place of the loop variable.
+    fn for_each(&mut self, next: OpCode, value_name: &str, position_name: &str, what: &str) {
+        let value = self.locals_mut().last_mut().unwrap();
+        let var_name = std::mem::replace(&mut value.name, String::from(value_name));
+        let var_token = value.token.take();
+        self.mark_initialised();
+        let value_idx = (self.locals().len() - 1) as u16;
+
+        self.push_constant(Value::float(0.0));
+        self.add_local(String::from(position_name), None);
+        self.mark_initialised();
+
+        let start = self.chunk_mut().jump_target();
+        self.push_opcode(next);
+        self.push_byte((value_idx >> 8) as u8);
+        self.push_byte((value_idx & 0xFF) as u8);
+        let jump = self.push_jump(OpCode::JumpIfFalse);
+
+        self.begin_scope();
+        self.add_local(var_name, var_token);
+        self.mark_initialised();
+        self.parser
+            .consume(TokenKind::OpenBrace, &format!("expected '{{' after {what}"));
+        self.block();
         self.end_scope();
 
         self.push_loop(start);
@@ -848,11 +1451,135 @@ impl Compiler {
         self.end_scope();
     }
 
+    /// Compares the value after switch with each case in turn, running the block of the
+    /// first that's equal, or the else block if none are
+    fn switch_statement(&mut self) {
+        self.begin_scope();
+        self.expression();
+        // evaluated once, every case compares against it in a hidden local
+        self.add_local(String::from("(switch)"), None);
+        self.mark_initialised();
+        let value_idx = (self.locals().len() - 1) as u16;
+        self.parser
+            .consume(TokenKind::OpenBrace, "expected '{' after switch value");
+
+        let mut cases: Vec<Value> = Vec::new();
+        // the jump past the block of the last case, taken when it doesn't match
+        let mut next_case: Option<usize> = None;
+        // a jump to the end after every block, since cases don't fall through
+        let mut end_jumps: Vec<usize> = Vec::new();
+        let mut had_else = false;
+        loop {
+            if self.parser.check(TokenKind::Else) {
+                if had_else {
+                    self.parser
+                        .error(ErrorCode::ExpectedToken, "a switch can only have one else");
+                }
+                if let Some(jump) = next_case.take() {
+                    self.patch_case_jump(jump, &mut end_jumps);
+                }
+                had_else = true;
+                self.parser
+                    .consume(TokenKind::OpenBrace, "expected '{' after else");
+                self.begin_scope();
+                self.block();
+                self.end_scope();
+                continue;
+            }
+            if !self.parser.check(TokenKind::Case) {
+                break;
+            }
+
+            if had_else {
+                self.parser.error(
+                    ErrorCode::ExpectedToken,
+                    "else must come after every case of a switch",
+                );
+            }
+            if let Some(jump) = next_case.take() {
+                self.patch_case_jump(jump, &mut end_jumps);
+            }
+
+            let start = self.parser.current().start;
+            self.push_variable(OpCode::GetLocal, value_idx);
+            if let Some(value) = self.case_literal() {
+                if cases.contains(&value) {
+                    self.parser.error(
+                        ErrorCode::DuplicateCase,
+                        "duplicate case, the earlier one with this value always runs instead",
+                    );
+                }
+                cases.push(value);
+            }
+            self.push_opcode(OpCode::Equal);
+            next_case = Some(self.spanning(start, |this| this.push_jump(OpCode::JumpIfFalse)));
+
+            self.parser
+                .consume(TokenKind::OpenBrace, "expected '{' after case");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+
+            if !self.parser.compare_next(TokenKind::CloseBrace) {
+                end_jumps.push(self.push_jump(OpCode::Jump));
+            }
+        }
+
+        if let Some(jump) = next_case {
+            self.patch_case_jump(jump, &mut end_jumps);
+        }
+        self.parser
+            .consume(TokenKind::CloseBrace, "expected 'case', 'else', or '}'");
+
+        // the last first, so widening one never moves those still to be patched
+        for jump in end_jumps.into_iter().rev() {
+            self.chunk_mut().patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    /// Patches the jump taken when a case doesn't match to the code compiled so far
+    fn patch_case_jump(&mut self, jump: usize, end_jumps: &mut [usize]) {
+        let inserted = self.chunk_mut().patch_jump(jump);
+        // widening the jump moves the end jump of its own block, which comes after it
+        if let Some(end_jump) = end_jumps.last_mut().filter(|end_jump| **end_jump > jump) {
+            *end_jump += inserted;
+        }
+    }
+
+    /// Pushes the number or string after case, returning its value
+    fn case_literal(&mut self) -> Option<Value> {
+        let negative = self.parser.check(TokenKind::Op(OpKind::Minus));
+        let value = if self.parser.check(TokenKind::Atom(AtomKind::Number)) {
+            let value = self.number_value();
+            Value::float(if negative { -value } else { value })
+        } else if !negative && self.parser.check(TokenKind::Atom(AtomKind::String)) {
+            self.string_value()?
+        } else {
+            let token = self.parser.current();
+            self.parser.error_at(
+                ErrorCode::ExpectedToken,
+                token.start,
+                token.end,
+                token.line,
+                "case must be a number or string literal",
+            );
+            // skipped so the block after it still compiles as the case's
+            if !self.parser.compare_next(TokenKind::OpenBrace) {
+                self.parser.advance();
+            }
+            return None;
+        };
+        self.push_constant(value);
+        Some(value)
+    }
+
     fn while_loop(&mut self) {
         let start = self.chunk_mut().jump_target();
+        let condition_start = self.parser.current().start;
         self.expression();
 
-        let jump = self.push_jump(OpCode::JumpIfFalse);
+        let jump = self.spanning(condition_start, |this| this.push_jump(OpCode::JumpIfFalse));
 
         self.parser
             .consume(TokenKind::OpenBrace, "expected '{' after condition");
@@ -872,6 +1599,8 @@ impl Compiler {
             self.for_loop();
         } else if self.parser.check(TokenKind::If) {
             self.if_statement();
+        } else if self.parser.check(TokenKind::Switch) {
+            self.switch_statement();
         } else if self.parser.check(TokenKind::Let) {
             self.var_decl();
         } else if self.parser.check(TokenKind::Return) {
@@ -890,10 +1619,8 @@ impl Compiler {
     }
 
     fn define_native(&mut self, name: &str, native: NativeFn) {
-        let native = ObjNative::new(native);
-        let native = self.vm.alloc(native);
-        let idx = self.vm.globals.get_global_idx(name);
-        self.vm.globals.set(idx, Value::obj(native));
+        let idx = self.vm.define_native(name, native);
+        self.builtin_globals.insert(idx);
     }
 
     fn define_natives(&mut self) {
@@ -901,38 +1628,328 @@ impl Compiler {
 
         self.define_native("time", native_time);
         self.define_native("print", native_print);
-        self.define_native("read", native_read);
+        self.define_native("print_raw", native_print_raw);
+        self.define_native("eprint", native_eprint);
+        self.define_native("flush", native_flush);
+        self.define_native("exit", native_exit);
+        self.define_native("write", native_write);
+        self.define_native("append", native_append);
+        self.define_native("input", native_input);
+        self.define_native("args", native_args);
+        self.define_native("args_count", native_args_count);
         self.define_native("num", native_num);
+        self.define_native("parse_num", native_parse_num);
+        self.define_native("is_num", native_is_num);
+        self.define_native("is_string", native_is_string);
+        self.define_native("is_bool", native_is_bool);
+        self.define_native("is_null", native_is_null);
         self.define_native("abs", native_abs);
+        self.define_native("str", native_str);
+        self.define_native("to_fixed", native_to_fixed);
+        self.define_native("upper", native_upper);
+        self.define_native("lower", native_lower);
+        self.define_native("trim", native_trim);
+        self.define_native("replace", native_replace);
+        self.define_native("contains", native_contains);
+        self.define_native("find", native_find);
+        self.define_native("starts_with", native_starts_with);
+        self.define_native("ends_with", native_ends_with);
         self.define_native("split", native_split);
         self.define_native("split_into", native_split_into);
         self.define_native("chars", native_chars);
         self.define_native("chars_into", native_chars_into);
+        self.define_native("encode_into", native_encode_into);
+        self.define_native("decode", native_decode);
+        self.define_native("ord", native_ord);
+        self.define_native("chr", native_chr);
         self.define_native("sort", native_sort);
+        self.define_native("sort_pairs", native_sort_pairs);
+        self.define_native("sort_by", native_sort_by);
+        self.define_native("reverse", native_reverse);
+        self.define_native("min_of", native_min_of);
+        self.define_native("max_of", native_max_of);
+        self.define_native("sum", native_sum);
+        self.define_native("join", native_join);
+        self.define_native("push", native_push);
+        self.define_native("pop", native_pop);
+        self.define_native("insert", native_insert);
+        self.define_native("has", native_has);
+        self.define_native("get_or", native_get_or);
+        self.define_native("remove", native_remove);
+        self.define_native("count", native_count);
+        self.define_native("clear", native_clear);
+        self.define_native("reserve", native_reserve);
+        self.define_native("map_equals", native_map_equals);
+        self.define_native("map_copy", native_map_copy);
+        self.define_native("buffer", native_buffer);
+        self.define_native("to_str", native_to_str);
+        self.define_native("call_depth", native_call_depth);
+        self.define_native("stack_slots_used", native_stack_slots_used);
+        self.define_native("gc_stats", native_gc_stats);
+
+        for (name, native) in versioned_natives(self.lang_version) {
+            self.define_native(name, native);
+        }
+        self.vm.bool_conditions = self.lang_version >= LangVersion::V3;
+        self.vm.bool_operands = self.lang_version >= LangVersion::V4;
     }
 
-    pub fn compile(mut self) -> VM {
-        self.define_natives();
+    fn compile_prelude(&mut self) {
+        let program = std::mem::replace(
+            &mut self.parser,
+            Parser::new(PRELUDE.to_owned(), Some(PRELUDE_NAME)),
+        );
 
         while !self.parser.compare_next(TokenKind::Eof) {
             self.statement();
         }
 
+        let prelude = std::mem::replace(&mut self.parser, program);
+        self.parser.had_error |= prelude.had_error;
+        self.parser.diagnostics.extend(prelude.diagnostics);
+    }
+
+    /// Switches to the version named by the program's `//! lang` pragma, if it has one
+    fn read_lang_pragma(&mut self) {
+        let Some((line, span)) = LangVersion::find_pragma(self.parser.lexer.program()) else {
+            return;
+        };
+
+        let number = &self.parser.lexer.program()[span.clone()];
+        match LangVersion::from_number(number) {
+            Some(version) => self.lang_version = version,
+            None => {
+                let message = format!(
+                    "'{number}' is not a language version, the latest is {}",
+                    LangVersion::LATEST
+                );
+                self.parser.error_at(
+                    ErrorCode::InvalidLangVersion,
+                    span.start,
+                    span.end,
+                    line,
+                    &message,
+                );
+                self.parser.handling_error = false;
+            }
+        }
+    }
+
+    /// Switches a VM that has already been compiled for to the behaviour of version,
+    /// redefining the natives that depend on it
+    pub(crate) fn set_lang_version(vm: &mut VM, version: LangVersion) {
+        for (name, native) in natives::versioned_natives(version) {
+            vm.define_native(name, native);
+        }
+        vm.bool_conditions = version >= LangVersion::V3;
+        vm.bool_operands = version >= LangVersion::V4;
+    }
+
+    fn check_unread_globals(&mut self) {
+        fn collect_reads(chunk: &Chunk, reads: &mut HashSet<u16>) {
+            for instruction in chunk.instructions() {
+                if matches!(instruction.op, OpCode::GetGlobal | OpCode::GetGlobalExt) {
+                    reads.insert(instruction.operand as u16);
+                }
+            }
+
+            for constant in chunk.constants.iter() {
+                if constant.is_obj() && constant.as_obj().kind() == ObjKind::Function {
+                    collect_reads(unsafe { &(*constant.as_obj().function).chunk }, reads);
+                }
+            }
+        }
+
+        let mut reads = HashSet::new();
+        collect_reads(self.chunk(), &mut reads);
+
+        let mut unread: Vec<Token> = self
+            .global_writes
+            .iter()
+            .filter(|(idx, _)| !reads.contains(idx) && !self.builtin_globals.contains(idx))
+            .map(|(_, token)| *token)
+            .collect();
+        unread.sort_by_key(|token| token.start);
+
+        for token in unread {
+            let message = format!(
+                "global '{}' is never read",
+                token.lexeme_str(self.parser.lexer.program())
+            );
+            self.warning(ErrorCode::UnreadGlobal, token, &message);
+        }
+    }
+
+    /// Warns about globals the program reads but that nothing ever assigns, which are
+    /// usually misspelt. A global assigned in code this compile doesn't see, like a
+    /// later line of the REPL, is only a warning away from being fine.
+    fn check_undefined_globals(&mut self) {
+        let globals = &self.vm.globals;
+        let defined = |idx: u16| {
+            self.global_writes.contains_key(&idx)
+                || self.builtin_globals.contains(&idx)
+                || !globals.get(idx).is_undef()
+        };
+
+        let mut undefined: Vec<Token> = self
+            .global_reads
+            .iter()
+            .filter(|(idx, _)| !defined(**idx))
+            .map(|(_, token)| *token)
+            .collect();
+        undefined.sort_by_key(|token| token.start);
+        if undefined.is_empty() {
+            return;
+        }
+
+        // the most recently added first, so the program's own globals are suggested
+        // before natives
+        let defined_names: Vec<&str> = (0..globals.globals.len() as u16)
+            .rev()
+            .filter(|idx| defined(*idx))
+            .map(|idx| globals.name(idx))
+            .collect();
+        let messages: Vec<(Token, String)> = undefined
+            .into_iter()
+            .map(|token| {
+                let name = token.lexeme_str(self.parser.lexer.program());
+                let mut message = format!("global '{name}' is read but never defined");
+                if let Some(closest) = closest_name(name, defined_names.iter().copied()) {
+                    message.push_str(&format!(", did you mean '{closest}'?"));
+                }
+                (token, message)
+            })
+            .collect();
+
+        for (token, message) in messages {
+            self.warning(ErrorCode::UndefinedGlobal, token, &message);
+        }
+    }
+
+    /// Warns about natives compared with `==` or `!=` rather than called, like
+    /// `read == "y"`, which is only true if the other side is the same native
+    fn check_compared_natives(&mut self) {
+        let compared = std::mem::take(&mut self.compared_globals);
+        for (idx, token) in compared {
+            if self.global_writes.contains_key(&idx) || !self.vm.globals.get(idx).is_native() {
+                continue;
+            }
+            let message = format!(
+                "comparing the native function '{}' rather than calling it, did you mean '{0}()'?",
+                token.lexeme_str(self.parser.lexer.program())
+            );
+            self.warning(ErrorCode::ComparedNative, token, &message);
+        }
+    }
+
+    /// Runs every pass over the script chunk and the chunks of all functions nested in it
+    fn run_passes(&mut self, timings: &mut Timings) {
+        fn collect_chunks(chunk: *mut Chunk, chunks: &mut Vec<*mut Chunk>) {
+            chunks.push(chunk);
+            for constant in unsafe { (*chunk).constants.iter() } {
+                if constant.is_obj() && constant.as_obj().kind() == ObjKind::Function {
+                    collect_chunks(unsafe { &mut (*constant.as_obj().function).chunk }, chunks);
+                }
+            }
+        }
+
+        let mut chunks = Vec::new();
+        collect_chunks(self.chunk_mut(), &mut chunks);
+
+        for pass in PASSES {
+            let start = Instant::now();
+            let mut counters = Counters::default();
+            for chunk in chunks.iter() {
+                (pass.run)(unsafe { &mut **chunk }, &mut counters);
+            }
+            timings.record(pass.name, start.elapsed(), counters);
+        }
+    }
+
+    pub fn compile(mut self) -> Result<VM, Vec<Diagnostic>> {
+        self.compile_script()?;
+        Ok(self.vm)
+    }
+
+    /// Like `compile`, but hands back the VM even when there are errors so a resumed
+    /// session can carry on
+    pub fn compile_line(mut self) -> (VM, Result<(), Vec<Diagnostic>>) {
+        let result = self.compile_script();
+        (self.vm, result)
+    }
+
+    fn compile_script(&mut self) -> Result<(), Vec<Diagnostic>> {
+        let mut timings = Timings::default();
+
+        if !self.repl {
+            self.read_lang_pragma();
+        }
+
+        if self.resumed {
+            if !self.repl {
+                Self::set_lang_version(&mut self.vm, self.lang_version);
+            }
+            // an earlier program may read what this one assigns, so it isn't unread
+            let globals = &self.vm.globals.globals;
+            self.builtin_globals
+                .extend((0..globals.len() as u16).filter(|idx| !globals[*idx as usize].is_undef()));
+        } else {
+            let start = Instant::now();
+            self.define_natives();
+            if self.use_prelude {
+                self.compile_prelude();
+            }
+            self.current().program_start = self.chunk().jump_target();
+            timings.record("prelude", start.elapsed(), Counters::default());
+        }
+
+        let start = Instant::now();
+        while !self.parser.compare_next(TokenKind::Eof) {
+            self.statement();
+        }
+
         self.push_opcode(OpCode::Null);
         self.push_opcode(OpCode::Return);
+        let mut counters = Counters::default();
+        counters.add("constants deduplicated", self.constants_reused);
+        timings.record("lex and parse", start.elapsed(), counters);
+
+        // jumps are left unpatched when there are errors, so the passes can't run
+        if !self.parser.had_error {
+            self.run_passes(&mut timings);
+
+            let start = Instant::now();
+            // every line of the repl would warn about the globals it defines
+            if !self.repl {
+                self.check_unread_globals();
+            }
+            self.check_undefined_globals();
+            self.check_compared_natives();
+            timings.record("lint", start.elapsed(), Counters::default());
+        }
 
-        #[cfg(feature = "decompile")]
-        self.chunk_mut().disassemble();
+        if self.timings {
+            self.vm.report(timings);
+        }
+
+        if cfg!(feature = "decompile") || self.disassemble {
+            self.current().disassemble();
+        }
 
         if self.parser.had_error {
-            std::process::exit(101);
+            let mut diagnostics = std::mem::take(&mut self.parser.diagnostics);
+            for diagnostic in diagnostics.iter_mut() {
+                diagnostic.file_name = self.file_name.clone();
+            }
+            return Err(diagnostics);
         }
 
-        let function = self.function_stack.pop().unwrap().function;
+        let mut function = self.function_stack.pop().unwrap().function;
+        function.source = Some(self.parser.lexer.source());
         let function = self.vm.alloc(function);
 
         self.vm.push_call_frame(function);
 
-        self.vm
+        Ok(())
     }
 }
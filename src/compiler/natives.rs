@@ -1,311 +1,1427 @@
 #![allow(clippy::useless_format)]
 
-use std::{ptr::NonNull, time::SystemTime};
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{self, BufRead, Write},
+    ops::RangeInclusive,
+    ptr::NonNull,
+    time::SystemTime,
+};
 
-use ordered_float::OrderedFloat;
+use super::LangVersion;
+use crate::vm::{
+    error::RuntimeError, hash::ValueMap, object::ObjBuffer, value::Value, NativeFn, VM,
+};
 
-use crate::vm::{object::ObjString, value::Value, VM};
+/// The arguments a native was called with. The getters check the type of an argument
+/// and report errors that name the native and which argument was wrong.
+#[derive(Clone, Copy)]
+struct NativeArgs {
+    name: &'static str,
+    count: u32,
+    args: NonNull<Value>,
+}
+
+impl NativeArgs {
+    /// Checks the native was called with a number of arguments in expected
+    fn new(
+        vm: &VM,
+        name: &'static str,
+        count: u32,
+        args: NonNull<Value>,
+        expected: RangeInclusive<u32>,
+    ) -> Result<Self, RuntimeError> {
+        if !expected.contains(&count) {
+            let (min, max) = expected.into_inner();
+            let expected = match max - min {
+                0 if min == 1 => format!("1 argument"),
+                0 => format!("{min} arguments"),
+                1 => format!("{min} or {max} arguments"),
+                _ => format!("{min} to {max} arguments"),
+            };
+            return Err(vm.native_error(format!("expected {expected} but got {count}")));
+        }
+
+        Ok(Self { name, count, args })
+    }
+
+    fn len(&self) -> u32 {
+        self.count
+    }
+
+    /// Argument i, which can be any value
+    fn any(&self, i: u32) -> Value {
+        debug_assert!(
+            i < self.count,
+            "argument {i} of '{}' wasn't checked",
+            self.name
+        );
+        unsafe { self.args.add(i as usize).read() }
+    }
+
+    /// Argument i, or None if the native was called with fewer arguments
+    fn optional(&self, i: u32) -> Option<Value> {
+        (i < self.count).then(|| self.any(i))
+    }
 
-pub fn native_time(_arg_count: u32, _args: NonNull<Value>, _vm: *mut VM) -> Value {
-    Value::float(
+    fn string(&self, vm: &VM, i: u32) -> Result<&str, RuntimeError> {
+        let value = self.any(i);
+        if !value.is_string() {
+            return Err(self.type_error(vm, i, "a string"));
+        }
+        // arguments stay on the stack until the native returns, so the string lives that long
+        Ok(unsafe { (*value.as_obj().string).value.as_ref() })
+    }
+
+    fn float(&self, vm: &VM, i: u32) -> Result<f64, RuntimeError> {
+        let value = self.any(i);
+        if !value.is_float() {
+            return Err(self.type_error(vm, i, "a number"));
+        }
+        Ok(value.as_float())
+    }
+
+    /// Argument i, which must be a number without a fractional part
+    fn integer(&self, vm: &VM, i: u32) -> Result<f64, RuntimeError> {
+        let value = self.any(i);
+        if !value.is_float() || value.as_float() != value.as_float().trunc() {
+            return Err(self.type_error(vm, i, "an integer"));
+        }
+        Ok(value.as_float())
+    }
+
+    /// Error for argument i not being what the native expected, which completes
+    /// "must be ..."
+    fn type_error(&self, vm: &VM, i: u32, expected: &str) -> RuntimeError {
+        vm.native_error(format!(
+            "argument {} to '{}' must be {expected}, got {}",
+            i + 1,
+            self.name,
+            self.any(i).describe()
+        ))
+    }
+}
+
+pub fn native_time(
+    _vm: &mut VM,
+    _arg_count: u32,
+    _args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    Ok(Value::float(
         SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs_f64(),
-    )
+    ))
 }
 
-pub fn native_print(arg_count: u32, args: NonNull<Value>, _vm: *mut VM) -> Value {
-    if arg_count == 0 {
-        println!();
-    } else {
-        for i in 0..arg_count {
-            println!("{}", unsafe { args.add(i as usize).read() })
+/// Prints the arguments on one line, separated by spaces
+pub fn native_print(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "print", arg_count, args, 0..=u32::MAX)?;
+    vm.write_output(format_args!("{}\n", join_args(args)));
+    Ok(Value::NULL)
+}
+
+/// Prints the arguments like print but without ending the line, for progress output
+pub fn native_print_raw(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "print_raw", arg_count, args, 0..=u32::MAX)?;
+    vm.write_output(format_args!("{}", join_args(args)));
+    vm.flush_output();
+    Ok(Value::NULL)
+}
+
+/// Prints the arguments like print, but to the error output rather than the output
+pub fn native_eprint(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "eprint", arg_count, args, 0..=u32::MAX)?;
+    vm.write_error_output(format_args!("{}\n", join_args(args)));
+    Ok(Value::NULL)
+}
+
+/// Every argument as print shows it, separated by spaces
+fn join_args(args: NativeArgs) -> String {
+    let mut line = String::new();
+    for i in 0..args.len() {
+        if i > 0 {
+            line.push(' ');
         }
+        write!(line, "{}", args.any(i)).unwrap();
     }
-    Value::NULL
+    line
 }
 
-pub fn native_read(arg_count: u32, args: NonNull<Value>, vm: *mut VM) -> Value {
-    unsafe {
-        if arg_count != 1 {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("expected 1 argument but got {arg_count}"),
-            );
-        }
-        let value = args.read();
-        if !value.is_string() {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("file path ({:?}) must be a string", value),
-            );
-        }
-        let Ok(text) = std::fs::read_to_string((*value.as_obj().string).value.as_ref()) else {
-            (*vm).runtime_error((*vm).frame().ip, format!("file ({:?}) not found", value));
-        };
-        let obj = ObjString::new(text.trim());
-        let obj = (*vm).alloc(obj);
-        Value::obj(obj)
+pub fn native_flush(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    NativeArgs::new(vm, "flush", arg_count, args, 0..=0)?;
+    vm.flush_output();
+    Ok(Value::NULL)
+}
+
+/// Stops the program with the given exit status, 0 if left out. It unwinds like an
+/// error so the output is still flushed and an embedding program gets control back
+pub fn native_exit(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "exit", arg_count, args, 0..=1)?;
+    let status = match args.optional(0) {
+        Some(_) => args.integer(vm, 0)?,
+        None => 0.0,
+    };
+    if !(0.0..=255.0).contains(&status) {
+        return Err(vm.native_error(format!("exit status must be from 0 to 255, got {status}")));
     }
+    let status = status as i32;
+
+    let mut err = vm.native_error(format!("exit({status}) was called"));
+    err.exit_status = Some(status);
+    Err(err)
 }
 
-pub fn native_num(arg_count: u32, args: NonNull<Value>, vm: *mut VM) -> Value {
-    unsafe {
-        if arg_count != 1 {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("expected 1 argument but got {arg_count}"),
-            );
-        }
-        let value = args.read();
-        if !value.is_string() {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!(
-                    "attemped to convert {:?}, but can only convert strings to numbers",
-                    value
-                ),
-            );
-        }
-        let Ok(num) = (*value.as_obj().string).value.trim().parse() else {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!(
-                    "attemped to convert {:?}, but string must represent a valid number",
-                    value
-                ),
-            );
-        };
-        Value::float(num)
+/// Natives whose behaviour depends on the language version
+pub fn versioned_natives(version: LangVersion) -> [(&'static str, NativeFn); 1] {
+    let read = if version >= LangVersion::V2 {
+        native_read
+    } else {
+        native_read_trimmed
+    };
+    [("read", read)]
+}
+
+pub fn native_read(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    read_impl(vm, arg_count, args, false)
+}
+
+/// `read` before version 2, which trimmed whitespace from both ends of the file
+pub fn native_read_trimmed(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    read_impl(vm, arg_count, args, true)
+}
+
+#[inline]
+fn read_impl(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+    trim: bool,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "read", arg_count, args, 1..=1)?;
+    let Ok(text) = std::fs::read_to_string(args.string(vm, 0)?) else {
+        return Err(vm.native_error(format!("file ({:?}) not found", args.any(0))));
+    };
+    let text = if trim {
+        text.trim()
+    } else {
+        text.strip_suffix('\n').map_or(text.as_str(), |text| {
+            text.strip_suffix('\r').unwrap_or(text)
+        })
+    };
+    let obj = vm.intern(text);
+    Ok(Value::obj(obj))
+}
+
+pub fn native_write(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "write", arg_count, args, 2..=2)?;
+    write_impl(vm, args, false)
+}
+
+pub fn native_append(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "append", arg_count, args, 2..=2)?;
+    write_impl(vm, args, true)
+}
+
+#[inline]
+fn write_impl(vm: &mut VM, args: NativeArgs, append: bool) -> Result<Value, RuntimeError> {
+    let path = args.string(vm, 0)?;
+    let text = args.string(vm, 1)?;
+
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .and_then(|mut file| file.write_all(text.as_bytes()));
+    if let Err(err) = result {
+        return Err(vm.native_error(format!(
+            "file ({:?}) could not be written: {err}",
+            args.any(0)
+        )));
+    }
+
+    Ok(Value::NULL)
+}
+
+pub fn native_args(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "args", arg_count, args, 1..=1)?;
+    let idx = args.integer(vm, 0)?;
+    if idx < 0.0 || idx as usize >= vm.args.len() {
+        return Ok(Value::NULL);
+    }
+
+    let arg = vm.args[idx as usize].clone();
+    let obj = vm.intern(&arg);
+    Ok(Value::obj(obj))
+}
+
+pub fn native_args_count(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    NativeArgs::new(vm, "args_count", arg_count, args, 0..=0)?;
+    Ok(Value::float(vm.args.len() as f64))
+}
+
+/// Depth of the function calling this, where the top level of the script is 1
+pub fn native_call_depth(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    NativeArgs::new(vm, "call_depth", arg_count, args, 0..=0)?;
+    Ok(Value::float(vm.call_depth() as f64))
+}
+
+/// Fills a namespace, "gc_stats" unless another is given, with what the collector has
+/// done so far: bytes allocated over the run, live now, live after the last collection,
+/// and at most at once, the number of collections, and the milliseconds they took
+pub fn native_gc_stats(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "gc_stats", arg_count, args, 0..=1)?;
+    // read before interning anything, which could start a collection
+    let stats = vm.gc_stats();
+    let key = match args.len() {
+        1 => args.any(0),
+        _ => Value::obj(vm.intern("gc_stats")),
+    };
+
+    let entries = [
+        ("allocated", stats.allocated as f64),
+        ("live", stats.live as f64),
+        ("live_after_collection", stats.live_after_collection as f64),
+        ("peak", stats.peak as f64),
+        ("collections", stats.collections as f64),
+        ("pause_ms", stats.total_pause.as_secs_f64() * 1000.0),
+    ];
+    let roots = vm.native_roots.len();
+    vm.native_roots.push(key);
+    for (name, value) in entries {
+        let name = Value::obj(vm.intern(name));
+        vm.globals
+            .global_map
+            .entry(key)
+            .or_default()
+            .insert(name, Value::float(value));
     }
+    vm.native_roots.truncate(roots);
+
+    Ok(Value::NULL)
+}
+
+/// Stack slots used by the caller at the point of the call, not counting the call
+/// to stack_slots_used itself
+pub fn native_stack_slots_used(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    NativeArgs::new(vm, "stack_slots_used", arg_count, args, 0..=0)?;
+    Ok(Value::float((vm.stack_slots_used() - 1) as f64))
 }
 
-pub fn native_abs(arg_count: u32, args: NonNull<Value>, vm: *mut VM) -> Value {
-    unsafe {
-        if arg_count != 1 {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("expected 1 argument but {arg_count}"),
-            );
+pub fn native_input(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "input", arg_count, args, 0..=1)?;
+    if args.len() == 1 {
+        let prompt = args.string(vm, 0)?;
+        vm.write_output(format_args!("{prompt}"));
+        vm.flush_output();
+    }
+
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) => return Ok(Value::NULL),
+        Ok(_) => (),
+        Err(err) => return Err(vm.native_error(format!("failed to read from stdin: {err}"))),
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
         }
-        let value = args.read();
-        if !value.is_float() {
-            (*vm).runtime_error((*vm).frame().ip, format!("attemped to get the absoute value of {:?}, but can only get the absolute value of numbers", value));
+    }
+
+    let obj = vm.intern(&line);
+    Ok(Value::obj(obj))
+}
+
+pub fn native_num(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "num", arg_count, args, 1..=1)?;
+    let Ok(num) = args.string(vm, 0)?.trim().parse::<f64>() else {
+        return Err(vm.native_error(format!(
+            "attemped to convert {:?}, but string must represent a valid number",
+            args.any(0)
+        )));
+    };
+    if !num.is_finite() {
+        return Err(vm.native_error(format!(
+            "attemped to convert {:?}, but numbers must be finite",
+            args.any(0)
+        )));
+    }
+    Ok(Value::float(num))
+}
+
+/// Like num, but returns null for strings that aren't numbers instead of erroring. An
+/// optional radix between 2 and 36 parses an integer in that base.
+pub fn native_parse_num(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "parse_num", arg_count, args, 1..=2)?;
+    let text = args.string(vm, 0)?.trim();
+
+    let num = if args.len() == 2 {
+        let radix = args.integer(vm, 1)?;
+        if !(2.0..=36.0).contains(&radix) {
+            return Err(vm.native_error(format!("radix must be between 2 and 36, got {radix}")));
         }
+        i128::from_str_radix(text, radix as u32)
+            .ok()
+            .map(|num| num as f64)
+    } else {
+        text.parse::<f64>().ok()
+    };
+
+    Ok(match num {
+        Some(num) if num.is_finite() => Value::float(num),
+        _ => Value::NULL,
+    })
+}
+
+/// Tests the type of the one argument, which can be anything
+fn test_type(
+    vm: &VM,
+    name: &'static str,
+    arg_count: u32,
+    args: NonNull<Value>,
+    test: fn(&Value) -> bool,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, name, arg_count, args, 1..=1)?;
+    Ok(Value::bool(test(&args.any(0))))
+}
+
+pub fn native_is_num(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    test_type(vm, "is_num", arg_count, args, Value::is_float)
+}
+
+pub fn native_is_string(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    test_type(vm, "is_string", arg_count, args, Value::is_string)
+}
+
+pub fn native_is_bool(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    test_type(vm, "is_bool", arg_count, args, Value::is_bool)
+}
+
+pub fn native_is_null(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    test_type(vm, "is_null", arg_count, args, Value::is_null)
+}
 
-        Value::float(value.as_float().abs())
+pub fn native_abs(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "abs", arg_count, args, 1..=1)?;
+    Ok(Value::float(args.float(vm, 0)?.abs()))
+}
+
+/// Converts any value to the string `print` would show for it
+pub fn native_str(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "str", arg_count, args, 1..=1)?;
+    let value = args.any(0);
+    if value.is_string() {
+        return Ok(value);
+    }
+
+    let string = vm.intern(&format!("{value}"));
+    Ok(Value::obj(string))
+}
+
+/// Most decimal places to_fixed will write
+const MAX_FIXED_PLACES: f64 = 100.0;
+
+/// Formats a number with exactly the given number of decimal places, rounding exact
+/// halves to even like 2.5 to 2
+pub fn native_to_fixed(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "to_fixed", arg_count, args, 2..=2)?;
+    let value = args.float(vm, 0)?;
+    let places = args.integer(vm, 1)?;
+    if !(0.0..=MAX_FIXED_PLACES).contains(&places) {
+        return Err(vm.native_error(format!(
+            "decimal places must be from 0 to {MAX_FIXED_PLACES}, got {places}"
+        )));
+    }
+
+    let string = vm.intern(&format!("{:.*}", places as usize, value));
+    Ok(Value::obj(string))
+}
+
+pub fn native_upper(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    map_string(vm, "upper", arg_count, args, |s| s.to_uppercase())
+}
+
+pub fn native_lower(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    map_string(vm, "lower", arg_count, args, |s| s.to_lowercase())
+}
+
+pub fn native_trim(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    map_string(vm, "trim", arg_count, args, |s| s.trim().to_owned())
+}
+
+pub fn native_replace(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "replace", arg_count, args, 3..=3)?;
+    let string = args.string(vm, 0)?;
+    let from = args.string(vm, 1)?;
+    let to = args.string(vm, 2)?;
+    if from.is_empty() {
+        return Err(vm.native_error(format!(
+            "attemped to replace \"\" in {string:?}, but the string to replace can't be empty"
+        )));
     }
+
+    let replaced = string.replace(from, to);
+    let string = vm.intern(&replaced);
+    Ok(Value::obj(string))
 }
 
-pub fn native_split(arg_count: u32, args: NonNull<Value>, vm: *mut VM) -> Value {
-    unsafe {
-        if !(1..=2).contains(&arg_count) {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("expected 1 or 2 arguments but got {arg_count}"),
-            );
+pub fn native_contains(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "contains", arg_count, args, 2..=2)?;
+    let contains = args.string(vm, 0)?.contains(args.string(vm, 1)?);
+    Ok(Value::bool(contains))
+}
+
+/// Index of the first occurrence of a needle at or after an optional start index, or -1.
+/// Indices count characters rather than bytes, the same as the keys `chars` uses.
+pub fn native_find(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "find", arg_count, args, 2..=3)?;
+    let string = args.string(vm, 0)?;
+    let needle = args.string(vm, 1)?;
+    let start = match args.len() {
+        3 => {
+            let start = args.integer(vm, 2)?;
+            if start < 0.0 {
+                return Err(vm.native_error(format!(
+                    "start index must be a non-negative integer, got {start}"
+                )));
+            }
+            start as usize
         }
-        let key = {
-            let obj = ObjString::new("split");
-            let obj = (*vm).alloc(obj);
-            Value::obj(obj)
+        _ => 0,
+    };
+
+    // a start at the end can still find an empty needle
+    let start_byte = string
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain([string.len()])
+        .nth(start);
+    let index = start_byte.and_then(|start_byte| {
+        let found = string[start_byte..].find(needle)?;
+        Some(start + string[start_byte..start_byte + found].chars().count())
+    });
+
+    Ok(Value::float(index.map_or(-1.0, |index| index as f64)))
+}
+
+pub fn native_starts_with(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "starts_with", arg_count, args, 2..=2)?;
+    let starts_with = args.string(vm, 0)?.starts_with(args.string(vm, 1)?);
+    Ok(Value::bool(starts_with))
+}
+
+pub fn native_ends_with(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "ends_with", arg_count, args, 2..=2)?;
+    let ends_with = args.string(vm, 0)?.ends_with(args.string(vm, 1)?);
+    Ok(Value::bool(ends_with))
+}
+
+/// Calls f on a native's only argument, which must be a string, and returns the result
+fn map_string(
+    vm: &mut VM,
+    name: &'static str,
+    arg_count: u32,
+    args: NonNull<Value>,
+    f: impl FnOnce(&str) -> String,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, name, arg_count, args, 1..=1)?;
+    let string = vm.intern(&f(args.string(vm, 0)?));
+    Ok(Value::obj(string))
+}
+
+pub fn native_split(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "split", arg_count, args, 1..=2)?;
+    let key = {
+        let obj = vm.intern("split");
+        Value::obj(obj)
+    };
+    split_impl(vm, args, key, args.len() == 1)
+}
+
+pub fn native_split_into(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "split_into", arg_count, args, 2..=3)?;
+    let key = args.any(args.len() - 1);
+    split_impl(vm, args, key, args.len() == 2)
+}
+
+#[inline]
+fn split_impl(
+    vm: &mut VM,
+    args: NativeArgs,
+    key: Value,
+    whitespace: bool,
+) -> Result<Value, RuntimeError> {
+    let str = args.string(vm, 0)?;
+
+    let count = if whitespace {
+        store_pieces(vm, key, str.split_whitespace())
+    } else {
+        let pat = args.string(vm, 1)?;
+        store_pieces(vm, key, str.split(pat))
+    };
+
+    Ok(Value::float(count as f64))
+}
+
+/// Interns each piece and stores them at indices 0, 1, ... of a namespace, returning
+/// how many there were. The key and pieces are rooted while the rest are interned, so
+/// the namespace is only looked up once, with room for all of them reserved.
+fn store_pieces<'a>(vm: &mut VM, key: Value, pieces: impl Iterator<Item = &'a str>) -> usize {
+    let roots = vm.native_roots.len();
+    // a key the native interned itself isn't anywhere else the collector looks
+    vm.native_roots.push(key);
+    for piece in pieces {
+        let obj = vm.intern(piece);
+        vm.native_roots.push(Value::obj(obj));
+    }
+
+    let pieces = &vm.native_roots[roots + 1..];
+    let map = vm.globals.global_map.entry(key).or_default();
+    map.reserve(pieces.len());
+    for (i, piece) in pieces.iter().enumerate() {
+        map.insert(Value::float(i as f64), *piece);
+    }
+
+    let count = pieces.len();
+    vm.native_roots.truncate(roots);
+    count
+}
+
+pub fn native_chars(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "chars", arg_count, args, 1..=1)?;
+    let key = {
+        let obj = vm.intern("chars");
+        Value::obj(obj)
+    };
+    chars_impl(vm, args, key)
+}
+
+pub fn native_chars_into(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "chars_into", arg_count, args, 2..=2)?;
+    let key = args.any(1);
+    chars_impl(vm, args, key)
+}
+
+fn chars_impl(vm: &mut VM, args: NativeArgs, key: Value) -> Result<Value, RuntimeError> {
+    let str = args.string(vm, 0)?;
+    let chars = str.char_indices().map(|(i, c)| &str[i..i + c.len_utf8()]);
+    let count = store_pieces(vm, key, chars);
+
+    Ok(Value::float(count as f64))
+}
+
+pub fn native_encode_into(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "encode_into", arg_count, args, 2..=2)?;
+    let str = args.string(vm, 0)?;
+    let key = args.any(1);
+
+    let map = vm.globals.global_map.entry(key).or_default();
+    let mut count = 0.0;
+    for c in str.chars() {
+        map.insert(Value::float(count), Value::float(c as u32 as f64));
+        count += 1.0;
+    }
+
+    Ok(Value::float(count))
+}
+
+pub fn native_decode(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "decode", arg_count, args, 3..=3)?;
+    let key = args.any(0);
+    let Some(map) = vm.globals.global_map.get(&key) else {
+        return Err(vm.native_error(format!("'{key}' has no values associated with it")));
+    };
+
+    let (start, end) = index_range(vm, args, 1, "decode")?;
+
+    let mut str = String::with_capacity(end - start);
+    for i in start..end {
+        let Some(value) = map.get(&Value::float(i as f64)) else {
+            return Err(vm.native_error(format!("no value at index {i}")));
+        };
+        let c = if value.is_float() && value.as_float() == value.as_float().round() {
+            char::from_u32(value.as_float() as u32).filter(|_| value.as_float() >= 0.0)
+        } else {
+            None
+        };
+        let Some(c) = c else {
+            return Err(vm.native_error(format!(
+                "attempted to decode {:?}, but can only decode valid unicode scalar values",
+                value
+            )));
         };
-        split_impl(args, vm, key, arg_count == 1)
+        str.push(c);
     }
+
+    let obj = vm.intern(&str);
+    Ok(Value::obj(obj))
 }
 
-pub fn native_split_into(arg_count: u32, args: NonNull<Value>, vm: *mut VM) -> Value {
-    unsafe {
-        if !(2..=3).contains(&arg_count) {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("expected 2 or 3 arguments but got {arg_count}"),
-            );
-        }
-        let key = args.add(arg_count as usize - 1).read();
-        split_impl(args, vm, key, arg_count == 2)
+/// Unicode scalar value of a one character string
+pub fn native_ord(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "ord", arg_count, args, 1..=1)?;
+    let mut chars = args.string(vm, 0)?.chars();
+    let Some(c) = chars.next().filter(|_| chars.next().is_none()) else {
+        return Err(vm.native_error(format!(
+            "attempted to get the code of {:?}, but can only get the code of one character strings",
+            args.any(0)
+        )));
+    };
+
+    Ok(Value::float(c as u32 as f64))
+}
+
+/// One character string with the given Unicode scalar value
+pub fn native_chr(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "chr", arg_count, args, 1..=1)?;
+    let code = args.integer(vm, 0)?;
+    let c = if (0.0..=char::MAX as u32 as f64).contains(&code) {
+        char::from_u32(code as u32)
+    } else {
+        None
+    };
+    let Some(c) = c else {
+        return Err(vm.native_error(format!(
+            "attempted to get the character for {code}, but can only use unicode scalar values"
+        )));
+    };
+
+    let string = vm.intern(c.encode_utf8(&mut [0; 4]));
+    Ok(Value::obj(string))
+}
+
+/// Reads arguments i and i + 1 of a native that works on a range of a namespace, like a
+/// sort, which must be integers with start <= end
+fn index_range(
+    vm: &VM,
+    args: NativeArgs,
+    i: u32,
+    action: &str,
+) -> Result<(usize, usize), RuntimeError> {
+    let start = args.integer(vm, i)?;
+    let end = args.integer(vm, i + 1)?;
+    if start < 0.0 || start > end {
+        return Err(vm.native_error(format!(
+            "can't {action} from {start} to {end}, start must be between 0 and end"
+        )));
     }
+
+    Ok((start as usize, end as usize))
 }
 
-#[inline]
-pub fn split_impl(args: NonNull<Value>, vm: *mut VM, key: Value, whitespace: bool) -> Value {
-    unsafe {
-        let value = args.read();
-        if !value.is_string() {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("attemped to split {:?}, but can only split strings", value),
-            );
+/// Reads the values at indices start..end of the namespace key
+fn range_values(vm: &VM, key: Value, start: usize, end: usize) -> Result<Vec<Value>, RuntimeError> {
+    let Some(map) = vm.globals.global_map.get(&key) else {
+        return Err(vm.native_error(format!("'{key}' has no values associated with it")));
+    };
+
+    (start..end)
+        .map(|i| match map.get(&Value::float(i as f64)) {
+            Some(value) => Ok(*value),
+            None => Err(vm.native_error(format!("no value at index {i} of '{key}'"))),
+        })
+        .collect()
+}
+
+/// Stable order of the values, which must be all numbers or all strings, as indices
+/// into the slice. Equal values keep the order they were in.
+fn sort_order(
+    vm: &VM,
+    values: &[Value],
+    start: usize,
+    descending: bool,
+) -> Result<Vec<usize>, RuntimeError> {
+    let strings = values.first().is_some_and(|value| value.is_string());
+    if let Some((i, value)) = values.iter().enumerate().find(|(_, value)| {
+        if strings {
+            !value.is_string()
+        } else {
+            !value.is_float()
         }
-        let str = (*value.as_obj().string).value.as_ref();
-
-        let mut count = 0.0;
-        if whitespace {
-            for (i, x) in str.split_whitespace().enumerate() {
-                count += 1.0;
-                let obj = ObjString::new(x);
-                let obj = (*vm).alloc(obj);
-                (*vm)
-                    .globals
-                    .global_map
-                    .entry(key)
-                    .or_default()
-                    .insert(Value::float(i as f64), Value::obj(obj));
+    }) {
+        return Err(vm.native_error(format!(
+            "attemped to sort {:?} at index {}, but can only sort all numbers or all strings",
+            value,
+            start + i
+        )));
+    }
+
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    let compare = |a: &usize, b: &usize| {
+        let (a, b) = if descending { (b, a) } else { (a, b) };
+        if strings {
+            unsafe {
+                (*values[*a].as_obj().string)
+                    .value
+                    .cmp(&(*values[*b].as_obj().string).value)
             }
         } else {
-            let pat = args.add(1).read();
-            if !pat.is_string() {
-                (*vm).runtime_error(
-                    (*vm).frame().ip,
-                    format!("split pattern ({:?}) must be a string", value),
-                );
-            }
-            let pat = (*pat.as_obj().string).value.as_ref();
-
-            for (i, x) in str.split(pat).enumerate() {
-                count += 1.0;
-                let obj = ObjString::new(x);
-                let obj = (*vm).alloc(obj);
-                (*vm)
-                    .globals
-                    .global_map
-                    .entry(key)
-                    .or_default()
-                    .insert(Value::float(i as f64), Value::obj(obj));
-            }
+            // numbers are always finite so this is a total order
+            values[*a].as_float().total_cmp(&values[*b].as_float())
         }
+    };
+    order.sort_by(compare);
 
-        Value::float(count)
-    }
+    Ok(order)
 }
 
-pub fn native_chars(arg_count: u32, args: NonNull<Value>, vm: *mut VM) -> Value {
-    unsafe {
-        if arg_count != 1 {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("expected 1 argument but got {arg_count}"),
-            );
+/// Reads optional argument i of a sort, "asc" or "desc", or a bool that's true for
+/// descending
+fn sort_descending(vm: &VM, args: NativeArgs, i: u32) -> Result<bool, RuntimeError> {
+    let Some(order) = args.optional(i) else {
+        return Ok(false);
+    };
+    if order.is_bool() {
+        return Ok(order.as_bool());
+    }
+    if order.is_string() {
+        match unsafe { (*order.as_obj().string).value.as_ref() } {
+            "asc" => return Ok(false),
+            "desc" => return Ok(true),
+            _ => (),
         }
-        let key = {
-            let obj = ObjString::new("chars");
-            let obj = (*vm).alloc(obj);
-            Value::obj(obj)
-        };
-        chars_impl(args, vm, key)
     }
+
+    Err(args.type_error(vm, i, "\"asc\", \"desc\", or a bool"))
 }
 
-pub fn native_chars_into(arg_count: u32, args: NonNull<Value>, vm: *mut VM) -> Value {
-    unsafe {
-        if arg_count != 2 {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("expected 2 arguments but got {arg_count}"),
-            );
-        }
-        let key = args.add(1).read();
-        chars_impl(args, vm, key)
+/// Writes values back to indices start.. of the namespace key in the given order
+fn write_sorted(vm: &mut VM, key: Value, start: usize, values: &[Value], order: &[usize]) {
+    let map = vm.globals.global_map.entry(key).or_default();
+    for (i, idx) in order.iter().enumerate() {
+        map.insert(Value::float((start + i) as f64), values[*idx]);
     }
 }
 
-pub fn chars_impl(args: NonNull<Value>, vm: *mut VM, key: Value) -> Value {
-    unsafe {
-        let value = args.read();
-        if !value.is_string() {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!(
-                    "attempted to get chars of {:?}, but can only get chars of strings",
+pub fn native_sort(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "sort", arg_count, args, 3..=4)?;
+    let key = args.any(0);
+    let (start, end) = index_range(vm, args, 1, "sort")?;
+    let descending = sort_descending(vm, args, 3)?;
+    let values = range_values(vm, key, start, end)?;
+    let order = sort_order(vm, &values, start, descending)?;
+    write_sorted(vm, key, start, &values, &order);
+
+    Ok(Value::NULL)
+}
+
+/// Reverses the order of the values at indices start..end of a namespace
+pub fn native_reverse(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "reverse", arg_count, args, 3..=3)?;
+    let key = args.any(0);
+    let (start, end) = index_range(vm, args, 1, "reverse")?;
+    let values = range_values(vm, key, start, end)?;
+    let order: Vec<usize> = (0..values.len()).rev().collect();
+    write_sorted(vm, key, start, &values, &order);
+
+    Ok(Value::NULL)
+}
+
+/// Combines the numbers at indices start..end of a namespace with f, or None if the
+/// range is empty
+fn fold_range(
+    vm: &VM,
+    name: &'static str,
+    arg_count: u32,
+    args: NonNull<Value>,
+    action: &str,
+    f: fn(f64, f64) -> f64,
+) -> Result<Option<f64>, RuntimeError> {
+    let args = NativeArgs::new(vm, name, arg_count, args, 3..=3)?;
+    let key = args.any(0);
+    let (start, end) = index_range(vm, args, 1, action)?;
+    if start == end {
+        return Ok(None);
+    }
+    let Some(map) = vm.globals.global_map.get(&key) else {
+        return Err(vm.native_error(format!("'{key}' has no values associated with it")));
+    };
+
+    let mut result = None;
+    for i in start..end {
+        let value = match map.get(&Value::float(i as f64)) {
+            Some(value) if value.is_float() => value.as_float(),
+            Some(value) => {
+                return Err(vm.native_error(format!(
+                    "attemped to {action} {:?} at index {i}, but can only {action} numbers",
                     value
-                ),
-            );
-        }
-        let str = (*value.as_obj().string).value.as_ref();
-
-        let mut count = 0.0;
-        for x in str.chars() {
-            let obj = ObjString::new(&x.to_string());
-            let obj = (*vm).alloc(obj);
-            (*vm)
-                .globals
-                .global_map
-                .entry(key)
-                .or_default()
-                .insert(Value::float(count), Value::obj(obj));
-            count += 1.0;
-        }
+                )))
+            }
+            None => return Err(vm.native_error(format!("no value at index {i} of '{key}'"))),
+        };
+        result = Some(result.map_or(value, |result| f(result, value)));
+    }
 
-        Value::float(count)
+    Ok(result)
+}
+
+/// Smallest number at indices start..end of a namespace, null if the range is empty
+pub fn native_min_of(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let min = fold_range(vm, "min_of", arg_count, args, "compare", f64::min)?;
+    Ok(min.map_or(Value::NULL, Value::float))
+}
+
+/// Largest number at indices start..end of a namespace, null if the range is empty
+pub fn native_max_of(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let max = fold_range(vm, "max_of", arg_count, args, "compare", f64::max)?;
+    Ok(max.map_or(Value::NULL, Value::float))
+}
+
+/// Total of the numbers at indices start..end of a namespace, 0 if the range is empty
+pub fn native_sum(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let sum = fold_range(vm, "sum", arg_count, args, "sum", |a, b| a + b)?.unwrap_or(0.0);
+    // numbers are always finite, like the result of +
+    if !sum.is_finite() {
+        return Err(vm.native_error(format!("sum is too large to be a number")));
     }
+    Ok(Value::float(sum))
 }
 
-pub fn native_sort(arg_count: u32, args: NonNull<Value>, vm: *mut VM) -> Value {
-    unsafe {
-        if arg_count != 3 {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("expected 3 arguments but got {arg_count}"),
-            );
-        }
+/// Joins the values at indices 0, 1, ... of a namespace, up to the first missing one,
+/// into one string with a separator between them
+pub fn native_join(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "join", arg_count, args, 2..=2)?;
+    let key = args.any(0);
+    let separator = args.string(vm, 1)?;
+    let Some(map) = vm.globals.global_map.get(&key) else {
+        return Err(vm.native_error(format!("'{key}' has no values associated with it")));
+    };
 
-        let key = args.read();
-        let Some(map) = (*vm).globals.global_map.get_mut(&key) else {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("'{key}' has no values associated with it"),
-            )
+    let mut joined = String::new();
+    for i in 0.. {
+        let Some(value) = map.get(&Value::float(i as f64)) else {
+            break;
         };
-
-        let start = args.add(1).read();
-        let end = args.add(2).read();
-        if !start.is_float() || !end.is_float() {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("can only sort data indexed by numbers"),
-            );
-        }
-        let start = start.as_float();
-        let end = end.as_float();
-        if start != start.round() || end != end.round() {
-            (*vm).runtime_error(
-                (*vm).frame().ip,
-                format!("can only sort data indexed by integers"),
-            );
+        if i > 0 {
+            joined.push_str(separator);
         }
-        let start = start as usize;
-        let end = end as usize;
+        write!(joined, "{value}").unwrap();
+    }
 
-        let mut buf = Vec::with_capacity(end - start);
+    let string = vm.intern(&joined);
+    Ok(Value::obj(string))
+}
 
-        for i in start..end {
-            let Some(value) = map.get(&Value::float(i as f64)) else {
-                (*vm).runtime_error((*vm).frame().ip, format!("no value at index {i}"));
-            };
-            if !value.is_float() {
-                (*vm).runtime_error(
-                    (*vm).frame().ip,
-                    format!("attemped to sort {:?}, but can only sort numbers", value),
-                );
+/// Sorts the keys namespace and moves the values namespace along with it, so equal
+/// keys keep their values in the original order
+pub fn native_sort_pairs(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "sort_pairs", arg_count, args, 4..=5)?;
+    let key_ns = args.any(0);
+    let value_ns = args.any(1);
+    let (start, end) = index_range(vm, args, 2, "sort")?;
+    let descending = sort_descending(vm, args, 4)?;
+    let keys = range_values(vm, key_ns, start, end)?;
+    let values = range_values(vm, value_ns, start, end)?;
+    let order = sort_order(vm, &keys, start, descending)?;
+    write_sorted(vm, key_ns, start, &keys, &order);
+    write_sorted(vm, value_ns, start, &values, &order);
+
+    Ok(Value::NULL)
+}
+
+/// Whether the comparator of sort_by puts a before b
+fn sorts_before(vm: &mut VM, compare: Value, a: Value, b: Value) -> Result<bool, RuntimeError> {
+    let before = vm.call_function(compare, &[a, b])?;
+    if !before.is_bool() {
+        return Err(vm.native_error(format!(
+            "sort comparator must return a bool, true when a goes before b, got {:?}",
+            before
+        )));
+    }
+    Ok(before.as_bool())
+}
+
+/// Sorts the values at indices start..end of a namespace with a function that takes two
+/// values and returns true when the first goes before the second. Stable, so values the
+/// comparator doesn't separate keep their order.
+pub fn native_sort_by(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    // the comparator can grow the stack, so read every argument before calling it
+    let args = NativeArgs::new(vm, "sort_by", arg_count, args, 4..=4)?;
+    let key = args.any(0);
+    let (start, end) = index_range(vm, args, 1, "sort")?;
+    let compare = args.any(3);
+    let values = range_values(vm, key, start, end)?;
+
+    // the comparator can overwrite the values in the namespace while they're being sorted
+    let roots = vm.native_roots.len();
+    vm.native_roots.extend_from_slice(&values);
+
+    // bottom up merge sort, which only needs one comparison call per step
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    let mut merged = Vec::with_capacity(values.len());
+    let mut width = 1;
+    let result = 'sort: {
+        while width < order.len() {
+            merged.clear();
+            for low in (0..order.len()).step_by(width * 2) {
+                let mid = usize::min(low + width, order.len());
+                let high = usize::min(low + width * 2, order.len());
+                let (mut i, mut j) = (low, mid);
+                while i < mid && j < high {
+                    match sorts_before(vm, compare, values[order[j]], values[order[i]]) {
+                        Ok(true) => {
+                            merged.push(order[j]);
+                            j += 1;
+                        }
+                        Ok(false) => {
+                            merged.push(order[i]);
+                            i += 1;
+                        }
+                        Err(err) => break 'sort Err(err),
+                    }
+                }
+                merged.extend_from_slice(&order[i..mid]);
+                merged.extend_from_slice(&order[j..high]);
             }
+            std::mem::swap(&mut order, &mut merged);
+            width *= 2;
+        }
+        Ok(())
+    };
+
+    vm.native_roots.truncate(roots);
+    result?;
+    write_sorted(vm, key, start, &values, &order);
+
+    Ok(Value::NULL)
+}
+
+/// The index a key is at when its namespace is used as an array, if it's one of
+/// 0, 1, ...
+fn array_index(key: &Value) -> Option<usize> {
+    let index = key.as_float();
+    (key.is_float() && index >= 0.0 && index == index.trunc()).then_some(index as usize)
+}
+
+/// Number of values at indices 0, 1, ... of a namespace used as an array, up to the
+/// first index without one. Other keys and values after a gap don't count.
+fn array_len(map: &ValueMap) -> usize {
+    // n different indices that are all below n can only be 0 to n - 1, which is the
+    // usual case of the array being the only thing in its namespace
+    let len = map.len();
+    if map
+        .keys()
+        .all(|key| array_index(key).is_some_and(|index| index < len))
+    {
+        return len;
+    }
+
+    array_len_from(map, 0)
+}
+
+/// First index from start on without a value, when every index below start has one
+fn array_len_from(map: &ValueMap, start: usize) -> usize {
+    (start..)
+        .find(|i| !map.contains_key(&Value::float(*i as f64)))
+        .unwrap()
+}
+
+/// Appends a value at the end of a namespace used as an array and returns its new length
+pub fn native_push(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "push", arg_count, args, 2..=2)?;
+    let map = vm.globals.global_map.entry(args.any(0)).or_default();
+    let len = array_len(map);
+    map.insert(Value::float(len as f64), args.any(1));
+
+    // filling a gap joins the array up with the values after it
+    Ok(Value::float(array_len_from(map, len + 1) as f64))
+}
 
-            buf.push(std::mem::transmute::<f64, OrderedFloat<f64>>(
-                value.as_float(),
-            ));
+/// Removes and returns the value at the highest index of a namespace used as an array,
+/// or null if it has none
+pub fn native_pop(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "pop", arg_count, args, 1..=1)?;
+    let Some(map) = vm.globals.global_map.get_mut(&args.any(0)) else {
+        return Ok(Value::NULL);
+    };
+    let Some(last) = map.keys().filter_map(array_index).max() else {
+        return Ok(Value::NULL);
+    };
+
+    Ok(map
+        .remove(&Value::float(last as f64))
+        .unwrap_or(Value::NULL))
+}
+
+/// Inserts a value at an index of a namespace used as an array, moving the values after
+/// it up by one, and returns its new length
+pub fn native_insert(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "insert", arg_count, args, 3..=3)?;
+    let key = args.any(0);
+    let index = args.integer(vm, 1)?;
+    let len = vm.globals.global_map.get(&key).map_or(0, array_len);
+    if index < 0.0 || index > len as f64 {
+        return Err(vm.native_error(format!(
+            "can't insert at {index}, index must be between 0 and the length ({len})"
+        )));
+    }
+
+    let index = index as usize;
+    let map = vm.globals.global_map.entry(key).or_default();
+    for i in (index..len).rev() {
+        if let Some(moved) = map.remove(&Value::float(i as f64)) {
+            map.insert(Value::float((i + 1) as f64), moved);
         }
+    }
+    map.insert(Value::float(index as f64), args.any(2));
+
+    // moving the values up can close a gap after them
+    Ok(Value::float(array_len_from(map, len + 1) as f64))
+}
+
+/// Whether a map has a value for a key, looking in the same places as indexing it does
+pub fn native_has(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "has", arg_count, args, 2..=2)?;
+    Ok(Value::bool(vm.map_get(args.any(0), args.any(1)).is_some()))
+}
+
+/// The value a map has for a key, or the default when it doesn't have one
+pub fn native_get_or(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "get_or", arg_count, args, 3..=3)?;
+    Ok(vm.map_get(args.any(0), args.any(1)).unwrap_or(args.any(2)))
+}
+
+/// Removes a key from a map and returns the value it had, or null if it had none
+pub fn native_remove(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "remove", arg_count, args, 2..=2)?;
+    Ok(vm
+        .map_remove(args.any(0), args.any(1))
+        .unwrap_or(Value::NULL))
+}
 
-        buf.sort_unstable();
+/// Number of keys in a map, 0 for one that was never written to
+pub fn native_count(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "count", arg_count, args, 1..=1)?;
+    Ok(Value::float(vm.map_count(args.any(0)) as f64))
+}
+
+/// Removes every key from a map
+pub fn native_clear(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "clear", arg_count, args, 1..=1)?;
+    vm.map_clear(args.any(0));
+
+    Ok(Value::NULL)
+}
 
-        for i in start..end {
-            map.insert(
-                Value::float(i as f64),
-                Value::float(std::mem::transmute::<OrderedFloat<f64>, f64>(
-                    buf[i - start],
-                )),
-            );
+/// Makes room in a namespace for n more entries, for scripts that know how many they'll
+/// write, so it isn't grown and rehashed along the way
+pub fn native_reserve(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "reserve", arg_count, args, 2..=2)?;
+    let n = args.integer(vm, 1)?;
+    if n < 0.0 {
+        return Err(vm.native_error(format!(
+            "number of entries to reserve must be a non-negative integer, got {n}"
+        )));
+    }
+    let map = vm.globals.global_map.entry(args.any(0)).or_default();
+    if map.try_reserve(n as usize).is_err() {
+        return Err(vm.native_error(format!("can't reserve room for {n} entries")));
+    }
+
+    Ok(Value::NULL)
+}
+
+/// Whether two namespaces hold the same entries, a namespace that was never written
+/// to is equal to an empty one
+pub fn native_map_equals(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "map_equals", arg_count, args, 2..=2)?;
+    let a = vm.globals.global_map.get(&args.any(0));
+    let b = vm.globals.global_map.get(&args.any(1));
+    let equal = match (a, b) {
+        (Some(a), Some(b)) => a == b,
+        (Some(map), None) | (None, Some(map)) => map.is_empty(),
+        (None, None) => true,
+    };
+
+    Ok(Value::bool(equal))
+}
+
+/// Replaces the entries of the destination namespace with a copy of the source's
+pub fn native_map_copy(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "map_copy", arg_count, args, 2..=2)?;
+    let dest = args.any(1);
+    match vm.globals.global_map.get(&args.any(0)).cloned() {
+        Some(map) => {
+            vm.globals.global_map.insert(dest, map);
+        }
+        None => {
+            vm.globals.global_map.remove(&dest);
         }
+    }
+
+    Ok(Value::NULL)
+}
+
+/// Creates a buffer, optionally starting with the contents of a string
+pub fn native_buffer(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "buffer", arg_count, args, 0..=1)?;
+    let initial = if args.len() == 1 {
+        args.string(vm, 0)?
+    } else {
+        ""
+    };
+
+    let buffer = vm.alloc(ObjBuffer::new(initial));
+    Ok(Value::obj(buffer))
+}
 
-        Value::NULL
+/// Copies the current contents of a buffer into a string
+pub fn native_to_str(
+    vm: &mut VM,
+    arg_count: u32,
+    args: NonNull<Value>,
+) -> Result<Value, RuntimeError> {
+    let args = NativeArgs::new(vm, "to_str", arg_count, args, 1..=1)?;
+    let value = args.any(0);
+    if !value.is_buffer() {
+        return Err(args.type_error(vm, 0, "a buffer"));
     }
+
+    let string = vm.intern(unsafe { &(*value.as_obj().buffer).value });
+    Ok(Value::obj(string))
 }
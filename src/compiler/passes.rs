@@ -0,0 +1,83 @@
+use std::{
+    fmt::{self, Display},
+    time::Duration,
+};
+
+use crate::vm::chunk::Chunk;
+
+/// A transformation or check run over every chunk once parsing has finished
+pub struct Pass {
+    pub name: &'static str,
+    pub run: fn(&mut Chunk, &mut Counters),
+}
+
+/// Passes in the order they run, each one sees every chunk before the next starts
+pub const PASSES: &[Pass] = &[Pass {
+    name: "validate",
+    run: validate,
+}];
+
+/// Named statistics a phase reports, like how many instructions it removed
+#[derive(Default)]
+pub struct Counters(Vec<(&'static str, usize)>);
+
+impl Counters {
+    pub fn add(&mut self, name: &'static str, count: usize) {
+        match self.0.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, total)) => *total += count,
+            None => self.0.push((name, count)),
+        }
+    }
+}
+
+struct Phase {
+    name: &'static str,
+    duration: Duration,
+    counters: Counters,
+}
+
+/// How long each compiler phase took, printed with --timings
+#[derive(Default)]
+pub struct Timings {
+    phases: Vec<Phase>,
+}
+
+impl Timings {
+    pub fn record(&mut self, name: &'static str, duration: Duration, counters: Counters) {
+        self.phases.push(Phase {
+            name,
+            duration,
+            counters,
+        });
+    }
+}
+
+impl Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "compiler timings:")?;
+        for phase in self.phases.iter() {
+            write!(
+                f,
+                "  {:20} {:>10.3}ms",
+                phase.name,
+                phase.duration.as_secs_f64() * 1000.0
+            )?;
+            for (name, count) in phase.counters.0.iter() {
+                write!(f, "  {count} {name}")?;
+            }
+            writeln!(f)?;
+        }
+
+        let total: Duration = self.phases.iter().map(|phase| phase.duration).sum();
+        write!(
+            f,
+            "  {:20} {:>10.3}ms",
+            "total",
+            total.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+fn validate(chunk: &mut Chunk, _counters: &mut Counters) {
+    debug_assert_eq!(chunk.validate(), Ok(()), "compiler emitted bad bytecode");
+}
@@ -0,0 +1,193 @@
+use std::{
+    fmt::{self, Display},
+    io::Write,
+};
+
+use crate::{
+    compiler::{Compiler, Diagnostic, LangVersion},
+    vm::{error::RuntimeError, value::Value, NativeFn, VM},
+};
+
+/// Why a call to [`Interpreter::interpret`] failed
+#[derive(Debug)]
+pub enum Error {
+    /// The source didn't compile, nothing was run
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Compile(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{diagnostic}")?;
+                }
+                Ok(())
+            }
+            Error::Runtime(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// The status the script asked to stop with, if it called exit rather than failing
+    pub fn exit_status(&self) -> Option<i32> {
+        match self {
+            Error::Runtime(err) => err.exit_status,
+            Error::Compile(_) => None,
+        }
+    }
+}
+
+impl From<Vec<Diagnostic>> for Error {
+    fn from(diagnostics: Vec<Diagnostic>) -> Self {
+        Error::Compile(diagnostics)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(err: RuntimeError) -> Self {
+        Error::Runtime(err)
+    }
+}
+
+/// A VM that runs one piece of source after another, keeping globals and the global
+/// map between them
+pub struct Interpreter {
+    /// Only taken while a source is being compiled, since the compiler owns the VM
+    vm: Option<VM>,
+    /// Version for sources without a `//! lang` pragma
+    lang_version: LangVersion,
+    /// Turns warnings into errors
+    pub strict: bool,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_prelude(true)
+    }
+
+    /// An interpreter with only the natives defined
+    pub fn without_prelude() -> Self {
+        Self::with_prelude(false)
+    }
+
+    fn with_prelude(use_prelude: bool) -> Self {
+        let mut compiler = Compiler::new(String::new());
+        compiler.use_prelude = use_prelude;
+        let Ok(mut vm) = compiler.compile() else {
+            panic!("prelude failed to compile");
+        };
+        if let Err(err) = vm.run() {
+            panic!("prelude failed to run: {err}");
+        }
+
+        Self {
+            vm: Some(vm),
+            lang_version: LangVersion::LATEST,
+            strict: false,
+        }
+    }
+
+    fn vm(&self) -> &VM {
+        self.vm
+            .as_ref()
+            .expect("the VM is only taken while compiling")
+    }
+
+    fn vm_mut(&mut self) -> &mut VM {
+        self.vm
+            .as_mut()
+            .expect("the VM is only taken while compiling")
+    }
+
+    /// Makes a native callable from scripts as the global name
+    pub fn define_native(&mut self, name: &str, native: NativeFn) {
+        self.vm_mut().define_native(name, native);
+    }
+
+    /// Sends output from `print` and friends somewhere other than stdout
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.vm_mut().set_output(Box::new(output));
+    }
+
+    /// Sends output from `eprint`, warnings, and errors passed to [`Interpreter::report`]
+    /// somewhere other than stderr
+    pub fn set_error_output(&mut self, output: impl Write + 'static) {
+        self.vm_mut().set_error_output(Box::new(output));
+    }
+
+    /// Writes a value from [`Interpreter::evaluate`] the way the REPL shows it, to the
+    /// same output as `print`
+    pub fn echo(&self, value: Value) {
+        self.vm().write_output(format_args!("{value:?}\n"));
+    }
+
+    /// Writes an error from [`Interpreter::interpret`] or [`Interpreter::evaluate`] to
+    /// the error output
+    pub fn report(&self, err: &Error) {
+        self.vm().report(err);
+    }
+
+    /// Gives natives and conditions the behaviour of an older version of the language.
+    /// Sources passed to [`Interpreter::interpret`] can still pick their own version
+    /// with a pragma.
+    pub fn set_lang_version(&mut self, version: LangVersion) {
+        self.lang_version = version;
+        Compiler::set_lang_version(self.vm_mut(), version);
+    }
+
+    /// Calls that can be nested before a runtime error reports a stack overflow
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.vm_mut().max_frames = depth;
+    }
+
+    /// Arguments scripts can read with `args` and `args_count`
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.vm_mut().args = args;
+    }
+
+    /// Runs source as a whole program, the way it would run from a file
+    pub fn interpret(&mut self, source: &str) -> Result<(), Error> {
+        let vm = self
+            .vm
+            .take()
+            .expect("the VM is only taken while compiling");
+        let mut compiler = Compiler::resume_script(source.to_owned(), vm);
+        compiler.lang_version = self.lang_version;
+        self.run(compiler)?;
+        Ok(())
+    }
+
+    /// Runs source the way the REPL does, returning the value of a trailing expression
+    /// without a semicolon, or null. Pragmas are ignored and globals that are never read
+    /// aren't reported. The value is only guaranteed to stay alive until the next call.
+    pub fn evaluate(&mut self, source: &str) -> Result<Value, Error> {
+        let vm = self
+            .vm
+            .take()
+            .expect("the VM is only taken while compiling");
+        self.run(Compiler::resume(source.to_owned(), vm))
+    }
+
+    fn run(&mut self, mut compiler: Compiler) -> Result<Value, Error> {
+        compiler.strict = self.strict;
+        let (vm, result) = compiler.compile_line();
+        let vm = self.vm.insert(vm);
+        result?;
+
+        Ok(vm.run()?)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
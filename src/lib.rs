@@ -0,0 +1,11 @@
+//! Dymaxilang, a small scripting language where every value has its own map.
+//!
+//! Embed it with [`Interpreter`], or drive the [`compiler`] and [`vm`] directly.
+
+pub mod codes;
+pub mod compiler;
+mod interpreter;
+pub mod style;
+pub mod vm;
+
+pub use interpreter::{Error, Interpreter};
@@ -1,21 +1,248 @@
-use std::{env, process};
+use std::{
+    env,
+    fs::File,
+    io::{self, IsTerminal, Read},
+    process,
+    rc::Rc,
+};
 
-use compiler::Compiler;
+use args::{Command, Options, USAGE};
+use dymaxilang::{compiler::Compiler, style};
 
-mod compiler;
-mod vm;
+mod args;
+mod repl;
+
+/// Exit status for source that didn't compile, EX_DATAERR from sysexits.h
+const EXIT_COMPILE_ERROR: i32 = 65;
+/// Exit status for a runtime error, EX_SOFTWARE from sysexits.h
+const EXIT_RUNTIME_ERROR: i32 = 70;
+
+fn error(message: &str, code: i32) -> ! {
+    style::report(format_args!("{}: {message}", style::error("error")));
+    process::exit(code);
+}
+
+/// Name diagnostics use for a program read from stdin
+const STDIN_NAME: &str = "<stdin>";
+
+/// Reads the program at path, or from stdin if path is -
+fn read_source(path: &str, max_size: u64) -> Result<String, (String, i32)> {
+    let reader: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        // the error kind tells a mistyped path apart from a permissions problem
+        let file = File::open(path).map_err(|err| {
+            let reason = match err.kind() {
+                io::ErrorKind::NotFound => "not found",
+                _ => "could not be opened",
+            };
+            let kind = err.kind();
+            (
+                format!("source file ({path}) {reason} ({kind:?}): {err}"),
+                66,
+            )
+        })?;
+        Box::new(file)
+    };
+    let path = if path == "-" { STDIN_NAME } else { path };
+
+    // read through take rather than trusting metadata so pipes and special files are limited too
+    let mut bytes = Vec::new();
+    reader
+        .take(max_size + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|err| {
+            let kind = err.kind();
+            (
+                format!("source file ({path}) could not be read ({kind:?}): {err}"),
+                66,
+            )
+        })?;
+
+    if bytes.len() as u64 > max_size {
+        return Err((
+            format!(
+                "source file ({path}) is larger than {max_size} bytes, use --max-source-size to raise the limit"
+            ),
+            65,
+        ));
+    }
+
+    if bytes.iter().take(4096).any(|byte| *byte == 0) {
+        return Err((
+            format!("source file ({path}) does not look like a dymaxilang source file"),
+            65,
+        ));
+    }
+
+    String::from_utf8(bytes).map_err(|err| {
+        (
+            format!(
+                "source file ({path}) is not valid UTF-8 (invalid byte at offset {})",
+                err.utf8_error().valid_up_to()
+            ),
+            65,
+        )
+    })
+}
 
 fn main() {
-    let mut args = env::args();
-    let Some(file) = args.nth(1) else {
-        eprintln!("\x1b[91merror\x1b[0m: need to provide path to source file");
-        process::exit(1);
+    style::init();
+    let options = match args::parse(env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            print!("{USAGE}");
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("dymaxilang {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Ok(Command::Explain(code)) => {
+            print!("{}", code.explanation());
+            return;
+        }
+        Err(message) => {
+            style::report(format_args!(
+                "{}: {message}\n\n{USAGE}",
+                style::error("error")
+            ));
+            process::exit(2);
+        }
+    };
+    let Options {
+        use_prelude,
+        buffered_output,
+        strict,
+        timings,
+        repl,
+        disassemble,
+        no_run,
+        check,
+        trace,
+        profile,
+        gc_stats,
+        debug,
+        breakpoints,
+        color,
+        max_source_size,
+        lang_version,
+        max_call_depth,
+        file,
+        args,
+    } = options;
+    if !color {
+        style::set_color(false);
+    }
+    if trace && !cfg!(feature = "runtime_trace") {
+        error(
+            "--trace needs dymaxilang built with the runtime_trace feature",
+            1,
+        );
+    }
+    if profile && !cfg!(feature = "profile") {
+        error(
+            "--profile needs dymaxilang built with the profile feature",
+            1,
+        );
+    }
+    if debug && !cfg!(feature = "debugger") {
+        error(
+            "--debug needs dymaxilang built with the debugger feature",
+            1,
+        );
+    }
+    // only the debugger reads them, and --break always turns on --debug
+    #[cfg(not(feature = "debugger"))]
+    let _ = breakpoints;
+    if check {
+        let Some(file) = file else {
+            error("--check must be given at least one file", 1);
+        };
+        let mut failed = false;
+        for path in std::iter::once(file).chain(args) {
+            let source = match read_source(&path, max_source_size) {
+                Ok(source) => source,
+                Err((message, _)) => {
+                    style::report(format_args!("{}: {message}", style::error("error")));
+                    failed = true;
+                    continue;
+                }
+            };
+            let mut compiler = Compiler::new(source);
+            compiler.use_prelude = use_prelude;
+            compiler.strict = strict;
+            compiler.lang_version = lang_version;
+            compiler.file_name = Some(Rc::from(if path == "-" { STDIN_NAME } else { &path }));
+            if let Err(diagnostics) = compiler.compile() {
+                for diagnostic in diagnostics {
+                    style::report(diagnostic);
+                }
+                failed = true;
+            }
+        }
+        process::exit(if failed { EXIT_COMPILE_ERROR } else { 0 });
+    }
+    // a program piped in without a file runs like one passed as -
+    let file = file.or_else(|| (!io::stdin().is_terminal()).then(|| String::from("-")));
+    let Some(file) = file.filter(|_| !repl) else {
+        repl::run(use_prelude, strict, lang_version, max_call_depth);
+        return;
+    };
+    if debug && file == "-" {
+        error(
+            "--debug reads commands from stdin, so the program has to be in a file",
+            1,
+        );
+    }
+    let source = match read_source(&file, max_source_size) {
+        Ok(source) => source,
+        Err((message, code)) => error(&message, code),
     };
-    let Ok(source) = std::fs::read_to_string(file) else {
-        eprintln!("\x1b[91merror\x1b[0m: source file not found");
-        process::exit(1);
+    let mut compiler = Compiler::new(source);
+    compiler.use_prelude = use_prelude;
+    compiler.strict = strict;
+    if file == "-" {
+        compiler.file_name = Some(Rc::from(STDIN_NAME));
+    }
+    compiler.timings = timings;
+    compiler.disassemble = disassemble;
+    compiler.lang_version = lang_version;
+    let mut vm = match compiler.compile() {
+        Ok(vm) => vm,
+        Err(diagnostics) => {
+            for diagnostic in diagnostics {
+                style::report(diagnostic);
+            }
+            process::exit(EXIT_COMPILE_ERROR);
+        }
     };
-    let compiler = Compiler::new(source);
-    let mut vm = compiler.compile();
-    vm.run();
+    if no_run {
+        return;
+    }
+    vm.args = args;
+    vm.buffered_output = buffered_output;
+    vm.trace = trace;
+    vm.profile = profile;
+    vm.gc_stats = gc_stats;
+    #[cfg(feature = "debugger")]
+    {
+        vm.debug = debug;
+        for line in breakpoints {
+            if !vm.break_at(line) {
+                style::report(format_args!(
+                    "{}: line {line} has no code, so its breakpoint will never be reached",
+                    style::warning("warning")
+                ));
+            }
+        }
+    }
+    vm.max_frames = max_call_depth;
+    if let Err(err) = vm.run() {
+        if let Some(status) = err.exit_status {
+            process::exit(status);
+        }
+        vm.report(err);
+        process::exit(EXIT_RUNTIME_ERROR);
+    }
 }
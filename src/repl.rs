@@ -0,0 +1,99 @@
+use std::{
+    io::{self, BufRead, Write},
+    process,
+};
+
+use dymaxilang::{compiler::LangVersion, Interpreter};
+
+const PROMPT: &str = ">> ";
+const CONTINUATION_PROMPT: &str = ".. ";
+
+/// How many more brackets are opened than closed, ignoring strings and comments.
+/// Input keeps being read while this is positive.
+fn open_brackets(source: &str) -> i32 {
+    let mut depth = 0;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '(' | '[' => depth += 1,
+            '}' | ')' | ']' => depth -= 1,
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => (),
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    depth
+}
+
+/// Reads one complete entry, prompting for more lines until its brackets balance.
+/// Returns None at the end of input.
+fn read_entry(input: &mut impl BufRead) -> Option<String> {
+    let mut source = String::new();
+    loop {
+        let prompt = if source.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        print!("{prompt}");
+        let _ = io::stdout().flush();
+
+        match input.read_line(&mut source) {
+            Ok(0) | Err(_) if source.is_empty() => return None,
+            Ok(0) | Err(_) => return Some(source),
+            Ok(_) => (),
+        }
+
+        if open_brackets(&source) <= 0 {
+            return Some(source);
+        }
+    }
+}
+
+pub fn run(use_prelude: bool, strict: bool, lang_version: LangVersion, max_call_depth: usize) {
+    let mut interpreter = if use_prelude {
+        Interpreter::new()
+    } else {
+        Interpreter::without_prelude()
+    };
+    interpreter.strict = strict;
+    interpreter.set_lang_version(lang_version);
+    interpreter.set_max_call_depth(max_call_depth);
+
+    let mut input = io::stdin().lock();
+    while let Some(source) = read_entry(&mut input) {
+        if source.trim().is_empty() {
+            continue;
+        }
+
+        match interpreter.evaluate(&source) {
+            Ok(value) if !value.is_null() => interpreter.echo(value),
+            Ok(_) => (),
+            Err(err) => {
+                if let Some(status) = err.exit_status() {
+                    process::exit(status);
+                }
+                interpreter.report(&err);
+            }
+        }
+    }
+
+    println!();
+}
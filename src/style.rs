@@ -0,0 +1,101 @@
+//! Colouring for diagnostics and a way to report them that never panics.
+//!
+//! Colour is on by default. The binary calls [`init`] to turn it off when stderr isn't
+//! a terminal, NO_COLOR is set, or the Windows console can't interpret ANSI codes.
+
+use std::{
+    fmt::{self, Display},
+    io::{self, IsTerminal, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static COLOR: AtomicBool = AtomicBool::new(true);
+
+pub fn set_color(enabled: bool) {
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+pub fn color_enabled() -> bool {
+    COLOR.load(Ordering::Relaxed)
+}
+
+/// Decides whether diagnostics on stderr should be coloured
+pub fn init() {
+    let enabled = io::stderr().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+        && enable_virtual_terminal();
+    set_color(enabled);
+}
+
+/// Consoles before Windows 10 and ones without VT processing print escape codes literally
+#[cfg(windows)]
+fn enable_virtual_terminal() -> bool {
+    use std::{ffi::c_void, os::windows::io::AsRawHandle};
+
+    const ENABLE_VIRTUAL_TERMINAL_PROCESSING: u32 = 0x0004;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetConsoleMode(handle: *mut c_void, mode: *mut u32) -> i32;
+        fn SetConsoleMode(handle: *mut c_void, mode: u32) -> i32;
+    }
+
+    let handle = io::stderr().as_raw_handle();
+    let mut mode = 0;
+    unsafe {
+        GetConsoleMode(handle, &mut mode) != 0
+            && SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0
+    }
+}
+
+#[cfg(not(windows))]
+fn enable_virtual_terminal() -> bool {
+    true
+}
+
+/// Text wrapped in an ANSI colour when colour is enabled
+pub struct Styled<'a> {
+    text: &'a str,
+    color: &'static str,
+}
+
+impl Display for Styled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if color_enabled() {
+            write!(f, "\x1b[{}m{}\x1b[0m", self.color, self.text)
+        } else {
+            write!(f, "{}", self.text)
+        }
+    }
+}
+
+pub fn error(text: &str) -> Styled<'_> {
+    Styled { text, color: "91" }
+}
+
+pub fn warning(text: &str) -> Styled<'_> {
+    Styled { text, color: "93" }
+}
+
+/// Writes a line to stderr, ignoring failures rather than panicking like eprintln!
+/// does, since there's nowhere left to report them
+pub fn report(message: impl Display) {
+    let _ = writeln!(io::stderr().lock(), "{message}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One test, since colour is a global the others would race on
+    #[test]
+    fn styled_text_is_plain_without_colour() {
+        set_color(true);
+        assert_eq!(error("x").to_string(), "\x1b[91mx\x1b[0m");
+        assert_eq!(warning("x").to_string(), "\x1b[93mx\x1b[0m");
+
+        set_color(false);
+        assert_eq!(error("x").to_string(), "x");
+        assert_eq!(warning("x").to_string(), "x");
+    }
+}
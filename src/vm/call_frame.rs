@@ -1,7 +1,7 @@
-#[cfg(feature = "local_map_scopes")]
-use std::collections::HashMap;
 use std::ptr::NonNull;
 
+#[cfg(feature = "local_map_scopes")]
+use super::hash::Namespaces;
 use super::{object::Obj, value::Value};
 
 pub struct CallFrame {
@@ -9,7 +9,7 @@ pub struct CallFrame {
     pub ip: *const u8,
     pub fp_offset: usize,
     #[cfg(feature = "local_map_scopes")]
-    pub local_maps: Vec<HashMap<Value, HashMap<Value, Value>>>,
+    pub local_maps: Vec<Namespaces>,
 }
 
 impl CallFrame {
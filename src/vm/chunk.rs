@@ -1,11 +1,17 @@
+use std::{collections::HashMap, ops::Range};
+
 use super::value::Value;
 
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OpCode {
     LoadConstant,
     LoadConstantExt,
     Null,
+    LoadZero,
+    LoadOne,
+    LoadTrue,
+    LoadFalse,
     Pop,
     Add,
     Sub,
@@ -22,8 +28,14 @@ pub enum OpCode {
     DefineGlobal,
     GetGlobal,
     SetGlobal,
+    DefineGlobalExt,
+    GetGlobalExt,
+    SetGlobalExt,
     GetLocal,
     SetLocal,
+    GetLocalExt,
+    SetLocalExt,
+    IncrementLocal,
     GetMap,
     SetMap,
     #[cfg(feature = "local_map_scopes")]
@@ -35,15 +47,214 @@ pub enum OpCode {
     JumpIfFalseNoPop,
     JumpIfTrueNoPop,
     JumpUp,
+    JumpLong,
+    JumpIfFalseLong,
+    JumpIfFalseNoPopLong,
+    JumpIfTrueNoPopLong,
+    JumpUpLong,
+    NextChar,
+    /// Replaces the map key on top of the stack with a snapshot of the map's keys
+    MapKeys,
+    /// Pushes the next key of the snapshot in the given slot, using the slot after it
+    /// as the index, then whether there was one
+    NextKey,
+    /// Checks the step of a for range on top of the stack is a number other than 0
+    CheckStep,
+    /// Checks the right side of `&&` or `||` on top of the stack is a boolean
+    CheckBool,
     Call,
+    TailCall,
     Return,
 }
 
+/// What follows an opcode in the bytecode and how it should be interpreted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OperandShape {
+    None,
+    /// Slot, global index, or argument count
+    Byte,
+    Constant,
+    ConstantExt,
+    /// Local slot or global index past 255, or a slot that can be past 255
+    ByteExt,
+    /// Forward jump relative to the end of the instruction
+    Jump,
+    /// Backward jump relative to the end of the instruction
+    Loop,
+    /// Forward jump with a 32 bit operand, for functions too large for 16 bits
+    JumpLong,
+    LoopLong,
+}
+
+impl OperandShape {
+    pub fn len(self) -> usize {
+        match self {
+            OperandShape::None => 0,
+            OperandShape::Byte | OperandShape::Constant => 1,
+            OperandShape::ByteExt | OperandShape::Jump | OperandShape::Loop => 2,
+            OperandShape::ConstantExt => 3,
+            OperandShape::JumpLong | OperandShape::LoopLong => 4,
+        }
+    }
+}
+
+impl OpCode {
+    pub fn operand_shape(self) -> OperandShape {
+        use OpCode as Op;
+        match self {
+            Op::LoadConstant => OperandShape::Constant,
+            Op::LoadConstantExt => OperandShape::ConstantExt,
+            Op::DefineGlobal
+            | Op::GetGlobal
+            | Op::SetGlobal
+            | Op::GetLocal
+            | Op::SetLocal
+            | Op::IncrementLocal
+            | Op::Call
+            | Op::TailCall => OperandShape::Byte,
+            Op::Jump | Op::JumpIfFalse | Op::JumpIfFalseNoPop | Op::JumpIfTrueNoPop => {
+                OperandShape::Jump
+            }
+            Op::DefineGlobalExt
+            | Op::GetGlobalExt
+            | Op::SetGlobalExt
+            | Op::GetLocalExt
+            | Op::SetLocalExt
+            | Op::NextChar
+            | Op::NextKey => OperandShape::ByteExt,
+            Op::JumpUp => OperandShape::Loop,
+            Op::JumpLong
+            | Op::JumpIfFalseLong
+            | Op::JumpIfFalseNoPopLong
+            | Op::JumpIfTrueNoPopLong => OperandShape::JumpLong,
+            Op::JumpUpLong => OperandShape::LoopLong,
+            Op::Null
+            | Op::LoadZero
+            | Op::LoadOne
+            | Op::LoadTrue
+            | Op::LoadFalse
+            | Op::Pop
+            | Op::Add
+            | Op::Sub
+            | Op::Mul
+            | Op::Div
+            | Op::Equal
+            | Op::NotEqual
+            | Op::Greater
+            | Op::GreaterEqual
+            | Op::Less
+            | Op::LessEqual
+            | Op::Not
+            | Op::Negate
+            | Op::GetMap
+            | Op::SetMap
+            | Op::CheckStep
+            | Op::CheckBool
+            | Op::MapKeys
+            | Op::Return => OperandShape::None,
+            #[cfg(feature = "local_map_scopes")]
+            Op::PushMap | Op::PopMap => OperandShape::None,
+        }
+    }
+
+    /// The same jump with a 32 bit operand
+    fn long_form(self) -> Option<OpCode> {
+        use OpCode as Op;
+        Some(match self {
+            Op::Jump => Op::JumpLong,
+            Op::JumpIfFalse => Op::JumpIfFalseLong,
+            Op::JumpIfFalseNoPop => Op::JumpIfFalseNoPopLong,
+            Op::JumpIfTrueNoPop => Op::JumpIfTrueNoPopLong,
+            Op::JumpUp => Op::JumpUpLong,
+            _ => return None,
+        })
+    }
+
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        if byte <= OpCode::Return as u8 {
+            Some(unsafe { std::mem::transmute::<u8, OpCode>(byte) })
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Instruction {
+    pub offset: usize,
+    pub op: OpCode,
+    /// Operand decoded according to the opcode's shape, 0 if it has none
+    pub operand: usize,
+    pub len: usize,
+}
+
+impl Instruction {
+    /// Offset the instruction jumps to, if it is a jump
+    pub fn jump_target(&self) -> Option<usize> {
+        match self.op.operand_shape() {
+            OperandShape::Jump | OperandShape::JumpLong => {
+                Some(self.offset + self.len + self.operand)
+            }
+            OperandShape::Loop | OperandShape::LoopLong => {
+                (self.offset + self.len).checked_sub(self.operand)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: usize,
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.chunk.code.len() {
+            return None;
+        }
+
+        let instruction = self.chunk.decode(self.offset);
+        self.offset += instruction.len;
+        Some(instruction)
+    }
+}
+
+/// Where the code for an instruction came from, as its line and the byte offsets of
+/// the expression it belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub line: u32,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A local variable's name and stack slot, with the offsets of the code that runs while
+/// the slot holds it
+#[derive(Clone, Debug)]
+pub struct LocalName {
+    pub name: Box<str>,
+    pub slot: u16,
+    /// Ends at usize::MAX for locals that live until the function returns
+    pub live: Range<usize>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Chunk {
     code: Vec<u8>,
     pub constants: Vec<Value>,
-    pub lines: Vec<u32>,
+    /// Source location of each byte of code, as runs of (location, bytes) since every
+    /// instruction is several bytes in a row. Only errors and tracing read it.
+    locations: Vec<(Location, u32)>,
+    /// Index of each constant by its bits, which are equal for equal values since
+    /// strings are interned and zero has one representation
+    constant_indices: HashMap<u64, usize>,
+    /// Operand offsets of forward jumps that haven't been patched yet
+    pending_jumps: Vec<usize>,
+    /// Every local declared in the function, in the order they were declared
+    locals: Vec<LocalName>,
 }
 
 impl Chunk {
@@ -51,7 +262,10 @@ impl Chunk {
         Self {
             code: Vec::new(),
             constants: Vec::new(),
-            lines: Vec::new(),
+            locations: Vec::new(),
+            constant_indices: HashMap::new(),
+            pending_jumps: Vec::new(),
+            locals: Vec::new(),
         }
     }
 
@@ -59,124 +273,519 @@ impl Chunk {
         self.code.as_ptr()
     }
 
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            chunk: self,
+            offset: 0,
+        }
+    }
+
+    /// Decodes the instruction at offset, which must be the start of a valid instruction
+    pub fn decode(&self, offset: usize) -> Instruction {
+        let op = OpCode::from_byte(self.code[offset]).expect("invalid opcode");
+        let len = op.operand_shape().len();
+        let operand = self.code[offset + 1..offset + 1 + len]
+            .iter()
+            .fold(0, |acc, byte| (acc << 8) | *byte as usize);
+
+        Instruction {
+            offset,
+            op,
+            operand,
+            len: len + 1,
+        }
+    }
+
+    /// Checks that every opcode is valid, operands are in bounds, and jumps land on
+    /// the start of an instruction
+    pub fn validate(&self) -> Result<(), String> {
+        let mut starts = vec![false; self.code.len() + 1];
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let Some(op) = OpCode::from_byte(self.code[offset]) else {
+                return Err(format!(
+                    "invalid opcode {} at {offset:04X}",
+                    self.code[offset]
+                ));
+            };
+            if offset + 1 + op.operand_shape().len() > self.code.len() {
+                return Err(format!("truncated {op:?} at {offset:04X}"));
+            }
+            starts[offset] = true;
+            offset += 1 + op.operand_shape().len();
+        }
+
+        for instruction in self.instructions() {
+            match instruction.op.operand_shape() {
+                OperandShape::Constant | OperandShape::ConstantExt
+                    if instruction.operand >= self.constants.len() =>
+                {
+                    return Err(format!(
+                        "constant {} out of range at {:04X}",
+                        instruction.operand, instruction.offset
+                    ));
+                }
+                OperandShape::Jump
+                | OperandShape::Loop
+                | OperandShape::JumpLong
+                | OperandShape::LoopLong => {
+                    let target = instruction.jump_target();
+                    if target.is_none_or(|target| starts.get(target) != Some(&true)) {
+                        return Err(format!(
+                            "{:?} at {:04X} doesn't land on an instruction",
+                            instruction.op, instruction.offset
+                        ));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn size(&self) -> usize {
         self.code.len() + self.constants.len() * size_of::<Value>()
     }
 
     #[cfg(feature = "local_map_scopes")]
-    pub fn push_map(&mut self, target: usize, line: u32) {
+    pub fn push_map(&mut self, target: usize, location: Location) {
         self.code.insert(target, OpCode::PushMap as u8);
-        self.lines.insert(target, line);
-        self.push_byte(OpCode::PopMap as u8, line);
+        self.insert_locations(target, location, 1);
+        self.push_byte(OpCode::PopMap as u8, location);
     }
 
+    /// Returns the index of an equal constant if there is one, otherwise adds it
     pub fn add_constant(&mut self, constant: Value) -> usize {
-        self.constants.push(constant);
-        self.constants.len() - 1
+        *self
+            .constant_indices
+            .entry(constant.to_bits())
+            .or_insert_with(|| {
+                self.constants.push(constant);
+                self.constants.len() - 1
+            })
+    }
+
+    /// Emits a forward jump to be patched later, returning the offset of its operand
+    pub fn push_jump(&mut self, op: OpCode, location: Location) -> usize {
+        self.push_byte(op as u8, location);
+        self.push_byte(0xFF, location);
+        self.push_byte(0xFF, location);
+        let jump_idx = self.code.len() - 2;
+        self.pending_jumps.push(jump_idx);
+        jump_idx
     }
 
-    pub fn patch_jump(&mut self, jump_idx: usize) {
+    /// Points the jump with its operand at jump_idx to the end of the code.
+    ///
+    /// A jump too far for 16 bits is widened to its long form, which moves the code
+    /// after it and can push other jumps over the limit too. Returns how many bytes
+    /// were inserted, which callers holding the offset of a later unpatched jump need
+    /// to add to it.
+    pub fn patch_jump(&mut self, jump_idx: usize) -> usize {
+        self.pending_jumps.retain(|pending| *pending != jump_idx);
+
         let offset = self.code.len() - jump_idx - 2;
-        self.code[jump_idx] = (offset >> 8) as u8;
-        self.code[jump_idx + 1] = (offset & 0xFF) as u8;
+        if offset <= u16::MAX as usize {
+            self.code[jump_idx] = (offset >> 8) as u8;
+            self.code[jump_idx + 1] = (offset & 0xFF) as u8;
+            return 0;
+        }
+
+        // every patched jump as (offset, target), since widening moves both
+        let end = self.code.len();
+        let mut jumps: Vec<(usize, usize)> = self
+            .instructions()
+            .filter(|instruction| !self.pending_jumps.contains(&(instruction.offset + 1)))
+            .filter_map(|instruction| {
+                if instruction.offset == jump_idx - 1 {
+                    Some((instruction.offset, end))
+                } else {
+                    Some((instruction.offset, instruction.jump_target()?))
+                }
+            })
+            .collect();
+
+        let mut inserted = 0;
+        while let Some(&(offset, _)) = jumps.iter().find(|(offset, target)| {
+            let op = OpCode::from_byte(self.code[*offset]).unwrap();
+            op.long_form().is_some() && (offset + 3).abs_diff(*target) > u16::MAX as usize
+        }) {
+            let op = OpCode::from_byte(self.code[offset]).unwrap();
+            self.code[offset] = op.long_form().unwrap() as u8;
+
+            let end = offset + 3;
+            let location = self.location_for_offset(offset);
+            self.code.splice(end..end, [0, 0]);
+            self.insert_locations(end, location, 2);
+            inserted += 2;
+
+            for (jump_offset, target) in jumps.iter_mut() {
+                if *jump_offset > offset {
+                    *jump_offset += 2;
+                }
+                if *target >= end {
+                    *target += 2;
+                }
+            }
+            for pending in self.pending_jumps.iter_mut() {
+                if *pending > offset {
+                    *pending += 2;
+                }
+            }
+        }
+
+        for (offset, target) in jumps {
+            let instruction = self.decode(offset);
+            let distance = (offset + instruction.len).abs_diff(target);
+            let operand = &mut self.code[offset + 1..offset + instruction.len];
+            let len = operand.len();
+            for (i, byte) in operand.iter_mut().enumerate() {
+                *byte = (distance >> (8 * (len - 1 - i))) as u8;
+            }
+        }
+
+        inserted
+    }
+
+    /// Swaps the opcode at offset for one that takes the same operand
+    pub fn replace_opcode(&mut self, offset: usize, op: OpCode) {
+        debug_assert_eq!(
+            OpCode::from_byte(self.code[offset]).map(OpCode::operand_shape),
+            Some(op.operand_shape())
+        );
+        self.code[offset] = op as u8;
     }
 
     pub fn jump_target(&self) -> usize {
         self.code.len()
     }
 
-    pub fn push_byte(&mut self, byte: u8, line: u32) {
+    pub fn push_byte(&mut self, byte: u8, location: Location) {
         self.code.push(byte);
-        self.lines.push(line);
+        match self.locations.last_mut() {
+            Some((last, count)) if *last == location => *count += 1,
+            _ => self.locations.push((location, 1)),
+        }
     }
 
-    #[cfg(any(feature = "decompile", feature = "trace_execution"))]
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04X} ", offset);
+    /// Records that slot holds the local name from the end of the code so far, until
+    /// end_local is called with the index returned
+    pub fn start_local(&mut self, name: &str, slot: u16) -> usize {
+        self.locals.push(LocalName {
+            name: name.into(),
+            slot,
+            live: self.code.len()..usize::MAX,
+        });
+        self.locals.len() - 1
+    }
 
-        use OpCode as Op;
-        match unsafe { std::mem::transmute::<u8, OpCode>(self.code[offset]) } {
-            op @ (Op::Null
-            | Op::Pop
-            | Op::Add
-            | Op::Sub
-            | Op::Mul
-            | Op::Div
-            | Op::Equal
-            | Op::NotEqual
-            | Op::Greater
-            | Op::GreaterEqual
-            | Op::Less
-            | Op::LessEqual
-            | Op::Not
-            | Op::Negate
-            | Op::GetMap
-            | Op::SetMap
-            | Op::Return) => {
-                println!("{:?}", op);
-                offset + 1
+    pub fn end_local(&mut self, idx: usize) {
+        self.locals[idx].live.end = self.code.len();
+    }
+
+    /// Locals whose slots hold them when the instruction at offset runs
+    pub fn locals_at(&self, offset: usize) -> impl Iterator<Item = &LocalName> {
+        self.locals
+            .iter()
+            .filter(move |local| local.live.contains(&offset))
+    }
+
+    /// Offsets of the instructions compiled from code on line
+    pub fn offsets_for_line(&self, line: u32) -> impl Iterator<Item = usize> + '_ {
+        self.instructions()
+            .map(|instruction| instruction.offset)
+            .filter(move |offset| self.line_for_offset(*offset) == line)
+    }
+
+    /// Source line of the byte of code at offset
+    pub fn line_for_offset(&self, offset: usize) -> u32 {
+        self.location_for_offset(offset).line
+    }
+
+    /// Source location of the byte of code at offset
+    pub fn location_for_offset(&self, offset: usize) -> Location {
+        let mut run_end = 0;
+        for (location, count) in self.locations.iter() {
+            run_end += *count as usize;
+            if offset < run_end {
+                return *location;
             }
-            #[cfg(feature = "local_map_scopes")]
-            op @ (Op::PushMap | Op::PopMap) => {
-                println!("{:?}", op);
-                offset + 1
+        }
+        panic!("no location for offset {offset:04X}");
+    }
+
+    /// Records the location of count bytes inserted into the code at offset, moving the
+    /// ranges of the locals after it along
+    fn insert_locations(&mut self, offset: usize, location: Location, count: u32) {
+        for local in self.locals.iter_mut() {
+            if local.live.start >= offset {
+                local.live.start += count as usize;
             }
-            op @ (Op::DefineGlobal
-            | Op::GetGlobal
-            | Op::SetGlobal
-            | Op::GetLocal
-            | Op::SetLocal
-            | Op::Call) => {
-                let constant = self.code[offset + 1];
-                println!("{:16} {:04X}", format!("{:?}", op), constant);
-                offset + 2
+            if local.live.end > offset && local.live.end != usize::MAX {
+                local.live.end += count as usize;
             }
-            op @ Op::LoadConstant => {
-                let idx = self.code[offset + 1];
-                println!(
-                    "{:16} {:04X} {}",
-                    format!("{:?}", op),
-                    idx,
-                    self.constants[idx as usize]
-                );
-                offset + 2
+        }
+
+        let mut run_start = 0;
+        for i in 0..self.locations.len() {
+            let (run_location, run_count) = self.locations[i];
+            let run_end = run_start + run_count as usize;
+            if offset <= run_end {
+                if run_location == location {
+                    self.locations[i].1 += count;
+                } else if offset == run_start {
+                    self.locations.insert(i, (location, count));
+                } else if offset == run_end {
+                    self.locations.insert(i + 1, (location, count));
+                } else {
+                    let before = (offset - run_start) as u32;
+                    self.locations[i].1 = before;
+                    self.locations.splice(
+                        i + 1..i + 1,
+                        [(location, count), (run_location, run_count - before)],
+                    );
+                }
+                return;
             }
-            op @ Op::LoadConstantExt => {
-                println!(
-                    "{} {} {}",
-                    self.code[offset + 1],
-                    self.code[offset + 2],
-                    self.code[offset + 3]
-                );
-                let idx = (self.code[offset + 1] as usize) << 16
-                    | (self.code[offset + 2] as usize) << 8
-                    | self.code[offset + 3] as usize;
-                println!(
-                    "{:16} {:04X} {}",
-                    format!("{:?}", op),
-                    idx,
-                    self.constants[idx]
-                );
-                offset + 4
+            run_start = run_end;
+        }
+
+        self.locations.push((location, count));
+    }
+
+    pub fn disassemble_instruction(&self, offset: usize) -> usize {
+        print!("{:04X} ", offset);
+
+        let instruction = self.decode(offset);
+        let name = format!("{:?}", instruction.op);
+        match instruction.op.operand_shape() {
+            OperandShape::None => println!("{name}"),
+            OperandShape::Byte | OperandShape::ByteExt => {
+                println!("{:16} {:04X}", name, instruction.operand)
             }
-            op @ (Op::Jump
-            | Op::JumpIfFalse
-            | Op::JumpIfFalseNoPop
-            | Op::JumpIfTrueNoPop
-            | Op::JumpUp) => {
-                let jump_offset =
-                    (self.code[offset + 1] as usize) << 8 | self.code[offset + 2] as usize;
-                println!("{:16} {:04X}", format!("{:?}", op), jump_offset);
-                offset + 3
+            OperandShape::Constant | OperandShape::ConstantExt => println!(
+                "{:16} {:04X} {}",
+                name, instruction.operand, self.constants[instruction.operand]
+            ),
+            OperandShape::Jump
+            | OperandShape::Loop
+            | OperandShape::JumpLong
+            | OperandShape::LoopLong => {
+                println!("{:16} {:04X}", name, instruction.operand)
             }
         }
+
+        offset + instruction.len
     }
 
-    #[cfg(feature = "decompile")]
     pub fn disassemble(&self) {
-        let mut offset = 0;
-        while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+        for instruction in self.instructions() {
+            self.disassemble_instruction(instruction.offset);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(line: u32) -> Location {
+        Location {
+            line,
+            start: 0,
+            end: 0,
+        }
+    }
+
+    fn chunk(bytes: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for byte in bytes {
+            chunk.push_byte(*byte, line(1));
+        }
+        chunk
+    }
+
+    fn pad(chunk: &mut Chunk, count: usize, location: Location) {
+        for _ in 0..count {
+            chunk.push_byte(OpCode::Pop as u8, location);
+        }
+    }
+
+    #[test]
+    fn decodes_every_opcode() {
+        for byte in 0..=OpCode::Return as u8 {
+            let op = OpCode::from_byte(byte).unwrap();
+            let len = op.operand_shape().len();
+            let mut bytes = vec![byte];
+            bytes.extend(1..=len as u8);
+            let chunk = chunk(&bytes);
+
+            let instruction = chunk.decode(0);
+            assert_eq!(instruction.op, op);
+            assert_eq!(instruction.len, len + 1);
+            let expected = match len {
+                0 => 0,
+                1 => 0x01,
+                2 => 0x0102,
+                3 => 0x01_0203,
+                _ => 0x0102_0304,
+            };
+            assert_eq!(instruction.operand, expected, "{op:?}");
+        }
+        assert_eq!(OpCode::from_byte(OpCode::Return as u8 + 1), None);
+    }
+
+    #[test]
+    fn instructions_step_over_operands() {
+        let chunk = chunk(&[
+            OpCode::LoadConstantExt as u8,
+            0,
+            0,
+            0,
+            OpCode::GetLocalExt as u8,
+            1,
+            0,
+            OpCode::Add as u8,
+            OpCode::JumpLong as u8,
+            0,
+            0,
+            0,
+            0,
+            OpCode::Return as u8,
+        ]);
+        let offsets: Vec<(usize, OpCode)> = chunk
+            .instructions()
+            .map(|instruction| (instruction.offset, instruction.op))
+            .collect();
+        assert_eq!(
+            offsets,
+            [
+                (0, OpCode::LoadConstantExt),
+                (4, OpCode::GetLocalExt),
+                (7, OpCode::Add),
+                (8, OpCode::JumpLong),
+                (13, OpCode::Return)
+            ]
+        );
+        assert_eq!(chunk.decode(4).operand, 256);
+        assert_eq!(chunk.decode(8).jump_target(), Some(13));
+    }
+
+    #[test]
+    fn validate_accepts_valid_code() {
+        let mut chunk = chunk(&[OpCode::LoadConstant as u8, 0, OpCode::JumpUp as u8, 0, 5]);
+        chunk.add_constant(Value::float(1.0));
+        assert_eq!(chunk.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_invalid_opcode() {
+        let err = chunk(&[OpCode::Null as u8, 0xFF]).validate().unwrap_err();
+        assert_eq!(err, "invalid opcode 255 at 0001");
+    }
+
+    #[test]
+    fn validate_rejects_truncated_instruction() {
+        let err = chunk(&[OpCode::LoadConstantExt as u8, 0, 0])
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, "truncated LoadConstantExt at 0000");
+    }
+
+    #[test]
+    fn validate_rejects_constant_out_of_range() {
+        let mut chunk = chunk(&[OpCode::LoadConstant as u8, 1]);
+        chunk.add_constant(Value::float(1.0));
+        assert_eq!(
+            chunk.validate().unwrap_err(),
+            "constant 1 out of range at 0000"
+        );
+    }
+
+    #[test]
+    fn validate_rejects_misaligned_jumps() {
+        // lands on the operand of the constant
+        let err = chunk(&[OpCode::Jump as u8, 0, 1, OpCode::LoadConstant as u8, 0])
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, "Jump at 0000 doesn't land on an instruction");
+
+        // past the end of the code
+        let err = chunk(&[OpCode::Jump as u8, 0, 1]).validate().unwrap_err();
+        assert_eq!(err, "Jump at 0000 doesn't land on an instruction");
+
+        // before the start of the code
+        let err = chunk(&[OpCode::Null as u8, OpCode::JumpUp as u8, 0, 5])
+            .validate()
+            .unwrap_err();
+        assert_eq!(err, "JumpUp at 0001 doesn't land on an instruction");
+    }
+
+    #[test]
+    fn short_jumps_are_patched_in_place() {
+        let mut chunk = Chunk::new();
+        let jump = chunk.push_jump(OpCode::JumpIfFalse, line(1));
+        pad(&mut chunk, 10, line(1));
+        assert_eq!(chunk.patch_jump(jump), 0);
+        assert_eq!(chunk.decode(0).jump_target(), Some(13));
+        chunk.push_byte(OpCode::Return as u8, line(1));
+        assert_eq!(chunk.validate(), Ok(()));
+    }
+
+    #[test]
+    fn jump_past_u16_max_is_widened() {
+        let mut chunk = Chunk::new();
+        let far = chunk.push_jump(OpCode::JumpIfFalse, line(1));
+        // already patched, and moved along by the widening
+        let near = chunk.push_jump(OpCode::Jump, line(2));
+        chunk.push_byte(OpCode::Null as u8, line(2));
+        assert_eq!(chunk.patch_jump(near), 0);
+        // still pending, so its offset has to be moved by the caller
+        let pending = chunk.push_jump(OpCode::Jump, line(3));
+        let local = chunk.start_local("x", 0);
+        pad(&mut chunk, u16::MAX as usize, line(4));
+        chunk.end_local(local);
+
+        assert_eq!(chunk.patch_jump(far), 2);
+        assert_eq!(chunk.decode(0).op, OpCode::JumpIfFalseLong);
+        assert_eq!(chunk.decode(0).jump_target(), Some(chunk.jump_target()));
+        assert_eq!(chunk.decode(5).op, OpCode::Jump);
+        assert_eq!(chunk.decode(5).jump_target(), Some(9));
+
+        // the inserted operand bytes belong to the widened jump
+        assert_eq!(chunk.line_for_offset(3), 1);
+        assert_eq!(chunk.line_for_offset(4), 1);
+        assert_eq!(chunk.line_for_offset(5), 2);
+        assert_eq!(chunk.line_for_offset(12), 4);
+        let live = chunk.locals_at(12).next().unwrap().live.clone();
+        assert_eq!(live, 12..chunk.jump_target());
+
+        assert_eq!(chunk.patch_jump(pending + 2), 0);
+        assert_eq!(chunk.decode(9).jump_target(), Some(chunk.jump_target()));
+        chunk.push_byte(OpCode::Return as u8, line(5));
+        assert_eq!(chunk.validate(), Ok(()));
+    }
+
+    #[test]
+    fn widening_can_push_a_jump_over_it_past_the_limit() {
+        let mut chunk = Chunk::new();
+        let outer = chunk.push_jump(OpCode::Jump, line(1));
+        let inner = chunk.push_jump(OpCode::JumpIfFalse, line(2));
+        // the outer jump goes exactly as far as 16 bits allow
+        pad(&mut chunk, u16::MAX as usize - 3, line(3));
+        assert_eq!(chunk.patch_jump(outer), 0);
+        assert_eq!(chunk.decode(0).jump_target(), Some(chunk.jump_target()));
+        let outer_target = chunk.jump_target();
+        pad(&mut chunk, 10, line(4));
+
+        // widening the inner jump moves the outer one's target out of reach
+        assert_eq!(chunk.patch_jump(inner), 4);
+        assert_eq!(chunk.decode(0).op, OpCode::JumpLong);
+        assert_eq!(chunk.decode(0).jump_target(), Some(outer_target + 4));
+        assert_eq!(chunk.decode(5).op, OpCode::JumpIfFalseLong);
+        assert_eq!(chunk.decode(5).jump_target(), Some(chunk.jump_target()));
+        chunk.push_byte(OpCode::Return as u8, line(5));
+        assert_eq!(chunk.validate(), Ok(()));
+    }
+}
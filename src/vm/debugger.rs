@@ -0,0 +1,322 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead},
+    rc::Rc,
+};
+
+use super::{error::RuntimeError, object::ObjFunction, value::Value, ObjKind, VM};
+use crate::codes::ErrorCode;
+
+const HELP: &str = "\
+commands:
+  step, s           run to the next line, going into calls
+  next, n           run to the next line of this function, stepping over calls
+  continue, c       run until a breakpoint is reached
+  locals, l         print the local variables of this function
+  print, p <name>   print a local or global variable
+  map, m <key>      print the entries of a map, named by a string, a number, or a
+                    variable holding its key
+  break, b <line>   stop whenever line is reached
+  delete, d <line>  remove the breakpoint on line
+  quit, q           stop the program
+an empty line repeats the last command";
+
+/// When the program stops next, besides at breakpoints
+enum Mode {
+    Continue,
+    /// At the next line run in any function
+    Step,
+    /// At the next line of the function stopped in, or of a caller once it returns
+    Next {
+        depth: usize,
+        line: u32,
+    },
+}
+
+/// Breakpoints and stepping state for `--debug`
+pub(crate) struct Debugger {
+    breakpoints: BTreeSet<u32>,
+    mode: Mode,
+    /// Line and call depth of the last instruction checked, so the rest of a line's
+    /// instructions run without stopping
+    position: Option<(u32, usize)>,
+    /// Command an empty line runs again
+    last_command: String,
+}
+
+impl Debugger {
+    /// Stops at the first line of the program until a breakpoint is set
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            mode: Mode::Step,
+            position: None,
+            last_command: String::new(),
+        }
+    }
+
+    fn should_stop(&self, line: u32, depth: usize) -> bool {
+        if self.breakpoints.contains(&line) {
+            return true;
+        }
+
+        match self.mode {
+            Mode::Continue => false,
+            Mode::Step => true,
+            Mode::Next {
+                depth: next_depth,
+                line: next_line,
+            } => depth < next_depth || (depth == next_depth && line != next_line),
+        }
+    }
+}
+
+/// Whether any instruction of the program, in function or the functions defined in
+/// it, was compiled from line
+fn has_code_on_line(function: &ObjFunction, program: &Rc<str>, line: u32) -> bool {
+    if !function
+        .source
+        .as_ref()
+        .is_some_and(|source| Rc::ptr_eq(source, program))
+    {
+        return false;
+    }
+
+    function
+        .chunk
+        .offsets_for_line(line)
+        .any(|offset| offset >= function.program_start)
+        || function.chunk.constants.iter().any(|constant| {
+            constant.is_obj()
+                && constant.as_obj().kind() == ObjKind::Function
+                && has_code_on_line(unsafe { &*constant.as_obj().function }, program, line)
+        })
+}
+
+impl VM {
+    /// Stops the program whenever it reaches line, rather than at its first line.
+    /// Returns false if no code was compiled from the line, so it can't be reached.
+    pub fn break_at(&mut self, line: u32) -> bool {
+        self.debugger.breakpoints.insert(line);
+        self.debugger.mode = Mode::Continue;
+        self.program_has_code_on_line(line)
+    }
+
+    fn program_has_code_on_line(&self, line: u32) -> bool {
+        let Some(script) = self.frames.first() else {
+            return false;
+        };
+        let script = unsafe { &*script.function.function };
+        script
+            .source
+            .as_ref()
+            .is_some_and(|program| has_code_on_line(script, program, line))
+    }
+
+    /// Checks the instruction at ip, which is about to run, against the breakpoints and
+    /// the stepping mode, and reads commands from stdin if it starts a line to stop at
+    #[cold]
+    #[inline(never)]
+    pub(super) fn debug_instruction(&mut self, ip: *const u8) -> Result<(), RuntimeError> {
+        let function = unsafe { &*(*self.frame_top).function.function };
+        let script = unsafe { &*self.frames[0].function.function };
+        let offset = unsafe { ip.offset_from(function.chunk.code_ptr()) } as usize;
+
+        // the prelude's lines aren't the program's, so it runs without stopping
+        let in_program = match (&function.source, &script.source) {
+            (Some(source), Some(program)) => Rc::ptr_eq(source, program),
+            _ => false,
+        };
+        if !in_program || offset < function.program_start {
+            return Ok(());
+        }
+
+        let line = function.chunk.line_for_offset(offset);
+        let depth = self.frames.len();
+        if self.debugger.position == Some((line, depth)) {
+            return Ok(());
+        }
+        self.debugger.position = Some((line, depth));
+        if !self.debugger.should_stop(line, depth) {
+            return Ok(());
+        }
+
+        self.flush_output();
+        self.write_error_output(format_args!(
+            "stopped at line {line} in {}\n",
+            function.display_name()
+        ));
+        if let Some(text) = function
+            .source
+            .as_ref()
+            .and_then(|source| source.lines().nth(line as usize - 1))
+        {
+            self.write_error_output(format_args!("{line:>5} | {}\n", text.trim_end()));
+        }
+
+        loop {
+            self.write_error_output(format_args!("(debug) "));
+            let mut input = String::new();
+            if io::stdin()
+                .lock()
+                .read_line(&mut input)
+                .map_or(true, |read| read == 0)
+            {
+                // with nothing left to read commands from, the program runs to the end
+                self.write_error_output(format_args!("\n"));
+                self.debugger.breakpoints.clear();
+                self.debugger.mode = Mode::Continue;
+                return Ok(());
+            }
+
+            let input = match input.trim() {
+                "" => self.debugger.last_command.clone(),
+                input => {
+                    self.debugger.last_command = input.to_owned();
+                    input.to_owned()
+                }
+            };
+            let (command, argument) = input
+                .split_once(' ')
+                .map_or((input.as_str(), ""), |(command, argument)| {
+                    (command, argument.trim())
+                });
+
+            match command {
+                "step" | "s" => {
+                    self.debugger.mode = Mode::Step;
+                    return Ok(());
+                }
+                "next" | "n" => {
+                    self.debugger.mode = Mode::Next { depth, line };
+                    return Ok(());
+                }
+                "continue" | "c" => {
+                    self.debugger.mode = Mode::Continue;
+                    return Ok(());
+                }
+                "locals" | "l" => self.print_locals(function, offset),
+                "print" | "p" => match self.variable(function, offset, argument) {
+                    Some(value) => self.write_error_output(format_args!("{value:?}\n")),
+                    None => self.write_error_output(format_args!(
+                        "there is no variable named '{argument}' here\n"
+                    )),
+                },
+                "map" | "m" => self.print_map(function, offset, argument),
+                "break" | "b" => match argument.parse() {
+                    Ok(line) if self.program_has_code_on_line(line) => {
+                        self.debugger.breakpoints.insert(line);
+                        self.write_error_output(format_args!("breakpoint set on line {line}\n"));
+                    }
+                    Ok(line) => self.write_error_output(format_args!(
+                        "line {line} has no code, so a breakpoint on it would never be reached\n"
+                    )),
+                    Err(_) => self.write_error_output(format_args!(
+                        "break must be followed by a line number\n"
+                    )),
+                },
+                "delete" | "d" => match argument.parse() {
+                    Ok(line) if self.debugger.breakpoints.remove(&line) => {
+                        self.write_error_output(format_args!("breakpoint on line {line} removed\n"))
+                    }
+                    Ok(line) => self.write_error_output(format_args!(
+                        "there is no breakpoint on line {line}\n"
+                    )),
+                    Err(_) => self.write_error_output(format_args!(
+                        "delete must be followed by a line number\n"
+                    )),
+                },
+                "quit" | "q" => {
+                    // stops the program the way exit does, runtime_error expects ip to be
+                    // past the start of the instruction
+                    let mut err = self.runtime_error(
+                        unsafe { ip.add(1) },
+                        ErrorCode::NativeError,
+                        String::from("quit from the debugger"),
+                    );
+                    err.exit_status = Some(0);
+                    return Err(err);
+                }
+                "help" | "h" | "" => self.write_error_output(format_args!("{HELP}\n")),
+                _ => self.write_error_output(format_args!(
+                    "unknown command '{command}', enter help to see the commands\n"
+                )),
+            }
+        }
+    }
+
+    /// Value of a local of the current frame live at offset, or else of a global
+    fn variable(&self, function: &ObjFunction, offset: usize, name: &str) -> Option<Value> {
+        // a local declared later shadows an earlier one with the same name
+        let local = function
+            .chunk
+            .locals_at(offset)
+            .filter(|local| &*local.name == name)
+            .last();
+        if let Some(local) = local {
+            return Some(self.local_value(local.slot));
+        }
+
+        let value = self.globals.get(self.globals.lookup(name)?);
+        (!value.is_undef()).then_some(value)
+    }
+
+    /// Prints the entries of the map an argument names, either written out as a string
+    /// or number or held in a variable, in the order `for key in keys` visits them so
+    /// the output is the same every run
+    fn print_map(&self, function: &ObjFunction, offset: usize, argument: &str) {
+        let map_key = if let Some(name) = argument
+            .strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+        {
+            // a string that was never interned can't be the key of any map
+            self.gc.interned(name).map(Value::obj)
+        } else if let Ok(number) = argument.parse::<f64>() {
+            Some(Value::float(number))
+        } else if argument.is_empty() {
+            self.write_error_output(format_args!(
+                "map must be followed by a string, a number, or a variable\n"
+            ));
+            return;
+        } else if let Some(value) = self.variable(function, offset, argument) {
+            Some(value)
+        } else {
+            self.write_error_output(format_args!(
+                "there is no variable named '{argument}' here\n"
+            ));
+            return;
+        };
+
+        let keys = map_key.map_or_else(Vec::new, |map_key| self.map_keys(map_key));
+        if keys.is_empty() {
+            self.write_error_output(format_args!("{argument} has no entries\n"));
+        }
+        for key in keys {
+            if let Some(value) = map_key.and_then(|map_key| self.map_get(map_key, key)) {
+                self.write_error_output(format_args!("{key:?} = {value:?}\n"));
+            }
+        }
+    }
+
+    fn local_value(&self, slot: u16) -> Value {
+        let fp_offset = unsafe { (*self.frame_top).fp_offset };
+        unsafe { self.stack.base().add(fp_offset + slot as usize).read() }
+    }
+
+    fn print_locals(&self, function: &ObjFunction, offset: usize) {
+        let mut printed = false;
+        // names in brackets are hidden locals the compiler made, like a loop's string
+        for local in function
+            .chunk
+            .locals_at(offset)
+            .filter(|local| !local.name.starts_with('('))
+        {
+            let value = self.local_value(local.slot);
+            self.write_error_output(format_args!("{} = {value:?}\n", local.name));
+            printed = true;
+        }
+        if !printed {
+            self.write_error_output(format_args!("no locals\n"));
+        }
+    }
+}
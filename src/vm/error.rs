@@ -0,0 +1,86 @@
+use std::fmt::{self, Display};
+
+use crate::{codes::ErrorCode, compiler::Snippet, style};
+
+const TRACE_LIMIT: usize = 10;
+
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub line: u32,
+    /// Line the error happened on, if the function still has its source
+    pub snippet: Option<Snippet>,
+    /// Function name and line of the active call for each frame, innermost first
+    pub trace: Vec<(String, u32)>,
+    /// Set when the script stopped itself by calling exit, rather than failing
+    pub exit_status: Option<i32>,
+}
+
+/// The candidate closest to name, if one is close enough to be a likely misspelling.
+/// Of equally close candidates the earliest wins, so they should come most likely first.
+pub(crate) fn closest_name<'a>(
+    name: &str,
+    candidates: impl Iterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let max_distance = usize::max(1, name.chars().count() / 3);
+    candidates
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance, the fewest characters inserted, removed, or replaced to turn
+/// a into b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // distances from the prefix of a seen so far to every prefix of b
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let replaced = diagonal + usize::from(a_char != *b_char);
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} on line {}: {} [{}]",
+            style::error("error"),
+            self.line,
+            self.message,
+            self.code
+        )?;
+
+        if let Some(snippet) = &self.snippet {
+            write!(f, "\n{snippet}")?;
+        }
+
+        if self.trace.len() > 1 {
+            for (i, (name, line)) in self.trace.iter().enumerate() {
+                // deep recursion would bury the message, so only show both ends
+                if self.trace.len() > TRACE_LIMIT * 2 && i >= TRACE_LIMIT {
+                    if i == TRACE_LIMIT {
+                        let skipped = self.trace.len() - TRACE_LIMIT * 2;
+                        write!(f, "\n    ... {skipped} more frames")?;
+                    }
+                    if i < self.trace.len() - TRACE_LIMIT {
+                        continue;
+                    }
+                }
+                write!(f, "\n    in {name} on line {line}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
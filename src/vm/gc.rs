@@ -1,15 +1,108 @@
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    fmt::{self, Display},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
 use super::{
+    hash::FastBuildHasher,
     object::{Obj, ObjKind},
     value::Value,
 };
 
+/// Entry in the intern table, hashed and compared by the string's contents so it
+/// can be looked up with a &str
+struct InternedString(Obj);
+
+impl InternedString {
+    fn value(&self) -> &str {
+        unsafe { &(*self.0.string).value }
+    }
+}
+
+impl Hash for InternedString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.value().hash(state);
+    }
+}
+
+impl PartialEq for InternedString {
+    fn eq(&self, other: &Self) -> bool {
+        self.value() == other.value()
+    }
+}
+
+impl Eq for InternedString {}
+
+impl Borrow<str> for InternedString {
+    fn borrow(&self) -> &str {
+        self.value()
+    }
+}
+
 pub struct GC {
     objects: Vec<Option<Obj>>,
+    /// Every live string, weakly held so sweeping removes the dead ones
+    strings: HashSet<InternedString, FastBuildHasher>,
     free_slots: Vec<usize>,
     greys: Vec<Obj>,
     bytes_allocated: usize,
     next_gc: usize,
     program_running: bool,
+    /// Counters for `gc_stats` and `--gc-stats`, only touched when a collection runs
+    collections: u64,
+    total_pause: Duration,
+    bytes_freed: u64,
+    /// Most bytes allocated at once, as of the last collection
+    peak_bytes: usize,
+    live_after_collection: usize,
+}
+
+/// What the collector has done so far, for working out whether a program is slowed down
+/// by collecting
+pub struct GCStats {
+    /// Bytes allocated over the whole run, freed or not
+    pub allocated: u64,
+    /// Bytes allocated and not yet freed
+    pub live: usize,
+    /// Bytes left after the last collection, which all survived it
+    pub live_after_collection: usize,
+    /// Most bytes allocated at once
+    pub peak: usize,
+    pub collections: u64,
+    /// Time spent collecting, including marking the roots
+    pub total_pause: Duration,
+}
+
+impl Display for GCStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MIB: f64 = 1024.0 * 1024.0;
+        write!(f, "== gc stats ==")?;
+        write!(
+            f,
+            "\n{} collections took {:.3}ms in total",
+            self.collections,
+            self.total_pause.as_secs_f64() * 1000.0
+        )?;
+        if self.collections > 0 {
+            write!(
+                f,
+                " ({:.3}ms each on average)",
+                self.total_pause.as_secs_f64() * 1000.0 / self.collections as f64
+            )?;
+        }
+        write!(
+            f,
+            ". {:.2} MiB was allocated over the run, at most {:.2} MiB at once, and {:.2} MiB \
+             is still allocated, {:.2} MiB of it having survived the last collection.",
+            self.allocated as f64 / MIB,
+            self.peak as f64 / MIB,
+            self.live as f64 / MIB,
+            self.live_after_collection as f64 / MIB,
+        )
+    }
 }
 
 impl GC {
@@ -18,11 +111,17 @@ impl GC {
     pub fn new() -> Self {
         Self {
             objects: Vec::new(),
+            strings: HashSet::default(),
             free_slots: Vec::new(),
             greys: Vec::new(),
             bytes_allocated: 0,
             next_gc: 1024 * 1024,
             program_running: false,
+            collections: 0,
+            total_pause: Duration::ZERO,
+            bytes_freed: 0,
+            peak_bytes: 0,
+            live_after_collection: 0,
         }
     }
 
@@ -30,6 +129,10 @@ impl GC {
         self.program_running = true;
     }
 
+    pub fn program_stopped(&mut self) {
+        self.program_running = false;
+    }
+
     pub fn alloc<T>(&mut self, obj: impl GCAlloc<T>) -> Obj {
         obj.alloc(self)
     }
@@ -44,12 +147,17 @@ impl GC {
             println!("Blacken: {:?} {obj}", obj.kind());
 
             match unsafe { obj.common.read().kind } {
-                ObjKind::String | ObjKind::Native => (),
+                ObjKind::String | ObjKind::Native | ObjKind::Buffer => (),
                 ObjKind::Function => {
                     for value in unsafe { (*obj.function).chunk.constants.iter_mut() } {
                         self.mark(*value);
                     }
                 }
+                ObjKind::Keys => {
+                    for value in unsafe { (*obj.keys).keys.iter() } {
+                        self.mark(*value);
+                    }
+                }
             }
         }
     }
@@ -61,17 +169,43 @@ impl GC {
                     unsafe { (*obj.common).mark = false }
                 } else {
                     self.bytes_allocated -= obj.size();
+                    self.bytes_freed += obj.size() as u64;
+                    if obj.kind() == ObjKind::String {
+                        self.strings.remove(unsafe { &*(*obj.string).value });
+                    }
                     self.objects[i].take().unwrap().free()
                 }
             }
         }
     }
 
-    pub fn collect(&mut self) {
+    /// Frees everything that wasn't marked, once the roots have been. started is when
+    /// the collection began, so marking the roots counts towards its pause.
+    pub fn collect(&mut self, started: Instant) {
+        self.peak_bytes = self.peak_bytes.max(self.bytes_allocated);
         self.trace();
         self.sweep();
 
         self.next_gc = self.bytes_allocated * Self::HEAP_GROW_FACTOR;
+        self.live_after_collection = self.bytes_allocated;
+        self.collections += 1;
+        self.total_pause += started.elapsed();
+    }
+
+    pub fn stats(&self) -> GCStats {
+        GCStats {
+            allocated: self.bytes_freed + self.bytes_allocated as u64,
+            live: self.bytes_allocated,
+            live_after_collection: self.live_after_collection,
+            peak: self.peak_bytes.max(self.bytes_allocated),
+            collections: self.collections,
+            total_pause: self.total_pause,
+        }
+    }
+
+    /// Accounts for an object that grew after it was allocated, like an appended buffer
+    pub fn grew(&mut self, bytes: usize) {
+        self.bytes_allocated += bytes;
     }
 
     pub fn should_gc(&self) -> bool {
@@ -79,7 +213,16 @@ impl GC {
             && self.program_running
     }
 
+    pub fn interned(&self, value: &str) -> Option<Obj> {
+        self.strings.get(value).map(|string| string.0)
+    }
+
+    pub fn add_interned(&mut self, string: Obj) {
+        self.strings.insert(InternedString(string));
+    }
+
     pub fn free_everything(&mut self) {
+        self.strings.clear();
         for i in 0..self.objects.len() {
             if let Some(obj) = self.objects[i].take() {
                 obj.free()
@@ -1,38 +1,66 @@
 use std::collections::HashMap;
 
-use super::value::Value;
+use super::{
+    hash::{FastBuildHasher, Namespaces},
+    value::Value,
+};
 
 pub struct Globals {
     pub globals: Vec<Value>,
-    global_names: HashMap<String, u8>,
-    pub global_map: HashMap<Value, HashMap<Value, Value>>,
+    global_names: HashMap<String, u16, FastBuildHasher>,
+    /// Name of each global by index, the reverse of global_names
+    names: Vec<Box<str>>,
+    pub global_map: Namespaces,
 }
 
 impl Globals {
     pub fn new() -> Self {
         Self {
             globals: Vec::new(),
-            global_names: HashMap::new(),
-            global_map: HashMap::new(),
+            global_names: HashMap::default(),
+            names: Vec::new(),
+            global_map: Namespaces::default(),
         }
     }
 
-    pub fn get(&self, idx: u8) -> Value {
+    pub fn get(&self, idx: u16) -> Value {
         self.globals[idx as usize]
     }
 
-    pub fn set(&mut self, idx: u8, value: Value) {
+    pub fn set(&mut self, idx: u16, value: Value) {
         self.globals[idx as usize] = value;
     }
 
-    pub fn get_global_idx(&mut self, name: &str) -> u8 {
+    pub fn name(&self, idx: u16) -> &str {
+        &self.names[idx as usize]
+    }
+
+    /// Names of the globals that have been given a value, the most recently added first
+    /// so the program's own globals come before the natives and prelude
+    pub fn defined_names(&self) -> impl Iterator<Item = &str> {
+        self.names
+            .iter()
+            .zip(self.globals.iter())
+            .rev()
+            .filter(|(_, value)| !value.is_undef())
+            .map(|(name, _)| &**name)
+    }
+
+    /// Index of the global name, without defining it if there isn't one
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.global_names.get(name).copied()
+    }
+
+    /// Returns None once every index a global instruction can encode is in use
+    pub fn get_global_idx(&mut self, name: &str) -> Option<u16> {
         match self.global_names.get(name) {
-            Some(idx) => *idx,
+            Some(idx) => Some(*idx),
             None => {
-                let len = self.globals.len() as u8;
+                let len = u16::try_from(self.globals.len()).ok()?;
                 self.global_names.insert(name.to_owned(), len);
+                self.names.push(name.into());
                 self.globals.push(Value::UNDEF);
-                len
+                Some(len)
             }
         }
     }
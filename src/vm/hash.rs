@@ -0,0 +1,63 @@
+//! A faster hasher than the standard library's SipHash for the maps scripts use. Keys
+//! are values, which hash as a single u64 since strings are interned, so the hash
+//! only needs to spread those bits. Interning hashes the contents of new strings with
+//! it too. Resistance to keys crafted to collide isn't worth SipHash's cost here, a
+//! script can slow itself down far more easily.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hasher},
+};
+
+use super::value::Value;
+
+pub type FastBuildHasher = BuildHasherDefault<FastHasher>;
+
+/// The keys and values of one map
+pub type ValueMap = HashMap<Value, Value, FastBuildHasher>;
+
+/// Every map, keyed by the value that names it. Iterating one visits keys in an order
+/// that depends on where their objects were allocated, which changes from run to run,
+/// so anything that shows a script a map's keys gets them from `VM::map_keys`, which
+/// sorts them with `Value::key_cmp`.
+pub type Namespaces = HashMap<Value, ValueMap, FastBuildHasher>;
+
+/// Odd constant from the fractional part of pi, so multiplying by it loses no bits
+const MULTIPLIER: u64 = 0x243f_6a88_85a3_08d3;
+
+#[derive(Default)]
+pub struct FastHasher {
+    hash: u64,
+}
+
+impl Hasher for FastHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.write_u64(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let mut rest = [0; 8];
+        rest[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+        self.write_u64(u64::from_le_bytes(rest));
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        // folding the high half of the full product into the low half mixes every bit
+        // of i into the low bits, which pick the bucket. Pointers and whole numbers as
+        // floats have their low bits all zero, so a plain multiply would leave them so.
+        let product = (self.hash ^ i) as u128 * MULTIPLIER as u128;
+        self.hash = (product as u64) ^ ((product >> 64) as u64);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
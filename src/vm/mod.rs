@@ -1,54 +1,466 @@
-#[cfg(feature = "local_map_scopes")]
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, BufWriter, Write};
 use std::ptr::{self, NonNull};
+use std::time::Instant;
 
+use crate::{codes::ErrorCode, compiler::Snippet};
 use call_frame::CallFrame;
+use error::RuntimeError;
 use gc::{GCAlloc, GC};
 use globals::Globals;
-use object::{Obj, ObjKind, ObjString};
+#[cfg(feature = "local_map_scopes")]
+use hash::Namespaces;
+use object::{Obj, ObjKeys, ObjKind, ObjNative, ObjString};
 use stack::Stack;
 use value::Value;
 
-pub mod call_frame;
-pub mod chunk;
-pub mod gc;
-pub mod globals;
-pub mod object;
-pub mod stack;
+pub(crate) mod call_frame;
+pub(crate) mod chunk;
+#[cfg(feature = "debugger")]
+pub(crate) mod debugger;
+pub mod error;
+pub(crate) mod gc;
+pub(crate) mod globals;
+pub(crate) mod hash;
+pub(crate) mod object;
+#[cfg(feature = "profile")]
+pub(crate) mod profile;
+pub(crate) mod stack;
 pub mod value;
 
+pub use object::NativeFn;
+
 pub struct VM {
     frames: Vec<CallFrame>,
     frame_top: *mut CallFrame,
     gc: GC,
     stack: Stack,
-    pub globals: Globals,
+    pub(crate) globals: Globals,
+    pub args: Vec<String>,
+    /// Where `print` and friends write to. Flushed after every write unless
+    /// `buffered_output` is set, and always flushed before the VM exits.
+    pub output: RefCell<Box<dyn Write>>,
+    pub buffered_output: bool,
+    /// Where `eprint` and the interpreter's own diagnostics are written, kept apart from
+    /// `output` so a host can capture each one. Flushed after every write.
+    pub error_output: RefCell<Box<dyn Write>>,
+    /// Prints every instruction and the stack before running it, only has an effect
+    /// when built with the `runtime_trace` feature
+    pub trace: bool,
+    /// Counts every instruction and function call and reports them when the program
+    /// finishes, only has an effect when built with the `profile` feature
+    pub profile: bool,
+    /// Reports what the collector did when the program finishes
+    pub gc_stats: bool,
+    #[cfg(feature = "profile")]
+    profiler: profile::Profiler,
+    /// Stops at breakpoints and the first line to take commands from stdin, only has
+    /// an effect when built with the `debugger` feature
+    pub debug: bool,
+    #[cfg(feature = "debugger")]
+    debugger: debugger::Debugger,
+    /// Most frames there can be before a call is a stack overflow
+    pub max_frames: usize,
+    /// Most stack slots all frames together can use before a call is a stack overflow
+    pub max_stack_slots: usize,
+    /// Whether conditional jumps reject conditions that aren't booleans, which language
+    /// versions before 3 treat as false
+    pub(crate) bool_conditions: bool,
+    /// Whether the right side of `&&` and `||` has to be a boolean, which language
+    /// versions before 4 pass through as the result
+    pub(crate) bool_operands: bool,
+    /// Values a native is holding while it calls back into the VM, marked so the
+    /// collector doesn't free them while the native can't see them on the stack
+    pub(crate) native_roots: Vec<Value>,
 }
 
+pub const DEFAULT_MAX_FRAMES: usize = 10_000;
+pub const DEFAULT_MAX_STACK_SLOTS: usize = 1 << 22;
+
 // using format! rather than to_string measurably improves performance
 #[allow(clippy::useless_format)]
 impl VM {
-    pub fn new() -> Self {
+    pub(crate) fn new() -> Self {
         VM {
             frames: Vec::new(),
             frame_top: ptr::null_mut(),
             gc: GC::new(),
             stack: Stack::new(),
             globals: Globals::new(),
+            args: Vec::new(),
+            output: RefCell::new(Box::new(BufWriter::new(io::stdout()))),
+            buffered_output: false,
+            error_output: RefCell::new(Box::new(io::stderr())),
+            trace: false,
+            profile: false,
+            gc_stats: false,
+            #[cfg(feature = "profile")]
+            profiler: profile::Profiler::new(),
+            debug: false,
+            #[cfg(feature = "debugger")]
+            debugger: debugger::Debugger::new(),
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_stack_slots: DEFAULT_MAX_STACK_SLOTS,
+            bool_conditions: true,
+            bool_operands: true,
+            native_roots: Vec::new(),
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    pub fn runtime_error(&self, ip: *const u8, code: ErrorCode, message: String) -> RuntimeError {
+        // ip has already moved past the instruction, so look at its last byte
+        let location_at = |frame: &CallFrame, ip: *const u8| {
+            let chunk = unsafe { &(*frame.function.function).chunk };
+            let offset = unsafe { ip.offset_from(chunk.code_ptr()) };
+            chunk.location_for_offset(offset as usize - 1)
+        };
+
+        let mut trace = Vec::with_capacity(self.frames.len());
+        let mut location = None;
+        for (i, frame) in self.frames.iter().enumerate().rev() {
+            let name = unsafe { (*frame.function.function).display_name() }.to_owned();
+            let ip = if i == self.frames.len() - 1 {
+                ip
+            } else {
+                frame.ip
+            };
+            let frame_location = location_at(frame, ip);
+            location.get_or_insert(frame_location);
+            trace.push((name, frame_location.line));
+        }
+
+        let location = location.unwrap();
+        let source = unsafe {
+            (*self.frames.last().unwrap().function.function)
+                .source
+                .clone()
+        };
+        let snippet = source.and_then(|source| {
+            // the expression can start on an earlier line, so show the line it ends on
+            let last_byte = location.end.checked_sub(1)?;
+            let line_start = source
+                .as_bytes()
+                .get(..last_byte)?
+                .iter()
+                .rposition(|byte| *byte == b'\n')
+                .map_or(0, |i| i + 1);
+            let line_end = source[line_start..]
+                .find('\n')
+                .map_or(source.len(), |i| line_start + i);
+            let text = &source[line_start..line_end];
+            let start = match location.start.checked_sub(line_start) {
+                Some(start) => start,
+                None => text.len() - text.trim_start().len(),
+            };
+            Some(Snippet::new(
+                text,
+                location.line,
+                start..location.end - line_start,
+            ))
+        });
+
+        RuntimeError {
+            code,
+            message,
+            line: location.line,
+            snippet,
+            trace,
+            exit_status: None,
+        }
+    }
+
+    /// Error raised from a native, reported at the call that invoked it
+    #[cold]
+    #[inline(never)]
+    pub fn native_error(&self, message: String) -> RuntimeError {
+        self.runtime_error(
+            unsafe { (*self.frame_top).ip },
+            ErrorCode::NativeError,
+            message,
+        )
+    }
+
+    // error paths are kept out of line so the dispatch loop only contains the happy path
+
+    #[cold]
+    #[inline(never)]
+    fn arity_error(&self, arity: u32, arg_count: u8) -> RuntimeError {
+        self.runtime_error(
+            unsafe { (*self.frame_top).ip },
+            ErrorCode::WrongArgumentCount,
+            format!("expected {arity} arguments but got {arg_count}"),
+        )
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn stack_overflow_error(&self) -> RuntimeError {
+        self.runtime_error(
+            unsafe { (*self.frame_top).ip },
+            ErrorCode::StackOverflow,
+            format!("stack overflow, {} calls deep", self.frames.len()),
+        )
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn call_error(&self) -> RuntimeError {
+        self.runtime_error(
+            unsafe { (*self.frame_top).ip },
+            ErrorCode::NotCallable,
+            format!("can only call functions"),
+        )
+    }
+
+    /// Numbers are always finite, so results that would be infinite or NaN are errors
+    #[cold]
+    #[inline(never)]
+    fn arithmetic_error(&self, ip: *const u8, op: &str, a: Value, b: Value) -> RuntimeError {
+        if op == "/" && b.as_float() == 0.0 {
+            self.runtime_error(
+                ip,
+                ErrorCode::DivisionByZero,
+                format!("can't divide {a} by zero"),
+            )
+        } else {
+            self.runtime_error(
+                ip,
+                ErrorCode::NumberOverflow,
+                format!("'{op}' overflowed to infinity, numbers must be finite"),
+            )
+        }
+    }
+
+    /// Orders two strings by their contents for a comparison operator, which is an
+    /// error for anything else that isn't two floats
+    #[cold]
+    #[inline(never)]
+    fn compare_strings(
+        &self,
+        ip: *const u8,
+        op: &str,
+        a: Value,
+        b: Value,
+    ) -> Result<Ordering, RuntimeError> {
+        if !a.is_string() || !b.is_string() {
+            return Err(self.binary_op_error(ip, op, a, b));
         }
+        unsafe { Ok((*a.as_obj().string).value.cmp(&(*b.as_obj().string).value)) }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn binary_op_error(&self, ip: *const u8, op: &str, a: Value, b: Value) -> RuntimeError {
+        self.runtime_error(
+            ip,
+            ErrorCode::InvalidOperands,
+            format!(
+                "can't apply '{op}' to {} and {}",
+                a.describe(),
+                b.describe()
+            ),
+        )
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn unary_op_error(&self, ip: *const u8, op: &str, value: Value) -> RuntimeError {
+        self.runtime_error(
+            ip,
+            ErrorCode::InvalidOperand,
+            format!("can't apply '{op}' to {}", value.describe()),
+        )
     }
 
     #[cold]
-    pub fn runtime_error(&self, ip: *const u8, message: String) -> ! {
-        // this sleep improves the performance of the vm
-        // this is likely due to a weird interaction with binary layout or branch prediction
-        // remove if this no longer results in a performance gain
-        std::thread::sleep(std::time::Duration::from_millis(10));
+    #[inline(never)]
+    fn condition_error(&self, ip: *const u8, value: Value) -> RuntimeError {
+        self.runtime_error(
+            ip,
+            ErrorCode::NonBoolCondition,
+            format!("condition must be a boolean, got {}", value.describe()),
+        )
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn operand_bool_error(&self, ip: *const u8, value: Value) -> RuntimeError {
+        self.runtime_error(
+            ip,
+            ErrorCode::NonBoolCondition,
+            format!(
+                "right side of '&&' or '||' must be a boolean, got {}",
+                value.describe()
+            ),
+        )
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn iteration_error(&self, ip: *const u8, value: Value) -> RuntimeError {
+        self.runtime_error(
+            ip,
+            ErrorCode::NotIterable,
+            format!("cannot iterate over a {}", value.type_name()),
+        )
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn step_error(&self, ip: *const u8, step: Value) -> RuntimeError {
+        let message = if step.is_float() {
+            String::from("step of a for range can't be 0, the loop would never end")
+        } else {
+            format!(
+                "step of a for range must be a number, not {}",
+                step.describe()
+            )
+        };
+        self.runtime_error(ip, ErrorCode::InvalidStep, message)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn undefined_global_error(&self, ip: *const u8, action: &str, idx: u16) -> RuntimeError {
+        let name = self.globals.name(idx);
+        let mut message = format!("{action} value of undefined variable '{name}'");
+
+        // the locals in scope where the error happened are the likeliest to be meant,
+        // innermost first
+        let function = unsafe { &*(*self.frame_top).function.function };
+        let offset = unsafe { ip.offset_from(function.chunk.code_ptr()) } as usize - 1;
+        let locals: Vec<&str> = function
+            .chunk
+            .locals_at(offset)
+            .map(|local| &*local.name)
+            .filter(|local| !local.starts_with('('))
+            .collect();
+        let candidates = locals.into_iter().rev().chain(self.globals.defined_names());
+        if let Some(closest) = error::closest_name(name, candidates) {
+            message.push_str(&format!(", did you mean '{closest}'?"));
+        }
+
+        self.runtime_error(ip, ErrorCode::UndefinedVariable, message)
+    }
+
+    /// Prints the stack and the instruction about to run
+    #[cfg(any(feature = "trace_execution", feature = "runtime_trace"))]
+    #[cold]
+    #[inline(never)]
+    fn trace_instruction(&self, ip: *const u8, sp: NonNull<Value>) {
+        let mut stack_ptr = self.stack.base();
+        while stack_ptr != sp.as_ptr() {
+            let value_str = String::from_utf8(escape_bytes::escape(
+                format!("{}", unsafe { *stack_ptr }).as_bytes(),
+            ))
+            .unwrap();
+            print!(
+                "[ {} ]",
+                value_str
+                    .chars()
+                    .take(usize::min(20, value_str.len()))
+                    .collect::<String>()
+            );
+            stack_ptr = unsafe { stack_ptr.add(1) };
+        }
+        println!();
+
         let chunk = unsafe { &(*(*self.frame_top).function.function).chunk };
-        let offset = unsafe { ip.offset_from(chunk.code_ptr()) };
-        let line = chunk.lines[offset as usize];
-        eprintln!("\x1b[91merror\x1b[0m on line {line}: {message}");
-        std::process::exit(101);
+        chunk.disassemble_instruction(ip as usize - chunk.code_ptr() as usize);
+    }
+
+    /// Appends a value to a buffer without copying the buffer, strings and buffers by
+    /// their contents
+    #[inline(never)]
+    fn append_to_buffer(&mut self, buffer: Value, value: Value) {
+        // copied out first since a buffer can be appended to itself, and anything that
+        // isn't a string is written the way print shows it
+        let appended = value.to_string();
+        unsafe { (*buffer.as_obj().buffer).value.push_str(&appended) };
+        self.gc.grew(appended.len());
+    }
+
+    /// What the collector has done since the VM was made
+    pub(crate) fn gc_stats(&self) -> gc::GCStats {
+        self.gc.stats()
+    }
+
+    /// Number of active call frames, counting the script itself. Natives don't get a
+    /// frame, so this is the depth of the function that called the native.
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Stack slots in use, synced when a native is called. Includes the native and
+    /// its arguments, which sit on top of the caller's values.
+    pub fn stack_slots_used(&self) -> usize {
+        unsafe { self.stack.top.as_ptr().offset_from(self.stack.base()) as usize }
+    }
+
+    /// Sends output from `print` and friends somewhere other than stdout, flushing
+    /// whatever was written to the old output first
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.flush_output();
+        *self.output.get_mut() = output;
+    }
+
+    /// Sends output from `eprint` and diagnostics somewhere other than stderr
+    pub fn set_error_output(&mut self, output: Box<dyn Write>) {
+        *self.error_output.get_mut() = output;
+    }
+
+    pub fn write_output(&self, args: fmt::Arguments) {
+        let mut output = self.output.borrow_mut();
+        let _ = output.write_fmt(args);
+        if !self.buffered_output {
+            let _ = output.flush();
+        }
+    }
+
+    pub fn flush_output(&self) {
+        let _ = self.output.borrow_mut().flush();
+    }
+
+    pub fn write_error_output(&self, args: fmt::Arguments) {
+        let mut output = self.error_output.borrow_mut();
+        let _ = output.write_fmt(args);
+        let _ = output.flush();
+    }
+
+    /// Writes a diagnostic, runtime error, or anything else from the interpreter itself
+    /// to error_output on its own line
+    pub fn report(&self, message: impl fmt::Display) {
+        // anything the script printed should come before the error it led to
+        self.flush_output();
+        self.write_error_output(format_args!("{message}\n"));
+    }
+
+    /// Makes a native callable from scripts as the global name, replacing anything
+    /// already defined with that name
+    pub fn define_native(&mut self, name: &str, native: NativeFn) -> u16 {
+        let native = self.alloc(ObjNative::new(native));
+        let idx = self
+            .globals
+            .get_global_idx(name)
+            .expect("natives are defined before the globals run out");
+        self.globals.set(idx, Value::obj(native));
+        idx
+    }
+
+    /// Returns the string object for value, creating it if no live string has the
+    /// same contents. All strings go through here, so equal strings are the same object.
+    pub fn intern(&mut self, value: &str) -> Obj {
+        if let Some(obj) = self.gc.interned(value) {
+            return obj;
+        }
+
+        let obj = self.alloc(ObjString::new(value));
+        self.gc.add_interned(obj);
+        obj
     }
 
     pub fn alloc<T>(&mut self, obj: impl GCAlloc<T>) -> Obj {
@@ -61,8 +473,9 @@ impl VM {
             #[cfg(feature = "debug_gc")]
             println!("--- GC START ---");
 
+            let started = Instant::now();
             self.mark_roots();
-            self.gc.collect();
+            self.gc.collect(started);
 
             #[cfg(feature = "debug_gc")]
             println!("--- GC END ---");
@@ -98,6 +511,10 @@ impl VM {
             self.gc.mark(*value);
         }
 
+        for value in self.native_roots.iter() {
+            self.gc.mark(*value);
+        }
+
         for (value, map) in self.globals.global_map.iter() {
             self.gc.mark(*value);
 
@@ -108,43 +525,87 @@ impl VM {
         }
     }
 
-    pub fn call(&mut self, function: Obj, arg_count: u8) {
+    pub fn call(&mut self, function: Obj, arg_count: u8) -> Result<(), RuntimeError> {
         let arity = unsafe { (*function.function).arity };
         if arg_count as u32 != arity {
-            self.runtime_error(
-                unsafe { (*self.frame_top).ip },
-                format!("expected {arity} arguments but got {arg_count}"),
-            );
+            return Err(self.arity_error(arity, arg_count));
+        }
+
+        let slots = unsafe { (*function.function).stack_effect } as usize;
+        if self.frames.len() >= self.max_frames
+            || self.stack.allocated() + slots > self.max_stack_slots
+        {
+            return Err(self.stack_overflow_error());
+        }
+
+        #[cfg(feature = "profile")]
+        if self.profile {
+            self.profiler
+                .call(unsafe { (*function.function).display_name() });
         }
 
         self.push_call_frame(function);
+        Ok(())
     }
 
-    pub fn call_value(&mut self, function: Value, arg_count: u8) {
+    pub fn call_value(&mut self, function: Value, arg_count: u8) -> Result<(), RuntimeError> {
         if function.is_obj() {
             match function.as_obj().kind() {
-                ObjKind::Function => self.call(function.as_obj(), arg_count),
+                ObjKind::Function => return self.call(function.as_obj(), arg_count),
                 ObjKind::Native => {
                     let native = unsafe { (*function.as_obj().native).function };
-                    let result = native(
-                        arg_count as u32,
-                        unsafe { self.stack.top.sub(arg_count as usize) },
-                        self as *mut VM,
-                    );
+                    let args = unsafe { self.stack.top.sub(arg_count as usize) };
+                    let result = native(self, arg_count as u32, args)?;
                     self.stack.top = unsafe { self.stack.top.sub(arg_count as usize + 1) };
                     self.stack.push(result);
+                    return Ok(());
                 }
-                _ => self.runtime_error(
-                    unsafe { (*self.frame_top).ip },
-                    format!("can only call functions"),
-                ),
+                _ => (),
             }
-            return;
         }
-        self.runtime_error(
-            unsafe { (*self.frame_top).ip },
-            format!("can only call functions"),
-        );
+        Err(self.call_error())
+    }
+
+    /// Replaces the current frame with a call to function, whose arguments are on top
+    /// of the stack, so tail recursion runs in constant space
+    fn tail_call(&mut self, function: Obj, arg_count: u8) -> Result<(), RuntimeError> {
+        let arity = unsafe { (*function.function).arity };
+        if arg_count as u32 != arity {
+            return Err(self.arity_error(arity, arg_count));
+        }
+
+        let slots = unsafe { (*function.function).stack_effect } as usize;
+        let caller_slots = unsafe { (*self.frame().function.function).stack_effect } as usize;
+        if self.stack.allocated() - caller_slots + slots > self.max_stack_slots {
+            return Err(self.stack_overflow_error());
+        }
+
+        #[cfg(feature = "profile")]
+        if self.profile {
+            self.profiler
+                .call(unsafe { (*function.function).display_name() });
+        }
+
+        // move the function and its arguments down to where the caller's are
+        let fp_offset = self.frame().fp_offset;
+        unsafe {
+            let callee = self.stack.top.as_ptr().sub(arg_count as usize + 1);
+            let slot = self.stack.base_mut().add(fp_offset - 1);
+            ptr::copy(callee, slot, arg_count as usize + 1);
+            self.stack.top = NonNull::new_unchecked(slot.add(arg_count as usize + 1));
+        }
+
+        let caller = std::mem::replace(&mut self.frame().function, function);
+        self.stack
+            .free_slots(unsafe { (*caller.function).stack_effect });
+        self.stack
+            .allocate_slots(unsafe { (*function.function).stack_effect });
+
+        let frame = self.frame();
+        frame.ip = unsafe { (*function.function).chunk.code_ptr() };
+        #[cfg(feature = "local_map_scopes")]
+        frame.local_maps.clear();
+        Ok(())
     }
 
     pub fn push_call_frame(&mut self, function: Obj) {
@@ -155,20 +616,192 @@ impl VM {
         self.frame_top = unsafe { self.frames.last_mut().unwrap_unchecked() as *mut CallFrame };
     }
 
-    pub fn pop_call_frame(&mut self) -> CallFrame {
-        let function = unsafe { self.frames.pop().unwrap_unchecked() };
+    /// Pops the innermost frame, leaving frame_top null once the last one is gone
+    pub fn pop_call_frame(&mut self) -> Option<CallFrame> {
+        let frame = self.frames.pop()?;
         self.stack
-            .free_slots(unsafe { (*function.function.function).stack_effect });
-        self.frame_top = unsafe { self.frames.last_mut().unwrap_unchecked() as *mut CallFrame };
-        function
+            .free_slots(unsafe { (*frame.function.function).stack_effect });
+        self.frame_top = self
+            .frames
+            .last_mut()
+            .map_or(ptr::null_mut(), |frame| frame as *mut CallFrame);
+        Some(frame)
     }
 
     pub fn frame(&mut self) -> &mut CallFrame {
         unsafe { self.frame_top.as_mut().unwrap_unchecked() }
     }
 
+    /// Runs the script pushed by the compiler and returns its result. The VM can be
+    /// given another script afterwards, its globals are kept.
+    pub fn run(&mut self) -> Result<Value, RuntimeError> {
+        let result = self.execute(0);
+        // the compiler allocates constants that aren't rooted until they're run
+        self.gc.program_stopped();
+        if result.is_err() {
+            self.unwind();
+        }
+        self.flush_output();
+        #[cfg(feature = "profile")]
+        if self.profile {
+            let profile = self.profiler.finish();
+            self.report(profile);
+        }
+        if self.gc_stats {
+            self.report(self.gc.stats());
+        }
+        result
+    }
+
+    /// Drops every frame and value left on the stack by a runtime error
+    fn unwind(&mut self) {
+        while let Some(frame) = self.frames.pop() {
+            self.stack
+                .free_slots(unsafe { (*frame.function.function).stack_effect });
+        }
+        self.frame_top = ptr::null_mut();
+        self.stack.top = unsafe { NonNull::new_unchecked(self.stack.base_mut()) };
+        self.native_roots.clear();
+    }
+
+    /// Looks key up in the map map_key, searching the innermost local map scopes first
+    /// when they're enabled
+    #[inline]
+    pub(crate) fn map_get(&self, map_key: Value, key: Value) -> Option<Value> {
+        #[cfg(feature = "local_map_scopes")]
+        if let Some(frame) = self.frames.last() {
+            for map in frame.local_maps.iter().rev() {
+                if let Some(value) = map.get(&map_key).and_then(|map| map.get(&key)) {
+                    return Some(*value);
+                }
+            }
+        }
+
+        self.globals
+            .global_map
+            .get(&map_key)
+            .and_then(|map| map.get(&key))
+            .copied()
+    }
+
+    /// Removes key from the map map_key where map_get would find it, returning its value
+    pub(crate) fn map_remove(&mut self, map_key: Value, key: Value) -> Option<Value> {
+        #[cfg(feature = "local_map_scopes")]
+        if let Some(frame) = self.frames.last_mut() {
+            for map in frame.local_maps.iter_mut().rev() {
+                if let Some(value) = map.get_mut(&map_key).and_then(|map| map.remove(&key)) {
+                    return Some(value);
+                }
+            }
+        }
+
+        self.globals
+            .global_map
+            .get_mut(&map_key)
+            .and_then(|map| map.remove(&key))
+    }
+
+    /// Number of keys map_get can find in the map map_key. With local map scopes a key
+    /// set in more than one scope, or in a scope and globally, is only counted once.
+    pub(crate) fn map_count(&self, map_key: Value) -> usize {
+        let global = self.globals.global_map.get(&map_key);
+
+        #[cfg(feature = "local_map_scopes")]
+        if let Some(frame) = self.frames.last() {
+            let mut scopes = frame
+                .local_maps
+                .iter()
+                .filter_map(|map| map.get(&map_key))
+                .peekable();
+            if scopes.peek().is_some() {
+                let mut keys: std::collections::HashSet<Value> = global
+                    .into_iter()
+                    .flat_map(|map| map.keys().copied())
+                    .collect();
+                for map in scopes {
+                    keys.extend(map.keys().copied());
+                }
+                return keys.len();
+            }
+        }
+
+        global.map_or(0, |map| map.len())
+    }
+
+    /// Keys map_get can find in the map map_key, in the order of Value::key_cmp
+    pub(crate) fn map_keys(&self, map_key: Value) -> Vec<Value> {
+        let mut keys: Vec<Value> = self
+            .globals
+            .global_map
+            .get(&map_key)
+            .map_or_else(Vec::new, |map| map.keys().copied().collect());
+
+        #[cfg(feature = "local_map_scopes")]
+        if let Some(frame) = self.frames.last() {
+            // a key set in more than one scope is only visited once
+            let mut seen: std::collections::HashSet<Value> = keys.iter().copied().collect();
+            for map in frame.local_maps.iter().filter_map(|map| map.get(&map_key)) {
+                keys.extend(map.keys().filter(|key| seen.insert(**key)));
+            }
+        }
+
+        keys.sort_by(Value::key_cmp);
+        keys
+    }
+
+    /// Drops every value in the map map_key, including any in the current local map scopes
+    pub(crate) fn map_clear(&mut self, map_key: Value) {
+        #[cfg(feature = "local_map_scopes")]
+        if let Some(frame) = self.frames.last_mut() {
+            for map in frame.local_maps.iter_mut() {
+                map.remove(&map_key);
+            }
+        }
+
+        self.globals.global_map.remove(&map_key);
+    }
+
+    /// Calls function with args from inside a native and returns its result. The stack
+    /// can move while the function runs, so natives should read all of their own
+    /// arguments before calling this.
+    pub fn call_function(
+        &mut self,
+        function: Value,
+        args: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        let Ok(arg_count) = u8::try_from(args.len()) else {
+            return Err(self.native_error(format!(
+                "can't call a function with {} arguments, the most is 255",
+                args.len()
+            )));
+        };
+
+        // the function and its arguments aren't part of any frame's slots
+        let slots = arg_count as u32 + 1;
+        self.stack.allocate_slots(slots);
+        self.stack.push(function);
+        for arg in args {
+            self.stack.push(*arg);
+        }
+
+        let depth = self.frames.len();
+        let result = match self.call_value(function, arg_count) {
+            // run the new frame until it returns back to the native's caller
+            Ok(()) if self.frames.len() > depth => self.execute(depth),
+            // natives have already finished and left their result on the stack
+            Ok(()) => unsafe {
+                self.stack.top = self.stack.top.sub(1);
+                Ok(self.stack.top.read())
+            },
+            Err(err) => Err(err),
+        };
+        self.stack.free_slots(slots);
+        result
+    }
+
+    /// Runs frames until the one above return_depth returns, so 0 runs the whole script
     #[allow(unused_unsafe)]
-    pub fn run(&mut self) {
+    fn execute(&mut self, return_depth: usize) -> Result<Value, RuntimeError> {
         let mut ip = self.frame().ip;
         let mut sp = self.stack.top;
 
@@ -182,11 +815,26 @@ impl VM {
             };
         }
 
+        macro_rules! next_short {
+            () => {
+                ((next_byte!() as u16) << 8) | next_byte!() as u16
+            };
+        }
+
+        macro_rules! next_long {
+            () => {
+                ((next_byte!() as usize) << 24)
+                    | ((next_byte!() as usize) << 16)
+                    | ((next_byte!() as usize) << 8)
+                    | next_byte!() as usize
+            };
+        }
+
         macro_rules! next_constant {
             () => {
                 unsafe {
                     let byte = next_byte!();
-                    (*self.frame().function.function).chunk.constants[byte as usize]
+                    (&(*self.frame().function.function).chunk.constants)[byte as usize]
                 }
             };
         }
@@ -199,14 +847,6 @@ impl VM {
             };
         }
 
-        #[cfg(feature = "trace_execution")]
-        macro_rules! current_offset {
-            () => {
-                ip.wrapping_sub((*self.frame().function.function).chunk.code_ptr() as usize)
-                    as usize
-            };
-        }
-
         macro_rules! stack_push {
             ($val:expr) => {
                 unsafe {
@@ -231,17 +871,32 @@ impl VM {
             };
         }
 
+        macro_rules! condition {
+            ($value:expr) => {{
+                let value = $value;
+                if !value.is_bool() && self.bool_conditions {
+                    return Err(self.condition_error(ip, value));
+                }
+                value.as_bool()
+            }};
+        }
+
         macro_rules! binary_op {
-            ($op:tt, $msg:expr) => {
+            ($op:tt) => {
                 {
                     let b = stack_pop!();
                     let a = stack_pop!();
 
                     if !a.is_float() || !b.is_float() {
-                        self.runtime_error(ip, format!("attemped to {0} {1:?} and {2:?}, but can only {0} numbers", $msg, a, b));
+                        return Err(self.binary_op_error(ip, stringify!($op), a, b));
+                    }
+
+                    let result = a.as_float() $op b.as_float();
+                    if !result.is_finite() {
+                        return Err(self.arithmetic_error(ip, stringify!($op), a, b));
                     }
 
-                    stack_push!(Value::float(a.as_float() $op b.as_float()));
+                    stack_push!(Value::float(result));
                 }
             };
         }
@@ -263,41 +918,34 @@ impl VM {
                     let b = stack_pop!();
                     let a = stack_pop!();
 
-                    if !a.is_float() || !b.is_float() {
-                        self.runtime_error(ip, format!("attemped to compare {:?} and {:?}, but can only compare numbers", a, b));
-                    }
+                    // floats first, with strings out of line so the float path stays small
+                    let result = if a.is_float() && b.is_float() {
+                        a.as_float() $op b.as_float()
+                    } else {
+                        self.compare_strings(ip, stringify!($op), a, b)? $op Ordering::Equal
+                    };
 
-                    stack_push!(Value::bool(a.as_float() $op b.as_float()));
+                    stack_push!(Value::bool(result));
                 }
             };
         }
 
         self.gc.program_started();
 
-        '_next: loop {
-            #[cfg(feature = "trace_execution")]
-            {
-                let mut stack_ptr = self.stack.base();
-                while stack_ptr != sp.as_ptr() {
-                    let value_str = String::from_utf8(escape_bytes::escape(
-                        format!("{}", unsafe { *stack_ptr }).as_bytes(),
-                    ))
-                    .unwrap();
-                    print!(
-                        "[ {} ]",
-                        value_str
-                            .chars()
-                            .take(usize::min(20, value_str.len()))
-                            .collect::<String>()
-                    );
-                    stack_ptr = unsafe { stack_ptr.add(1) };
-                }
-                println!();
-                unsafe {
-                    (*self.frame().function.function)
-                        .chunk
-                        .disassemble_instruction(current_offset!());
-                }
+        loop {
+            #[cfg(any(feature = "trace_execution", feature = "runtime_trace"))]
+            if cfg!(feature = "trace_execution") || self.trace {
+                self.trace_instruction(ip, sp);
+            }
+
+            #[cfg(feature = "profile")]
+            if self.profile {
+                self.profiler.instruction(unsafe { ip.read() });
+            }
+
+            #[cfg(feature = "debugger")]
+            if self.debug {
+                self.debug_instruction(ip)?;
             }
 
             use chunk::OpCode as Op;
@@ -310,10 +958,15 @@ impl VM {
                     let idx = ((next_byte!() as usize) << 16)
                         | ((next_byte!() as usize) << 8)
                         | next_byte!() as usize;
-                    let value = unsafe { (*self.frame().function.function).chunk.constants[idx] };
+                    let value =
+                        unsafe { (&(*self.frame().function.function).chunk.constants)[idx] };
                     stack_push!(value);
                 }
                 Op::Null => stack_push!(Value::NULL),
+                Op::LoadZero => stack_push!(Value::float(0.0)),
+                Op::LoadOne => stack_push!(Value::float(1.0)),
+                Op::LoadTrue => stack_push!(Value::TRUE),
+                Op::LoadFalse => stack_push!(Value::FALSE),
                 Op::Pop => {
                     stack_pop!();
                 }
@@ -322,7 +975,11 @@ impl VM {
                     let a = stack_pop!();
 
                     if a.is_float() && b.is_float() {
-                        stack_push!(Value::float(a.as_float() + b.as_float()))
+                        let result = a.as_float() + b.as_float();
+                        if !result.is_finite() {
+                            return Err(self.arithmetic_error(ip, "+", a, b));
+                        }
+                        stack_push!(Value::float(result))
                     } else if a.is_string() && b.is_string() {
                         let new_str = unsafe {
                             format!(
@@ -331,17 +988,25 @@ impl VM {
                                 (*b.as_obj().string).value
                             )
                         };
-                        let obj = ObjString::new(&new_str);
                         self.stack.top = sp;
-                        let obj = self.alloc(obj);
+                        let obj = self.intern(&new_str);
+                        stack_push!(Value::obj(obj))
+                    } else if a.is_buffer() {
+                        self.append_to_buffer(a, b);
+                        stack_push!(a)
+                    } else if a.is_string() || b.is_string() {
+                        // the side that isn't a string is written the way print shows it
+                        let new_str = format!("{a}{b}");
+                        self.stack.top = sp;
+                        let obj = self.intern(&new_str);
                         stack_push!(Value::obj(obj))
                     } else {
-                        self.runtime_error(ip, format!("attempted to add {:?} and {:?}, but can only add strings and numbers", a, b));
+                        return Err(self.binary_op_error(ip, "+", a, b));
                     }
                 }
-                Op::Sub => binary_op!(-, "subtract"),
-                Op::Mul => binary_op!(*, "multiply"),
-                Op::Div => binary_op!(/, "divide"),
+                Op::Sub => binary_op!(-),
+                Op::Mul => binary_op!(*),
+                Op::Div => binary_op!(/),
                 Op::Equal => equality_op!(==),
                 Op::NotEqual => equality_op!(!=),
                 Op::Greater => comparison_op!(>),
@@ -350,13 +1015,7 @@ impl VM {
                 Op::LessEqual => comparison_op!(<=),
                 Op::Negate => {
                     if !stack_peek!(0).is_float() {
-                        self.runtime_error(
-                            ip,
-                            format!(
-                                "attemped to negate {:?}, but can only negate numbers",
-                                stack_peek!(0)
-                            ),
-                        );
+                        return Err(self.unary_op_error(ip, "-", stack_peek!(0)));
                     }
                     unsafe {
                         let top_ptr = sp.sub(1);
@@ -365,13 +1024,7 @@ impl VM {
                 }
                 Op::Not => {
                     if !stack_peek!(0).is_bool() {
-                        self.runtime_error(
-                            ip,
-                            format!(
-                                "attemped to not {:?}, but can only not boolean values",
-                                stack_peek!(0)
-                            ),
-                        );
+                        return Err(self.unary_op_error(ip, "!", stack_peek!(0)));
                     }
                     unsafe {
                         let top_ptr = sp.sub(1);
@@ -379,31 +1032,49 @@ impl VM {
                     }
                 }
                 Op::DefineGlobal => {
-                    let idx = next_byte!();
+                    let idx = next_byte!() as u16;
+                    self.globals.set(idx, stack_pop!());
+                }
+                Op::DefineGlobalExt => {
+                    let idx = next_short!();
                     self.globals.set(idx, stack_pop!());
                 }
                 Op::GetGlobal => {
-                    let idx = next_byte!();
+                    let idx = next_byte!() as u16;
                     let value = self.globals.get(idx);
 
                     if value.is_undef() {
-                        self.runtime_error(
-                            ip,
-                            format!("attempted to get value of undefined variable"),
-                        );
+                        return Err(self.undefined_global_error(ip, "attempted to get", idx));
+                    }
+
+                    stack_push!(value);
+                }
+                Op::GetGlobalExt => {
+                    let idx = next_short!();
+                    let value = self.globals.get(idx);
+
+                    if value.is_undef() {
+                        return Err(self.undefined_global_error(ip, "attempted to get", idx));
                     }
 
                     stack_push!(value);
                 }
                 Op::SetGlobal => {
-                    let idx = next_byte!();
+                    let idx = next_byte!() as u16;
                     let prev_value = self.globals.get(idx);
 
                     if prev_value.is_undef() {
-                        self.runtime_error(
-                            ip,
-                            format!("attemped to set value of undefined variable"),
-                        );
+                        return Err(self.undefined_global_error(ip, "attempted to set", idx));
+                    }
+
+                    self.globals.set(idx, stack_peek!(0));
+                }
+                Op::SetGlobalExt => {
+                    let idx = next_short!();
+                    let prev_value = self.globals.get(idx);
+
+                    if prev_value.is_undef() {
+                        return Err(self.undefined_global_error(ip, "attempted to set", idx));
                     }
 
                     self.globals.set(idx, stack_peek!(0));
@@ -420,28 +1091,34 @@ impl VM {
                         .add(next_byte!() as usize + fp_offset)
                         .write(stack_peek!(0));
                 },
-                Op::GetMap => {
-                    let key = stack_pop!();
-                    let map_key = stack_pop!();
-
-                    #[cfg(feature = "local_map_scopes")]
-                    for map in unsafe { (*self.frame_top).local_maps.iter().rev() } {
-                        if let Some(value_map) = map.get(&map_key) {
-                            if let Some(value) = value_map.get(&key) {
-                                stack_push!(*value);
-                                continue '_next;
-                            }
-                        }
-                    }
+                Op::IncrementLocal => {
+                    let offset = next_byte!() as usize;
+                    let fp_offset = self.frame().fp_offset;
+                    let local = unsafe { self.stack.base_mut().add(offset + fp_offset) };
+                    let value = unsafe { local.read() };
 
-                    if let Some(value_map) = self.globals.global_map.get(&map_key) {
-                        if let Some(value) = value_map.get(&key) {
-                            stack_push!(*value);
-                            continue;
-                        }
+                    if !value.is_float() {
+                        return Err(self.binary_op_error(ip, "+", value, Value::float(1.0)));
                     }
 
-                    stack_push!(Value::NULL);
+                    unsafe { local.write(Value::float(value.as_float() + 1.0)) };
+                }
+                Op::GetLocalExt => {
+                    let offset = next_short!() as usize;
+                    let fp_offset = self.frame().fp_offset;
+                    stack_push!(self.stack.base().add(offset + fp_offset).read());
+                }
+                Op::SetLocalExt => unsafe {
+                    let fp_offset = self.frame().fp_offset;
+                    self.stack
+                        .base_mut()
+                        .add(next_short!() as usize + fp_offset)
+                        .write(stack_peek!(0));
+                },
+                Op::GetMap => {
+                    let key = stack_pop!();
+                    let map_key = stack_pop!();
+                    stack_push!(self.map_get(map_key, key).unwrap_or(Value::NULL));
                 }
                 Op::SetMap => {
                     let value = stack_pop!();
@@ -469,7 +1146,7 @@ impl VM {
                 }
                 #[cfg(feature = "local_map_scopes")]
                 Op::PushMap => {
-                    self.frame().local_maps.push(HashMap::new());
+                    self.frame().local_maps.push(Namespaces::default());
                 }
                 #[cfg(feature = "local_map_scopes")]
                 Op::PopMap => {
@@ -488,49 +1165,183 @@ impl VM {
                 Op::JumpIfFalse => {
                     let offset = (next_byte!() as usize) << 8 | next_byte!() as usize;
 
-                    if !stack_pop!().as_bool() {
+                    if !condition!(stack_pop!()) {
                         jump!(offset);
                     }
                 }
                 Op::JumpIfFalseNoPop => {
                     let offset = (next_byte!() as usize) << 8 | next_byte!() as usize;
 
-                    if !stack_peek!(0).as_bool() {
+                    if !condition!(stack_peek!(0)) {
                         jump!(offset);
                     }
                 }
                 Op::JumpIfTrueNoPop => {
                     let offset = (next_byte!() as usize) << 8 | next_byte!() as usize;
 
-                    if stack_peek!(0).as_bool() {
+                    if condition!(stack_peek!(0)) {
                         jump!(offset);
                     }
                 }
+                Op::JumpLong => {
+                    let offset = next_long!();
+
+                    jump!(offset);
+                }
+                Op::JumpUpLong => {
+                    let offset = next_long!();
+
+                    unsafe { ip = ip.sub(offset) }
+                }
+                Op::JumpIfFalseLong => {
+                    let offset = next_long!();
+
+                    if !condition!(stack_pop!()) {
+                        jump!(offset);
+                    }
+                }
+                Op::JumpIfFalseNoPopLong => {
+                    let offset = next_long!();
+
+                    if !condition!(stack_peek!(0)) {
+                        jump!(offset);
+                    }
+                }
+                Op::JumpIfTrueNoPopLong => {
+                    let offset = next_long!();
+
+                    if condition!(stack_peek!(0)) {
+                        jump!(offset);
+                    }
+                }
+                Op::NextChar => {
+                    // the string being iterated over is in the given slot and the byte offset
+                    // of the next character is in the slot after it
+                    let slot = next_short!() as usize + self.frame().fp_offset;
+                    let string_ptr = unsafe { self.stack.base_mut().add(slot) };
+                    let string = unsafe { string_ptr.read() };
+
+                    if !string.is_string() {
+                        return Err(self.iteration_error(ip, string));
+                    }
+
+                    let offset = unsafe { string_ptr.add(1).read().as_float() as usize };
+                    let next =
+                        unsafe { (&(*string.as_obj().string).value)[offset..].chars().next() };
+                    match next {
+                        Some(c) => {
+                            unsafe {
+                                string_ptr
+                                    .add(1)
+                                    .write(Value::float((offset + c.len_utf8()) as f64))
+                            };
+                            self.stack.top = sp;
+                            let obj = self.intern(c.encode_utf8(&mut [0; 4]));
+                            stack_push!(Value::obj(obj));
+                            stack_push!(Value::TRUE);
+                        }
+                        None => stack_push!(Value::FALSE),
+                    }
+                }
+                Op::MapKeys => {
+                    let map_key = stack_pop!();
+                    let keys = self.map_keys(map_key);
+                    // the keys are still in the map, so collecting here can't free them
+                    self.stack.top = sp;
+                    let obj = self.alloc(ObjKeys::new(keys));
+                    stack_push!(Value::obj(obj));
+                }
+                Op::NextKey => {
+                    // the snapshot of keys is in the given slot and the index of the next
+                    // key is in the slot after it
+                    let slot = next_short!() as usize + self.frame().fp_offset;
+                    let keys_ptr = unsafe { self.stack.base_mut().add(slot) };
+                    let keys = unsafe { &(*keys_ptr.read().as_obj().keys).keys };
+                    let index = unsafe { keys_ptr.add(1).read().as_float() as usize };
+
+                    match keys.get(index) {
+                        Some(key) => {
+                            let key = *key;
+                            unsafe { keys_ptr.add(1).write(Value::float((index + 1) as f64)) };
+                            stack_push!(key);
+                            stack_push!(Value::TRUE);
+                        }
+                        None => stack_push!(Value::FALSE),
+                    }
+                }
+                Op::CheckStep => {
+                    let step = stack_peek!(0);
+                    if !step.is_float() || step.as_float() == 0.0 {
+                        return Err(self.step_error(ip, step));
+                    }
+                }
+                Op::CheckBool => {
+                    let value = stack_peek!(0);
+                    if !value.is_bool() && self.bool_operands {
+                        return Err(self.operand_bool_error(ip, value));
+                    }
+                }
                 Op::Call => {
                     let arg_count = next_byte!();
                     let function = stack_peek!(arg_count as usize);
                     self.frame().ip = ip;
                     self.stack.top = sp;
-                    self.call_value(function, arg_count);
+                    self.call_value(function, arg_count)?;
                     ip = self.frame().ip;
                     sp = self.stack.top;
                 }
-                Op::Return => {
-                    if self.frames.len() == 1 {
-                        self.gc.free_everything();
-                        return;
+                Op::TailCall => {
+                    let arg_count = next_byte!();
+                    let function = stack_peek!(arg_count as usize);
+                    self.frame().ip = ip;
+                    self.stack.top = sp;
+                    if function.is_obj() && function.as_obj().kind() == ObjKind::Function {
+                        self.tail_call(function.as_obj(), arg_count)?;
+                    } else {
+                        // natives finish straight away, then the Return after this
+                        // returns their result like any other call
+                        self.call_value(function, arg_count)?;
                     }
-
-                    let result = stack_pop!();
-                    let old_frame = self.pop_call_frame();
                     ip = self.frame().ip;
+                    sp = self.stack.top;
+                }
+                Op::Return => {
+                    let result = stack_pop!();
+                    // the frame being returned from is the one running, so there is one
+                    let old_frame = unsafe { self.pop_call_frame().unwrap_unchecked() };
+
+                    if self.frames.is_empty() {
+                        // only the script's return value should have been left, anything else
+                        // means some path through an expression didn't clean up after itself
+                        debug_assert_eq!(
+                            unsafe { sp.as_ptr().offset_from(self.stack.base()) },
+                            0,
+                            "stack unbalanced at end of program"
+                        );
+                        self.stack.top = sp;
+                        return Ok(result);
+                    }
 
                     sp = unsafe {
                         NonNull::new_unchecked(self.stack.base_mut().add(old_frame.fp_offset - 1))
                     };
+
+                    if self.frames.len() == return_depth {
+                        // a function called by a native has finished, hand its result back
+                        self.stack.top = sp;
+                        return Ok(result);
+                    }
+
+                    ip = self.frame().ip;
                     stack_push!(result);
                 }
             }
         }
     }
 }
+
+impl Drop for VM {
+    fn drop(&mut self) {
+        self.gc.free_everything();
+    }
+}
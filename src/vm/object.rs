@@ -1,9 +1,10 @@
 use std::{
     fmt::{Debug, Display},
     ptr::{self, NonNull},
+    rc::Rc,
 };
 
-use super::{chunk::Chunk, value::Value, VM};
+use super::{chunk::Chunk, error::RuntimeError, value::Value, VM};
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -11,6 +12,8 @@ pub enum ObjKind {
     String,
     Function,
     Native,
+    Buffer,
+    Keys,
 }
 
 #[derive(Clone, Copy)]
@@ -19,6 +22,8 @@ pub union Obj {
     pub string: *mut ObjString,
     pub function: *mut ObjFunction,
     pub native: *mut ObjNative,
+    pub buffer: *mut ObjBuffer,
+    pub keys: *mut ObjKeys,
 }
 
 impl Obj {
@@ -29,9 +34,13 @@ impl Obj {
     pub fn size(&self) -> usize {
         unsafe {
             match self.kind() {
-                ObjKind::String => (*self.string).value.len() + size_of::<ObjString>(),
+                ObjKind::String => (&*self.string).value.len() + size_of::<ObjString>(),
                 ObjKind::Function => (*self.function).chunk.size() + size_of::<ObjFunction>(),
                 ObjKind::Native => size_of::<ObjNative>(),
+                ObjKind::Buffer => (&*self.buffer).value.len() + size_of::<ObjBuffer>(),
+                ObjKind::Keys => {
+                    (&*self.keys).keys.len() * size_of::<Value>() + size_of::<ObjKeys>()
+                }
             }
         }
     }
@@ -45,6 +54,8 @@ impl Obj {
                 ObjKind::String => drop(Box::from_raw(self.string)),
                 ObjKind::Function => drop(Box::from_raw(self.function)),
                 ObjKind::Native => drop(Box::from_raw(self.native)),
+                ObjKind::Buffer => drop(Box::from_raw(self.buffer)),
+                ObjKind::Keys => drop(Box::from_raw(self.keys)),
             }
         }
     }
@@ -56,6 +67,9 @@ impl Debug for Obj {
             ObjKind::String => write!(f, "\"{}\"", unsafe {
                 String::from_utf8(escape_bytes::escape((*self.string).value.as_bytes())).unwrap()
             }),
+            ObjKind::Buffer => write!(f, "<buffer \"{}\">", unsafe {
+                String::from_utf8(escape_bytes::escape((*self.buffer).value.as_bytes())).unwrap()
+            }),
             _ => write!(f, "{self}"),
         }
     }
@@ -65,19 +79,21 @@ impl Display for Obj {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.kind() {
             ObjKind::String => write!(f, "{}", unsafe { &(*self.string).value }),
-            ObjKind::Function => write!(f, "<fn>"),
+            ObjKind::Function => match unsafe { &(*self.function).name } {
+                Some(name) => write!(f, "<fn {name}>"),
+                None => write!(f, "<fn>"),
+            },
             ObjKind::Native => write!(f, "<native fn>"),
+            ObjKind::Buffer => write!(f, "{}", unsafe { &(*self.buffer).value }),
+            ObjKind::Keys => write!(f, "<keys>"),
         }
     }
 }
 
+/// Strings are interned, so every kind of object is equal only to itself
 impl PartialEq for Obj {
     fn eq(&self, other: &Self) -> bool {
-        if self.kind() == ObjKind::String && other.kind() == ObjKind::String {
-            unsafe { (*self.string).value == (*other.string).value }
-        } else {
-            unsafe { ptr::eq(self.common, other.common) }
-        }
+        unsafe { ptr::eq(self.common, other.common) }
     }
 }
 
@@ -97,7 +113,9 @@ from_obj_impl! {
     common ObjCommon,
     string ObjString,
     function ObjFunction,
-    native ObjNative
+    native ObjNative,
+    buffer ObjBuffer,
+    keys ObjKeys
 }
 
 #[repr(C)]
@@ -128,12 +146,53 @@ impl ObjString {
     }
 }
 
+/// A mutable string that `+` appends to in place, for building up large strings
+/// without copying the prefix on every append
+#[repr(C)]
+pub struct ObjBuffer {
+    pub common: ObjCommon,
+    pub value: String,
+}
+
+impl ObjBuffer {
+    pub fn new(value: &str) -> Self {
+        Self {
+            common: ObjCommon::new(ObjKind::Buffer),
+            value: value.to_owned(),
+        }
+    }
+}
+
+/// The keys a map had when a for loop over them started, kept in a hidden local so
+/// changing the map inside the loop doesn't change which keys it visits
+#[repr(C)]
+pub struct ObjKeys {
+    pub common: ObjCommon,
+    pub keys: Vec<Value>,
+}
+
+impl ObjKeys {
+    pub fn new(keys: Vec<Value>) -> Self {
+        Self {
+            common: ObjCommon::new(ObjKind::Keys),
+            keys,
+        }
+    }
+}
+
 #[repr(C)]
 pub struct ObjFunction {
     pub common: ObjCommon,
+    /// Name of the variable the function was declared with, if any
+    pub name: Option<Box<str>>,
     pub arity: u32,
     pub stack_effect: u32,
     pub chunk: Chunk,
+    /// Program the function was compiled from, used to show the line of a runtime error
+    pub source: Option<Rc<str>>,
+    /// Offset of the first instruction compiled from the program, which comes after the
+    /// prelude in the script
+    pub program_start: usize,
 }
 
 impl ObjFunction {
@@ -141,14 +200,34 @@ impl ObjFunction {
         let common = ObjCommon::new(ObjKind::Function);
         Self {
             common,
+            name: None,
             arity: 0,
             stack_effect: 10,
             chunk: Chunk::new(),
+            source: None,
+            program_start: 0,
+        }
+    }
+
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("<anonymous>")
+    }
+
+    /// Disassembles this function followed by every function defined inside it
+    pub fn disassemble(&self) {
+        println!("== {} (arity {}) ==", self.display_name(), self.arity);
+        self.chunk.disassemble();
+
+        for constant in self.chunk.constants.iter() {
+            if constant.is_obj() && constant.as_obj().kind() == ObjKind::Function {
+                println!();
+                unsafe { (*constant.as_obj().function).disassemble() };
+            }
         }
     }
 }
 
-pub type NativeFn = fn(u32, NonNull<Value>, *mut VM) -> Value;
+pub type NativeFn = fn(&mut VM, u32, NonNull<Value>) -> Result<Value, RuntimeError>;
 
 #[repr(C)]
 pub struct ObjNative {
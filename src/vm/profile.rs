@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    time::{Duration, Instant},
+};
+
+use super::chunk::OpCode;
+
+/// Only one instruction in this many is timed, and its time is counted this many
+/// times, which keeps reading the clock from dominating the profile
+const SAMPLE_INTERVAL: u32 = 64;
+
+/// Instruction and call counts collected while profiling, reported when the program
+/// finishes
+pub(crate) struct Profiler {
+    op_counts: [u64; 256],
+    /// Estimated from the sampled instructions
+    op_times: [Duration; 256],
+    calls: HashMap<Box<str>, u64>,
+    executed: u64,
+    /// Opcode being timed and when it started, ended by the next instruction
+    sample: Option<(u8, Instant)>,
+    /// How long reading the clock takes, left out of every sample
+    clock_overhead: Duration,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            op_counts: [0; 256],
+            op_times: [Duration::ZERO; 256],
+            calls: HashMap::new(),
+            executed: 0,
+            sample: None,
+            clock_overhead: clock_overhead(),
+        }
+    }
+
+    /// Counts the instruction about to run, called before every instruction
+    #[inline]
+    pub fn instruction(&mut self, op: u8) {
+        self.end_sample();
+        self.op_counts[op as usize] += 1;
+        self.executed += 1;
+        if self.executed.is_multiple_of(SAMPLE_INTERVAL as u64) {
+            self.sample = Some((op, Instant::now()));
+        }
+    }
+
+    fn end_sample(&mut self) {
+        if let Some((op, start)) = self.sample.take() {
+            let elapsed = start.elapsed().saturating_sub(self.clock_overhead);
+            self.op_times[op as usize] += elapsed * SAMPLE_INTERVAL;
+        }
+    }
+
+    /// Counts a call to a script function, tail calls included
+    pub fn call(&mut self, name: &str) {
+        match self.calls.get_mut(name) {
+            Some(count) => *count += 1,
+            None => {
+                self.calls.insert(name.into(), 1);
+            }
+        }
+    }
+
+    /// Takes everything counted so far, leaving the profiler ready for the next run
+    pub fn finish(&mut self) -> Profile {
+        self.end_sample();
+        let profiler = std::mem::replace(
+            self,
+            Profiler {
+                clock_overhead: self.clock_overhead,
+                ..Profiler::new()
+            },
+        );
+
+        let mut ops: Vec<(OpCode, u64, Duration)> = (0..=u8::MAX)
+            .filter(|op| profiler.op_counts[*op as usize] > 0)
+            .map(|op| {
+                // only opcodes that were executed are counted, so op is a valid one
+                let opcode = unsafe { std::mem::transmute::<u8, OpCode>(op) };
+                (
+                    opcode,
+                    profiler.op_counts[op as usize],
+                    profiler.op_times[op as usize],
+                )
+            })
+            .collect();
+        // counts don't change between runs of the same program, so they decide the order
+        ops.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| (a.0 as u8).cmp(&(b.0 as u8))));
+
+        let mut calls: Vec<(Box<str>, u64)> = profiler.calls.into_iter().collect();
+        calls.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        Profile {
+            ops,
+            calls,
+            executed: profiler.executed,
+        }
+    }
+}
+
+/// Shortest time between reading the clock and reading it again, which every sample
+/// includes on top of the instruction it times
+fn clock_overhead() -> Duration {
+    (0..1000)
+        .map(|_| Instant::now().elapsed())
+        .min()
+        .unwrap_or_default()
+}
+
+/// Sorted counts from a finished run
+pub(crate) struct Profile {
+    ops: Vec<(OpCode, u64, Duration)>,
+    calls: Vec<(Box<str>, u64)>,
+    executed: u64,
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "== profile ==")?;
+        write!(
+            f,
+            "\n{:<24} {:>14} {:>7} {:>12}",
+            "opcode", "executed", "%", "est. time"
+        )?;
+        for (op, count, time) in self.ops.iter() {
+            let percent = *count as f64 / self.executed as f64 * 100.0;
+            write!(
+                f,
+                "\n{:<24} {count:>14} {percent:>6.2}% {:>10.3}ms",
+                format!("{op:?}"),
+                time.as_secs_f64() * 1000.0
+            )?;
+        }
+        write!(f, "\n{:<24} {:>14}", "total", self.executed)?;
+
+        if !self.calls.is_empty() {
+            write!(f, "\n\n{:<24} {:>14}", "function", "calls")?;
+            for (name, count) in self.calls.iter() {
+                write!(f, "\n{name:<24} {count:>14}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
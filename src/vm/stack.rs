@@ -40,12 +40,20 @@ impl Stack {
         self.max_use += slots as usize;
 
         if self.max_use > self.stack.capacity() {
+            // frames only store offsets into the stack, so top is the only pointer to fix up
+            // the vm reloads its cached stack pointer from top after every call
             let offset = unsafe { self.top.as_ptr().offset_from(self.base()) as usize };
-            self.stack.reserve(self.stack.capacity() * 2);
+            self.stack
+                .reserve(usize::max(self.max_use, self.stack.capacity() * 2));
             self.top = unsafe { NonNull::new_unchecked(self.base_mut().add(offset)) };
         }
     }
 
+    /// Slots allocated to every frame on the stack
+    pub fn allocated(&self) -> usize {
+        self.max_use
+    }
+
     pub fn free_slots(&mut self, slots: u32) {
         self.max_use -= slots as usize;
     }
@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::fmt;
 use std::mem;
 
@@ -34,8 +35,11 @@ impl Value {
         value: QNAN | TAG_FALSE,
     };
 
+    /// Numbers have a single zero, -0.0 is stored as 0.0 so it prints, compares,
+    /// and hashes the same everywhere
     pub fn float(value: f64) -> Self {
-        unsafe { mem::transmute(value) }
+        // adding positive zero turns -0.0 into 0.0 and leaves every other value alone
+        unsafe { mem::transmute(value + 0.0) }
     }
 
     pub fn bool(value: bool) -> Self {
@@ -54,6 +58,10 @@ impl Value {
         }
     }
 
+    pub fn to_bits(&self) -> u64 {
+        self.value
+    }
+
     pub fn is_float(&self) -> bool {
         self.value & QNAN != QNAN
     }
@@ -78,6 +86,14 @@ impl Value {
         self.is_obj() && matches!(self.as_obj().kind(), ObjKind::String)
     }
 
+    pub fn is_buffer(&self) -> bool {
+        self.is_obj() && matches!(self.as_obj().kind(), ObjKind::Buffer)
+    }
+
+    pub fn is_native(&self) -> bool {
+        self.is_obj() && matches!(self.as_obj().kind(), ObjKind::Native)
+    }
+
     pub fn as_float(&self) -> f64 {
         f64::from_bits(self.value)
     }
@@ -89,6 +105,66 @@ impl Value {
     pub fn as_obj(&self) -> Obj {
         ((self.value & !(SIGN_BIT | QNAN)) as *mut ObjCommon).into()
     }
+
+    pub fn type_name(&self) -> &'static str {
+        if self.is_float() {
+            "number"
+        } else if self.is_bool() {
+            "bool"
+        } else if self.is_null() {
+            "null"
+        } else if self.is_obj() {
+            match self.as_obj().kind() {
+                ObjKind::String => "string",
+                ObjKind::Function => "function",
+                ObjKind::Native => "native function",
+                ObjKind::Buffer => "buffer",
+                ObjKind::Keys => "keys",
+            }
+        } else {
+            "undefined"
+        }
+    }
+
+    /// A total order over values, so the keys of a map can be visited in the same order
+    /// every run: null, then booleans, numbers, strings, and anything else
+    pub fn key_cmp(&self, other: &Value) -> Ordering {
+        fn rank(value: &Value) -> u8 {
+            if value.is_null() {
+                0
+            } else if value.is_bool() {
+                1
+            } else if value.is_float() {
+                2
+            } else if value.is_string() {
+                3
+            } else {
+                4
+            }
+        }
+
+        rank(self).cmp(&rank(other)).then_with(|| match rank(self) {
+            1 => self.as_bool().cmp(&other.as_bool()),
+            // numbers are always finite so this is a total order
+            2 => self.as_float().total_cmp(&other.as_float()),
+            3 => unsafe {
+                (*self.as_obj().string)
+                    .value
+                    .cmp(&(*other.as_obj().string).value)
+            },
+            4 => format!("{self:?}").cmp(&format!("{other:?}")),
+            _ => Ordering::Equal,
+        })
+    }
+
+    /// Type name followed by the value, for use in error messages
+    pub fn describe(&self) -> String {
+        if self.is_null() {
+            String::from("null")
+        } else {
+            format!("{} ({:?})", self.type_name(), self)
+        }
+    }
 }
 
 impl std::cmp::PartialEq for Value {
@@ -109,9 +185,8 @@ impl std::cmp::Eq for Value {}
 
 impl std::hash::Hash for Value {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        if self.is_string() {
-            unsafe { (*self.as_obj().string).value.hash(state) };
-        } else if self.is_float() {
+        // strings are interned, so hashing the pointer is enough
+        if self.is_float() {
             unsafe { std::mem::transmute::<f64, OrderedFloat<f64>>(self.as_float()).hash(state) }
         } else {
             self.value.hash(state);
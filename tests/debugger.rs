@@ -0,0 +1,152 @@
+//! Drives `--debug` through stdin the way someone at the prompt would, checking where
+//! the program stops and what the commands print. Like the exit status tests, these
+//! read stdout, so they don't run with the features that dump the VM's workings to it.
+#![cfg(all(
+    feature = "debugger",
+    not(any(
+        feature = "trace_execution",
+        feature = "debug_gc",
+        feature = "decompile"
+    ))
+))]
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
+
+const PROGRAM: &str = "\
+let square = fn (x) {
+    let y = x * x;
+    return y;
+};
+
+let total = 0;
+for i in 0>3 {
+    let s = square(i);
+    total = total + s;
+}
+print(total);
+split_into(\"pear fig apple kiwi\", \" \", \"fruit\");
+\"sizes\"[\"pear\"] = 0; \"sizes\"[\"fig\"] = 1; \"sizes\"[\"apple\"] = 2; \"sizes\"[\"kiwi\"] = 3;
+clear(\"fruit\");
+";
+
+/// Writes the program once, since the tests run at the same time
+fn program_path() -> &'static Path {
+    static PATH: OnceLock<PathBuf> = OnceLock::new();
+    PATH.get_or_init(|| {
+        let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("debugger.dy");
+        fs::write(&path, PROGRAM).unwrap();
+        path
+    })
+}
+
+/// Runs the program under the debugger with the commands as its input, returning its
+/// exit status, output, and the debugger's own output
+fn debug(args: &[&str], commands: &str) -> (Option<i32>, String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dymaxilang"))
+        .args(args)
+        .arg("--no-color")
+        .arg(program_path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("dymaxilang should start");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(commands.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    (
+        output.status.code(),
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+fn stops(debugger_output: &str) -> Vec<&str> {
+    debugger_output
+        .lines()
+        .filter_map(|line| line.split("stopped at ").nth(1))
+        .collect()
+}
+
+#[test]
+fn breakpoint_and_locals() {
+    let (status, output, debugger) = debug(&["--break", "3"], "locals\nprint total\nc\nc\nc\n");
+    assert_eq!(status, Some(0));
+    assert_eq!(output, "5\n");
+    assert_eq!(stops(&debugger), ["line 3 in square"; 3]);
+    assert!(debugger.contains("x = 0\ny = 0\n"));
+    // total is a global, so it can be printed from inside square
+    assert!(debugger.contains("(debug) 0\n"));
+}
+
+#[test]
+fn step_goes_into_calls_and_next_steps_over_them() {
+    let (_, _, debugger) = debug(&["--break", "8"], "s\ns\ns\nq\n");
+    assert_eq!(
+        stops(&debugger),
+        [
+            "line 8 in <script>",
+            "line 2 in square",
+            "line 3 in square",
+            "line 9 in <script>"
+        ]
+    );
+
+    let (_, _, debugger) = debug(&["--break", "8"], "d 8\nn\nn\nq\n");
+    assert_eq!(
+        stops(&debugger),
+        [
+            "line 8 in <script>",
+            "line 9 in <script>",
+            "line 10 in <script>"
+        ]
+    );
+}
+
+#[test]
+fn quit_stops_the_program() {
+    let (status, output, _) = debug(&["--debug"], "quit\n");
+    assert_eq!(status, Some(0));
+    assert!(output.is_empty());
+}
+
+#[test]
+fn runs_to_the_end_without_input() {
+    let (status, output, _) = debug(&["--debug"], "");
+    assert_eq!(status, Some(0));
+    assert_eq!(output, "5\n");
+}
+
+#[test]
+fn breakpoint_on_line_without_code() {
+    let (_, output, debugger) = debug(&["--break", "5"], "");
+    assert!(debugger.contains("line 5 has no code"));
+    assert_eq!(output, "5\n");
+}
+
+#[test]
+fn map_entries_are_sorted_the_same_every_run() {
+    let commands = "m \"sizes\"\nm \"fruit\"\nm nothing\nc\n";
+    let (_, _, first) = debug(&["--break", "14"], commands);
+    let (_, _, second) = debug(&["--break", "14"], commands);
+    assert_eq!(first, second);
+    assert!(
+        first.contains("(debug) \"apple\" = 2\n\"fig\" = 1\n\"kiwi\" = 3\n\"pear\" = 0\n"),
+        "{first}"
+    );
+    assert!(
+        first.contains("(debug) 0 = \"pear\"\n1 = \"fig\"\n"),
+        "{first}"
+    );
+    assert!(first.contains("there is no variable named 'nothing' here"));
+}
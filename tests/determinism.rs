@@ -0,0 +1,88 @@
+//! Runs the same programs in separate processes and checks they print the same thing
+//! each time. Maps are iterated in an order that depends on where their keys were
+//! allocated, so anything that shows a map's keys has to sort them first.
+//!
+//! The programs fill maps inside loops, which `local_map_scopes` would keep local to
+//! the loop, and read what they print, which the features that dump the VM's workings
+//! to stdout would mix into.
+#![cfg(not(any(
+    feature = "local_map_scopes",
+    feature = "trace_execution",
+    feature = "debug_gc",
+    feature = "decompile"
+)))]
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+fn output(source: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dymaxilang"))
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("dymaxilang should start");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn assert_same_every_run(source: &str) -> String {
+    let first = output(source);
+    for _ in 0..3 {
+        assert_eq!(output(source), first);
+    }
+    first
+}
+
+#[test]
+fn keys_of_a_map() {
+    let source = r#"
+        split_into("kiwi pear fig apple plum date lime", " ", "fruit");
+        for i in 0>7 {
+            "lengths"["fruit"[i]] = i;
+        }
+        "lengths"[3] = true;
+        "lengths"[null] = 0;
+        "lengths"[false] = 1;
+        for key in keys "lengths" {
+            print(key, "lengths"[key]);
+        }
+    "#;
+    let printed = assert_same_every_run(source);
+    assert_eq!(
+        printed,
+        "null 0\nfalse 1\n3 true\napple 3\ndate 5\nfig 2\nkiwi 0\nlime 6\npear 1\nplum 4\n"
+    );
+}
+
+#[test]
+fn keys_after_removing_and_adding() {
+    let source = r#"
+        split_into("q w e r t y u i o p a s d f g h j k l z x c v b n m", " ", "letters");
+        for i in 0>26 {
+            "m"["letters"[i]] = i;
+        }
+        for i in 0>13 {
+            remove("m", "letters"[i * 2]);
+        }
+        "m"["zz"] = 0;
+        "m"["aa"] = 0;
+        let line = buffer();
+        for key in keys "m" {
+            line + key + ",";
+        }
+        print(to_str(line));
+    "#;
+    let printed = assert_same_every_run(source);
+    assert_eq!(printed, "aa,b,c,f,h,i,k,m,p,r,s,w,y,z,zz,\n");
+}
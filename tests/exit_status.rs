@@ -0,0 +1,226 @@
+//! Runs the dymaxilang binary on small programs given on stdin and checks the exit
+//! status it stops with for each way a program can end, including being killed, along
+//! with the command line around it: sources it can't read, arguments passed on to the
+//! script, --no-prelude, and --explain.
+//!
+//! Some checks read what the program printed, which the features that dump the VM's
+//! workings to stdout would mix into.
+#![cfg(not(any(
+    feature = "trace_execution",
+    feature = "debug_gc",
+    feature = "decompile"
+)))]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    process::{Command, Output, Stdio},
+};
+
+use dymaxilang::codes::ErrorCode;
+
+fn run(args: &[&str], source: impl AsRef<[u8]>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dymaxilang"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("dymaxilang should start");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_ref())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn status(args: &[&str], source: &str) -> i32 {
+    run(args, source).status.code().expect("exited normally")
+}
+
+#[test]
+fn success() {
+    assert_eq!(status(&["-"], "print(1);"), 0);
+}
+
+#[test]
+fn compile_error() {
+    assert_eq!(status(&["-"], "print(;"), 65);
+    assert_eq!(status(&["--check", "-"], "print(;"), 65);
+}
+
+#[test]
+fn runtime_error() {
+    assert_eq!(status(&["-"], "print(1 + true);"), 70);
+}
+
+#[test]
+fn bad_options() {
+    assert_eq!(status(&["--not-an-option"], ""), 2);
+}
+
+#[test]
+fn exit_with_status() {
+    let output = run(&["-"], "print(\"before\"); exit(3); print(\"after\");");
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "before\n");
+    assert!(output.stderr.is_empty());
+
+    assert_eq!(status(&["-"], "exit();"), 0);
+    assert_eq!(status(&["--buffered-output", "-"], "print(1); exit(4);"), 4);
+    assert_eq!(
+        run(&["--buffered-output", "-"], "print(1); exit(4);").stdout,
+        b"1\n"
+    );
+}
+
+#[test]
+fn exit_inside_call() {
+    let source = "let f = fn () { exit(5); }; f(); print(\"after\");";
+    let output = run(&["-"], source);
+    assert_eq!(output.status.code(), Some(5));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn invalid_exit_status() {
+    assert_eq!(status(&["-"], "exit(256);"), 70);
+    assert_eq!(status(&["-"], "exit(1.5);"), 70);
+}
+
+#[test]
+fn unreadable_sources() {
+    let output = run(&["-"], b"print(1);\0\x01\x02");
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("source file (<stdin>) does not look like a dymaxilang source file"),
+        "{stderr}"
+    );
+
+    // "café" in Latin-1
+    let output = run(&["-"], b"print(\"caf\xE9\");");
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("source file (<stdin>) is not valid UTF-8 (invalid byte at offset 10)"),
+        "{stderr}"
+    );
+
+    let output = run(&["--max-source-size", "4", "-"], "print(1);");
+    assert_eq!(output.status.code(), Some(65));
+
+    let missing = concat!(env!("CARGO_TARGET_TMPDIR"), "/missing.dy");
+    let output = run(&[missing], "");
+    assert_eq!(output.status.code(), Some(66));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("not found (NotFound)"), "{stderr}");
+}
+
+#[test]
+fn script_arguments() {
+    let source = "
+        print(args_count());
+        for i in 0>args_count() {
+            print(args(i));
+        }
+        print(args(3));
+    ";
+    let output = run(&["-", "one", "two words", "--strict"], source);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "3\none\ntwo words\n--strict\nnull\n"
+    );
+
+    assert_eq!(run(&["-"], "print(args_count());").stdout, b"0\n");
+}
+
+#[test]
+fn no_prelude_leaves_its_globals_undefined() {
+    let prelude = include_str!("../src/compiler/prelude.dy");
+    let names: Vec<&str> = prelude
+        .lines()
+        .filter_map(|line| line.strip_prefix("let "))
+        .filter_map(|line| line.split_once(" = ").map(|(name, _)| name))
+        .collect();
+    assert!(names.contains(&"max"), "prelude globals not found");
+
+    for name in names {
+        let source = format!("print(is_null({name}));");
+        assert_eq!(run(&["-"], &source).stdout, b"false\n", "{name}");
+
+        let output = run(&["--no-prelude", "-"], &source);
+        assert_eq!(output.status.code(), Some(70), "{name}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(&format!("undefined variable '{name}'")),
+            "{name}: {stderr}"
+        );
+    }
+}
+
+#[test]
+fn every_code_is_explained() {
+    for code in ErrorCode::ALL {
+        let output = run(&["--explain", code.as_str()], "");
+        assert_eq!(output.status.code(), Some(0), "--explain {code}");
+        let explanation = String::from_utf8_lossy(&output.stdout);
+        assert!(!explanation.trim().is_empty(), "{code} has no explanation");
+        assert_eq!(explanation, code.explanation());
+    }
+
+    // and every explanation belongs to a code
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/codes");
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_stem().unwrap().to_str().unwrap();
+        assert!(
+            ErrorCode::from_code(name).is_some(),
+            "{} doesn't explain a code",
+            path.display()
+        );
+    }
+
+    assert_eq!(status(&["--explain", "X9999"], ""), 2);
+}
+
+#[test]
+fn output_before_being_killed() {
+    // stops by itself after a while, so a missing line fails the test rather than
+    // leaving it waiting forever
+    let source = "
+        print(\"step 1\");
+        print_raw(\"step 2\\n\");
+        let start = time();
+        while time() < start + 30 {}
+    ";
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dymaxilang"))
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("dymaxilang should start");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+
+    // both lines have to arrive while the program is still running
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut printed = String::new();
+    for _ in 0..2 {
+        stdout.read_line(&mut printed).unwrap();
+    }
+    assert_eq!(child.try_wait().unwrap(), None, "stopped early");
+    child.kill().unwrap();
+    child.wait().unwrap();
+    stdout.read_to_string(&mut printed).unwrap();
+
+    assert_eq!(printed, "step 1\nstep 2\n");
+}
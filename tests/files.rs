@@ -0,0 +1,37 @@
+//! Writes files from scripts and reads them back.
+
+use std::{fs, path::PathBuf};
+
+use common::Captured;
+use dymaxilang::{style, Interpreter};
+
+mod common;
+
+#[test]
+fn write_append_and_read_back() {
+    style::set_color(false);
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("write_append.txt");
+    let path = path.to_str().unwrap();
+
+    let output = Captured::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(output.clone());
+    let source = format!(
+        r#"
+        let path = {path:?};
+        write(path, "first\n");
+        append(path, "second\n");
+        append(path, "third");
+        print(read(path));
+        write(path, "replaced");
+        print(read(path));
+        "#
+    );
+    let result = interpreter.interpret(&source);
+    let written = fs::read_to_string(path);
+    let _ = fs::remove_file(path);
+
+    result.unwrap();
+    assert_eq!(output.text(), "first\nsecond\nthird\nreplaced\n");
+    assert_eq!(written.unwrap(), "replaced");
+}
@@ -0,0 +1,64 @@
+//! Programs that keep strings alive only through places the collector has to look,
+//! run with `clobber_gc` so there's a collection at every allocation and anything it
+//! misses is freed before it's read again.
+//!
+//! The maps are filled inside loops, which `local_map_scopes` would keep local to them.
+#![cfg(all(feature = "clobber_gc", not(feature = "local_map_scopes")))]
+
+use common::Captured;
+use dymaxilang::{style, Interpreter};
+
+mod common;
+
+fn output(source: &str) -> String {
+    style::set_color(false);
+    let output = Captured::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(output.clone());
+    if let Err(err) = interpreter.interpret(source) {
+        panic!("{err}");
+    }
+    output.text()
+}
+
+#[test]
+fn strings_allocated_inside_a_function() {
+    // the string constants are only reachable through the function's chunk
+    let source = r#"
+        let describe = fn (i) {
+            let label = "item " + i;
+            return label + " of " + "many";
+        };
+        let last = "";
+        for i in 0>200 {
+            last = describe(i);
+        }
+        print(last);
+        print(describe(7));
+    "#;
+    assert_eq!(output(source), "item 199 of many\nitem 7 of many\n");
+}
+
+#[test]
+fn strings_stored_in_a_map() {
+    // the values are only reachable through the global map
+    let source = r#"
+        for i in 0>100 {
+            "stored"[i] = "value " + i;
+        }
+        let garbage = "";
+        for i in 0>100 {
+            garbage = garbage + "x";
+        }
+        let all_there = true;
+        for i in 0>100 {
+            if "stored"[i] != "value " + i {
+                all_there = false;
+                print("stored"[i]);
+            }
+        }
+        print(all_there);
+        print("stored"[99]);
+    "#;
+    assert_eq!(output(source), "true\nvalue 99\n");
+}
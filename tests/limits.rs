@@ -0,0 +1,118 @@
+//! Runs generated programs that go past the sizes the bytecode can encode in its short
+//! forms, so the long forms the compiler switches to get run too.
+
+use std::io;
+
+use common::Captured;
+use dymaxilang::{style, Interpreter};
+
+mod common;
+
+fn output(source: &str) -> String {
+    style::set_color(false);
+    let output = Captured::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(output.clone());
+    // the generated locals are mostly never read
+    interpreter.set_error_output(io::sink());
+    if let Err(err) = interpreter.interpret(source) {
+        panic!("{err}");
+    }
+    output.text()
+}
+
+/// Enough statements that their code is further than a 16 bit jump can go
+const LARGE_BODY: usize = 12_000;
+
+#[test]
+fn large_if_and_loop_bodies() {
+    // each increment of a local is 7 bytes of bytecode, making each body over 80KB
+    let increments = "x = x + 1;\n".repeat(LARGE_BODY);
+    let source = format!(
+        "
+        let f = fn (flag) {{
+            let x = 0;
+            if flag {{
+                {increments}
+            }} else {{
+                x = -1;
+            }}
+            let i = 0;
+            while i < 2 {{
+                {increments}
+                i = i + 1;
+            }}
+            return x;
+        }};
+        print(f(true));
+        print(f(false));
+        "
+    );
+    assert_eq!(
+        output(&source),
+        format!("{}\n{}\n", LARGE_BODY * 3, LARGE_BODY * 2 - 1)
+    );
+}
+
+/// More than the 256 a one byte operand can number
+const MANY: usize = 300;
+
+#[test]
+fn globals_past_slot_255() {
+    let mut source = String::new();
+    for i in 0..MANY {
+        source.push_str(&format!("let g{i} = {i};\n"));
+    }
+    source.push_str("let sum = 0;\n");
+    for i in 0..MANY {
+        source.push_str(&format!("sum = sum + g{i};\n"));
+    }
+    let last = MANY - 1;
+    source.push_str(&format!(
+        "
+        g{last} = g{last} + 1;
+        let f = fn () {{
+            g0 = g{last} * 2;
+            return g0 + g{last};
+        }};
+        print(sum);
+        print(f());
+        print(g0);
+        "
+    ));
+    assert_eq!(
+        output(&source),
+        format!(
+            "{}\n{}\n{}\n",
+            MANY * last / 2,
+            (last + 1) * 3,
+            (last + 1) * 2
+        )
+    );
+}
+
+#[test]
+fn locals_past_slot_255() {
+    let mut body = String::new();
+    for i in 0..MANY {
+        body.push_str(&format!("let l{i} = {i};\n"));
+    }
+    let last = MANY - 1;
+    body.push_str(&format!(
+        "
+        l{last} = l{last} + l0 + 1;
+        let total = 0;
+        for i in 0>3 {{
+            total = total + l{last};
+        }}
+        {{
+            let inner = l{last} + l256;
+            l0 = inner;
+        }}
+        return total + l0;
+        "
+    ));
+    let source = format!("let f = fn () {{\n{body}}};\nprint(f());\n");
+    let expected = (last + 1) * 3 + (last + 1) + 256;
+    assert_eq!(output(&source), format!("{expected}\n"));
+}
@@ -0,0 +1,155 @@
+//! Checks where maps written inside blocks and functions end up with
+//! `local_map_scopes`, which gives every scope that writes to a map its own copy of
+//! the namespaces, thrown away when the scope ends.
+#![cfg(feature = "local_map_scopes")]
+
+use std::io;
+
+use common::Captured;
+use dymaxilang::{style, Error, Interpreter};
+
+mod common;
+
+fn output(source: &str) -> String {
+    style::set_color(false);
+    let output = Captured::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(output.clone());
+    if let Err(err) = interpreter.interpret(source) {
+        panic!("{err}");
+    }
+    output.text()
+}
+
+/// Messages of the errors source fails to compile with
+fn compile_errors(source: &str) -> Vec<String> {
+    style::set_color(false);
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(io::sink());
+    interpreter.set_error_output(io::sink());
+    match interpreter.interpret(source) {
+        Err(Error::Compile(diagnostics)) => diagnostics
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect(),
+        other => panic!("expected compile errors, got {other:?}"),
+    }
+}
+
+#[test]
+fn nested_scopes() {
+    let source = r#"
+        "m"["a"] = 1;
+        {
+            "m"["a"] = 2;
+            print("m"["a"]);
+            {
+                "m"["b"] = 3;
+                print("m"["a"]);
+                print("m"["b"]);
+            }
+            print(has("m", "b"));
+        }
+        print("m"["a"]);
+    "#;
+    assert_eq!(output(source), "2\n2\n3\nfalse\n1\n");
+}
+
+#[test]
+fn loop_bodies_start_with_a_fresh_scope() {
+    let source = r#"
+        let i = 0;
+        while i < 3 {
+            print(has("loop", "seen"));
+            "loop"["seen"] = i;
+            i = i + 1;
+        }
+        print(has("loop", "seen"));
+    "#;
+    assert_eq!(output(source), "false\nfalse\nfalse\nfalse\n");
+}
+
+#[test]
+fn scopes_in_functions() {
+    let source = r#"
+        let f = fn () {
+            "g"["y"] = 1;
+            {
+                print("g"["y"]);
+                "g"["x"] = 6;
+                print("g"["x"]);
+            }
+            return "g"["y"];
+        };
+        print(f());
+        print(has("g", "x"));
+        print(has("g", "y"));
+
+        // each call has its own maps, so a recursive call can't change its caller's
+        let count = fn (n) {
+            "depth"["n"] = n;
+            if n > 0 {
+                count(n - 1);
+            }
+            return "depth"["n"];
+        };
+        print(count(3));
+    "#;
+    assert_eq!(output(source), "1\n6\n1\nfalse\nfalse\n3\n");
+}
+
+#[test]
+fn reads_fall_through_to_the_global_map() {
+    let source = r#"
+        "g"["x"] = 5;
+        let f = fn () {
+            print("g"["x"]);
+            {
+                "g"["other"] = 0;
+                print("g"["x"]);
+                "g"["x"] = 6;
+                print("g"["x"]);
+            }
+            print("g"["x"]);
+            return 0;
+        };
+        f();
+        print("g"["x"]);
+    "#;
+    assert_eq!(output(source), "5\n5\n6\n5\n5\n");
+}
+
+#[test]
+fn scopes_with_errors() {
+    // sync skips what's left of a bad statement, closing braces included, which has to
+    // leave the map scopes balanced rather than tripping finish_map_scope
+    assert_eq!(
+        compile_errors(r#"{ "m"[1] = ; }"#),
+        ["';' can't be used in an expression"]
+    );
+    assert_eq!(
+        compile_errors(r#"let f = fn () { { "m"[1] = 1; ) }; }; print(1);"#).len(),
+        2
+    );
+    assert_eq!(
+        compile_errors(r#"{ { "m"[1] = 2; }"#),
+        ["expected '}' after block, found end of file"]
+    );
+    assert_eq!(
+        compile_errors(r#"{ "m"[1] = 1; let x = fn () { "m"[2] = 2;"#).len(),
+        2
+    );
+    assert_eq!(
+        compile_errors(r#"let f = fn (a b) { "m"[1] = 2; return a; };"#)[0],
+        "expected ')' after function arguments, found 'b'"
+    );
+}
+
+#[test]
+fn nesting_limit() {
+    let source = format!("{}{}", "{ ".repeat(70), "} ".repeat(70));
+    assert_eq!(
+        compile_errors(&source),
+        ["can't nest more than 64 scopes in one function"]
+    );
+}
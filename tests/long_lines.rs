@@ -0,0 +1,93 @@
+//! Errors on a line a megabyte long, like minified or generated code. The snippet
+//! under the error is cut down to a window around the span instead of printing the
+//! whole line, and rendering it takes time in proportion to the length of the line.
+//! Timing depends on the machine, so that check only runs when asked for with
+//! `cargo test -- --ignored`.
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use dymaxilang::{style, Error, Interpreter};
+
+/// Repeated to make up the line, 19 bytes long
+const STATEMENT: &str = " total = total + 1;";
+
+/// Width of the source shown under an error, with room for the gutter and the `…` at
+/// each end of a cut line
+const MAX_RENDERED_WIDTH: usize = 120 + 6 + 2;
+
+/// A program on one line with count statements before and after the one in error
+fn one_line(count: usize, error: &str) -> String {
+    let statements = STATEMENT.repeat(count);
+    format!("let total = 0;{statements} {error}{statements}")
+}
+
+/// The error the program stops with, as it's printed
+fn render(source: &str) -> String {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(io::sink());
+    interpreter.set_error_output(io::sink());
+    match interpreter.interpret(source) {
+        Ok(()) => panic!("expected the program to fail"),
+        Err(Error::Compile(diagnostics)) => diagnostics.iter().map(ToString::to_string).collect(),
+        Err(err) => err.to_string(),
+    }
+}
+
+fn assert_cut_down(rendered: &str, shown: &str) {
+    assert!(rendered.contains(shown), "{rendered}");
+    for line in rendered.lines() {
+        let width = line.chars().count();
+        assert!(width <= MAX_RENDERED_WIDTH, "{width} characters: {line}");
+    }
+}
+
+#[test]
+fn compile_error_snippet_is_cut_down() {
+    style::set_color(false);
+    let source = one_line(50_000, "print(total +);");
+    assert!(source.len() > 1_000_000);
+
+    let rendered = render(&source);
+    assert_cut_down(&rendered, "print(total +);");
+    assert!(rendered.contains("| …tal"), "{rendered}");
+    assert!(rendered.trim_end().lines().nth(2).unwrap().ends_with('…'));
+}
+
+#[test]
+fn runtime_error_snippet_is_cut_down() {
+    style::set_color(false);
+    let source = one_line(50_000, "print(total + true);");
+
+    let rendered = render(&source);
+    assert_cut_down(&rendered, "print(total + true);");
+    assert!(rendered.contains("can't apply '+' to number (50000) and bool (true)"));
+}
+
+/// Time to compile and fail a program whose only line is count statements long
+fn fail(count: usize) -> Duration {
+    let source = one_line(count / 2, "print(total +);");
+    let start = Instant::now();
+    render(&source);
+    start.elapsed()
+}
+
+#[test]
+#[ignore = "measures time, run with --ignored"]
+fn rendering_a_1mb_line_is_linear() {
+    style::set_color(false);
+    // the fastest of a few runs, so a busy machine is less likely to fail the test
+    let fastest = |count| (0..3).map(|_| fail(count)).min().unwrap();
+    let small = fastest(5_000);
+    let large = fastest(50_000);
+
+    // ten times the length would take about a hundred times as long if each diagnostic
+    // or character offset walked the line from the start
+    let ratio = large.as_secs_f64() / small.as_secs_f64();
+    assert!(
+        ratio < 30.0,
+        "100KB took {small:?} but 1MB took {large:?}, {ratio:.1} times as long"
+    );
+}
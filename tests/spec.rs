@@ -0,0 +1,134 @@
+//! Runs every script in tests/scripts and checks what it prints against the
+//! `// expect: ...` comments in it, in order, and what it writes to stderr against
+//! its `// expect-stderr: ...` comments if it has any. A `// expect-error: ...`
+//! comment means the script has to fail with an error whose message contains the text, after printing
+//! whatever the `expect` comments before the error say. A script with several of them
+//! has to fail to compile with exactly that many errors, in the same order. A
+//! `//! lang N` pragma at the top runs the script as that version of the language.
+//!
+//! The scripts describe the language without `local_map_scopes`, which changes where
+//! maps written inside blocks end up.
+#![cfg(not(feature = "local_map_scopes"))]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use common::Captured;
+use dymaxilang::{style, Error, Interpreter};
+
+mod common;
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    /// Only checked when there's at least one, so scripts needn't list their warnings
+    error_output: Vec<String>,
+    errors: Vec<String>,
+}
+
+fn expectations(source: &str) -> Expectations {
+    let mut expected = Expectations::default();
+    for line in source.lines() {
+        if let Some((_, output)) = line.split_once("// expect: ") {
+            expected.output.push(output.to_owned());
+        } else if let Some((_, output)) = line.split_once("// expect-stderr: ") {
+            expected.error_output.push(output.to_owned());
+        } else if let Some((_, error)) = line.split_once("// expect-error: ") {
+            expected.errors.push(error.to_owned());
+        }
+    }
+    expected
+}
+
+fn run(path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|err| format!("couldn't be read: {err}"))?;
+    let expected = expectations(&source);
+
+    let output = Captured::default();
+    let errors = Captured::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(output.clone());
+    interpreter.set_error_output(errors.clone());
+    let result = interpreter.interpret(&source);
+
+    let printed = output.text();
+    let printed: Vec<&str> = printed.lines().collect();
+    if printed != expected.output {
+        return Err(format!(
+            "expected output:\n{}\ngot:\n{}",
+            expected.output.join("\n"),
+            printed.join("\n")
+        ));
+    }
+
+    let printed = errors.text();
+    let printed: Vec<&str> = printed.lines().collect();
+    if !expected.error_output.is_empty() && printed != expected.error_output {
+        return Err(format!(
+            "expected stderr:\n{}\ngot:\n{}",
+            expected.error_output.join("\n"),
+            printed.join("\n")
+        ));
+    }
+
+    let messages: Vec<String> = match &result {
+        Ok(()) => Vec::new(),
+        Err(Error::Compile(diagnostics)) => diagnostics.iter().map(ToString::to_string).collect(),
+        Err(err) => vec![err.to_string()],
+    };
+    let matches = messages.len() == expected.errors.len()
+        && messages
+            .iter()
+            .zip(expected.errors.iter())
+            .all(|(message, error)| message.contains(error));
+
+    match result {
+        _ if matches => Ok(()),
+        Ok(()) => Err(format!(
+            "expected errors containing {:?}, but the script succeeded",
+            expected.errors
+        )),
+        Err(err) if expected.errors.is_empty() => Err(format!("unexpected error:\n{err}")),
+        Err(err) => Err(format!(
+            "expected errors containing {:?}, got:\n{err}",
+            expected.errors
+        )),
+    }
+}
+
+fn scripts() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|err| panic!("{} couldn't be read: {err}", dir.display()))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "dy"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+#[test]
+fn spec_scripts() {
+    style::set_color(false);
+
+    let scripts = scripts();
+    assert!(!scripts.is_empty(), "no scripts found in tests/scripts");
+
+    let failures: Vec<String> = scripts
+        .iter()
+        .filter_map(|path| {
+            run(path)
+                .err()
+                .map(|err| format!("{}: {err}", path.display()))
+        })
+        .collect();
+    assert!(
+        failures.is_empty(),
+        "{} of {} scripts failed\n\n{}",
+        failures.len(),
+        scripts.len(),
+        failures.join("\n\n")
+    );
+}
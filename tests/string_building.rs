@@ -0,0 +1,53 @@
+//! Checks that building a string with a buffer takes time in proportion to its length,
+//! where repeated `+` copies everything built so far for every piece. Timing depends on
+//! the machine, so this only runs when asked for with `cargo test -- --ignored`.
+
+use std::time::{Duration, Instant};
+
+use common::Captured;
+use dymaxilang::Interpreter;
+
+mod common;
+
+/// Appended to the buffer over and over, 100 bytes long
+const PIECE: &str = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789abcdefghijklmnopqrstuvwxyz..";
+
+/// Time to build a string out of count pieces, checking it comes out the right length
+fn build(count: usize) -> Duration {
+    let output = Captured::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(output.clone());
+    let source = format!(
+        "
+        let built = buffer();
+        for i in 0 > {count} {{
+            built + \"{PIECE}\";
+        }}
+        print_raw(to_str(built));
+        "
+    );
+
+    let start = Instant::now();
+    interpreter.interpret(&source).unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(output.text().len(), count * PIECE.len());
+    elapsed
+}
+
+#[test]
+#[ignore = "measures time, run with --ignored"]
+fn building_10mb_is_linear() {
+    assert_eq!(PIECE.len(), 100);
+    // the fastest of a few runs, so a busy machine is less likely to fail the test
+    let fastest = |count| (0..3).map(|_| build(count)).min().unwrap();
+    let small = fastest(10_000);
+    let large = fastest(100_000);
+
+    // ten times the length would take about a hundred times as long if each append
+    // copied the string so far
+    let ratio = large.as_secs_f64() / small.as_secs_f64();
+    assert!(
+        ratio < 30.0,
+        "1MB took {small:?} but 10MB took {large:?}, {ratio:.1} times as long"
+    );
+}
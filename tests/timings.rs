@@ -0,0 +1,65 @@
+//! Checks the report --timings writes to stderr against a golden copy, with the
+//! durations blanked out since they change from run to run. Adding a compiler pass
+//! means adding its line here.
+#![cfg(not(any(
+    feature = "trace_execution",
+    feature = "debug_gc",
+    feature = "decompile"
+)))]
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+const GOLDEN: &str = "\
+compiler timings:
+  prelude _ms
+  lex and parse _ms 1 constants deduplicated
+  validate _ms
+  lint _ms
+  total _ms
+";
+
+/// Replaces every duration with `_ms` and collapses the padding between columns
+fn blank_durations(report: &str) -> String {
+    report
+        .lines()
+        .map(|line| {
+            let words: Vec<&str> = line
+                .split_whitespace()
+                .map(|word| match word.strip_suffix("ms") {
+                    Some(number) if number.parse::<f64>().is_ok() => "_ms",
+                    _ => word,
+                })
+                .collect();
+            let indent = if line.starts_with(' ') { "  " } else { "" };
+            format!("{indent}{}\n", words.join(" "))
+        })
+        .collect()
+}
+
+#[test]
+fn timings_name_every_phase() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dymaxilang"))
+        .args(["--timings", "--no-prelude", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("dymaxilang should start");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"let a = \"x\";\nprint(a + \"x\");\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"xx\n");
+    assert_eq!(
+        blank_durations(&String::from_utf8_lossy(&output.stderr)),
+        GOLDEN
+    );
+}
@@ -0,0 +1,194 @@
+//! Checks the warnings the compiler reports without stopping the program, and that
+//! strict mode turns them into errors.
+
+use std::io;
+
+use common::Captured;
+use dymaxilang::{style, Interpreter};
+
+mod common;
+
+/// Runs source, returning what it printed and the codes of the warnings reported
+fn warnings(source: &str) -> (String, Vec<String>) {
+    style::set_color(false);
+    let output = Captured::default();
+    let errors = Captured::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(output.clone());
+    interpreter.set_error_output(errors.clone());
+    interpreter.interpret(source).unwrap();
+
+    let codes = errors
+        .text()
+        .lines()
+        .filter(|line| line.starts_with("warning"))
+        .filter_map(|line| Some(line.rsplit_once('[')?.1.trim_end_matches(']').to_owned()))
+        .collect();
+    (output.text(), codes)
+}
+
+#[test]
+fn unread_locals() {
+    let source = "
+        let f = fn (unused_parameter) {
+            let result = 1;
+            let returned = 2;
+            return returned;
+        };
+        {
+            let written = 0;
+            written = 1;
+            let _ignored = 3;
+        }
+        for i in 0>2 {}
+        for _ in 0>2 {}
+        for c in \"ab\" {}
+        print(f(0));
+    ";
+    let (output, codes) = warnings(source);
+    assert_eq!(output, "2\n");
+    // result, written, i, and c
+    assert_eq!(codes, ["W0002"; 4]);
+}
+
+#[test]
+fn read_locals_are_fine() {
+    let source = "
+        let f = fn (a) {
+            let b = a + 1;
+            {
+                let c = b;
+                c[\"key\"] = 1;
+            }
+            return b;
+        };
+        for i in 0>2 {
+            print(f(i));
+        }
+    ";
+    assert_eq!(warnings(source), ("1\n2\n".to_owned(), Vec::new()));
+}
+
+#[test]
+fn unreachable_code() {
+    let source = "
+        let f = fn () {
+            return 1;
+            print(\"never\");
+            print(\"reported once\");
+        };
+        let g = fn (x) {
+            if x {
+                return 1;
+            }
+            return 2;
+        };
+        print(f() + g(true));
+    ";
+    assert_eq!(
+        warnings(source),
+        ("2\n".to_owned(), vec!["W0003".to_owned()])
+    );
+}
+
+#[test]
+fn strict_makes_warnings_errors() {
+    let mut interpreter = Interpreter::new();
+    interpreter.strict = true;
+    interpreter.set_error_output(io::sink());
+    let err = interpreter
+        .interpret("let f = fn () { let x = 1; return 0; }; print(f());")
+        .unwrap_err();
+    assert!(err.to_string().contains("W0002"), "{err}");
+}
+
+#[test]
+fn undefined_globals() {
+    let source = "
+        let f = fn () {
+            return later + 1;
+        };
+        let later = 1;
+        print(f());
+        if false {
+            print(nowhere);
+            prnt(later);
+        }
+    ";
+    let (output, codes) = warnings(source);
+    assert_eq!(output, "2\n");
+    assert_eq!(codes, ["W0004"; 2]);
+}
+
+#[test]
+fn globals_from_earlier_sources_are_defined() {
+    let errors = Captured::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(io::sink());
+    interpreter.set_error_output(errors.clone());
+    interpreter.interpret("let count = 1;").unwrap();
+    interpreter.interpret("print(count);").unwrap();
+    assert_eq!(errors.text(), "");
+}
+
+#[test]
+fn undefined_global_suggestion() {
+    let mut interpreter = Interpreter::new();
+    interpreter.strict = true;
+    interpreter.set_error_output(io::sink());
+    let err = interpreter
+        .interpret("let total = 0; prnt(total);")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("W0004"), "{err}");
+    assert!(err.contains("did you mean 'print'?"), "{err}");
+}
+
+#[test]
+fn compared_natives() {
+    let source = "
+        let answer = \"y\";
+        print(time == answer);
+        if answer != to_str {
+            print(\"different\");
+        }
+        print(time() > 0);
+        let f = fn (time) {
+            return time == 1;
+        };
+        print(f(1));
+    ";
+    let (output, codes) = warnings(source);
+    assert_eq!(output, "false\ndifferent\ntrue\ntrue\n");
+    // time and to_str, but not the call or the parameter named time
+    assert_eq!(codes, ["W0005"; 2]);
+}
+
+#[test]
+fn reassigned_natives_can_be_compared() {
+    let source = "
+        time = 3;
+        print(time == 3);
+    ";
+    let (output, codes) = warnings(source);
+    assert_eq!(output, "true\n");
+    assert!(codes.is_empty(), "{codes:?}");
+}
+
+#[test]
+fn unread_globals_are_only_reported_outside_the_repl() {
+    let errors = Captured::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(io::sink());
+    interpreter.set_error_output(errors.clone());
+    interpreter.evaluate("let typed = 1;").unwrap();
+    assert_eq!(errors.text(), "");
+    interpreter.interpret("let unused = 1; typed = 2;").unwrap();
+    let text = errors.text();
+    assert!(
+        text.contains("global 'unused' is never read [W0001]"),
+        "{text}"
+    );
+    // typed was defined by an earlier source, which might read it
+    assert_eq!(text.matches("never read").count(), 1, "{text}");
+}